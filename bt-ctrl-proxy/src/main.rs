@@ -3,6 +3,7 @@ use clap::Parser;
 use eyre::{Result, WrapErr};
 use std::io::ErrorKind;
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const TCP_RETRY_DURATION: Duration = Duration::from_secs(5);
 
@@ -73,13 +74,49 @@ fn main() -> Result<()> {
             }).await?;
             log::trace!("Advertising bluetooth service");
 
-            let (mut controller, _addr) = bt_socket.accept().await
+            let (controller, _addr) = bt_socket.accept().await
                 .wrap_err("Failed to accept controller connection")?;
             log::trace!("Accepting controller connections");
 
-            tokio::io::copy_bidirectional(&mut controllable, &mut controller).await
-                .wrap_err("Stream ended unexpectedly")?;
+            let (mut controller_rx, mut controller_tx) = tokio::io::split(controller);
+            let (mut controllable_rx, mut controllable_tx) = controllable.split();
+            tokio::try_join!(
+                forward_frames(&mut controller_rx, &mut controllable_tx),
+                async {
+                    tokio::io::copy(&mut controllable_rx, &mut controller_tx).await
+                        .wrap_err("Failed to forward messages to controller")
+                },
+            ).wrap_err("Stream ended unexpectedly")?;
 
             return Ok(());
         })
 }
+
+/// Forwards only complete frames to the robot, so a partial or corrupted frame from a
+/// flaky Bluetooth link can't be spliced into the next one
+async fn forward_frames(
+    controller: &mut (impl AsyncRead + Unpin),
+    robot: &mut (impl AsyncWrite + Unpin),
+) -> Result<()> {
+    let mut splitter = framing::FrameSplitter::new(framing::max_frame_len::<messages::Message>());
+    let mut buf = [0u8; 512];
+    let mut frames = Vec::new();
+
+    loop {
+        let len = controller.read(&mut buf).await
+            .wrap_err("Failed to read from controller")?;
+        if len == 0 {
+            return Ok(())
+        }
+
+        splitter.push(&buf[..len], |frame| match frame {
+            Ok(frame) => frames.extend_from_slice(frame),
+            Err(e) => log::warn!("Dropping frame from controller: {}", e),
+        });
+        if !frames.is_empty() {
+            robot.write_all(&frames).await
+                .wrap_err("Failed to forward messages to robot")?;
+            frames.clear();
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Framing of messages exchanged between operators and the robot.
+//!
+//! Every message is serialized with postcard, then COBS encoded and terminated with a zero byte.
+//! Frames can be sent as individual datagrams or back to back on a stream, and since zero bytes
+//! only ever appear as terminators, a reader can always find the start of the next frame.
+//!
+//! Nothing here performs I/O, so the same codec works with any runtime, or none at all.
+
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+
+pub use postcard::experimental::max_size::MaxSize;
+
+/// Worst case length of `len` bytes after COBS encoding, including the terminator.
+///
+/// COBS adds one overhead byte for every run of up to 254 non-zero bytes, and there's always at
+/// least one run, even for an empty message.
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 1 + 1
+}
+
+/// Worst case length of a frame holding an `M`, including the terminator
+pub const fn max_frame_len<M: MaxSize>() -> usize {
+    max_encoded_len(M::POSTCARD_MAX_SIZE)
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Frame is longer than any valid message")]
+    FrameTooLong,
+    #[error("Frame is missing its terminator")]
+    Unterminated,
+    /// The frame holds a kind of message this version doesn't know about, probably from a newer
+    /// peer. It's safe to skip.
+    #[error("Unknown kind of message")]
+    UnknownMessage,
+    #[error("Invalid message: {0}")]
+    Postcard(#[from] postcard::Error),
+}
+
+/// Encodes messages of type `M` into frames
+pub struct Encoder<M> {
+    buf: Vec<u8>,
+    _msg: PhantomData<fn(&M)>,
+}
+
+impl<M: Serialize + MaxSize> Encoder<M> {
+    pub fn new() -> Self {
+        Self {
+            buf: vec![0; max_frame_len::<M>()],
+            _msg: PhantomData,
+        }
+    }
+
+    /// Encodes a single frame, terminator included. The frame is valid until the next call.
+    pub fn encode(&mut self, msg: &M) -> Result<&[u8], Error> {
+        Ok(postcard::to_slice_cobs(msg, &mut self.buf)?)
+    }
+}
+
+impl<M: Serialize + MaxSize> Default for Encoder<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decodes a frame received on its own, e.g. as a datagram
+pub fn decode<M: DeserializeOwned>(frame: &mut [u8]) -> Result<M, Error> {
+    if frame.last() != Some(&0) {
+        return Err(Error::Unterminated)
+    }
+    match postcard::from_bytes_cobs(frame) {
+        Ok(msg) => Ok(msg),
+        // serde reports unknown enum variants as custom errors, and postcard drops their details.
+        // The only other custom errors come from values over the capacity of a field, which
+        // also means the message was sent by a peer with different limits.
+        Err(postcard::Error::DeserializeBadEnum | postcard::Error::SerdeDeCustom) => {
+            Err(Error::UnknownMessage)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Splits a byte stream into frames, without decoding them.
+///
+/// Stream transports don't preserve message boundaries, so a single read can end partway through
+/// a frame or contain several of them. Partial frames are buffered between calls to
+/// [`FrameSplitter::push`].
+pub struct FrameSplitter {
+    buf: Vec<u8>,
+    max_frame_len: usize,
+    overflowed: bool,
+}
+
+impl FrameSplitter {
+    /// `max_frame_len` includes the terminator
+    pub fn new(max_frame_len: usize) -> Self {
+        Self {
+            buf: Vec::with_capacity(max_frame_len),
+            max_frame_len,
+            overflowed: false,
+        }
+    }
+
+    /// Appends newly read bytes, calling `on_frame` with every frame they complete, terminator
+    /// included.
+    ///
+    /// Frames longer than the maximum are reported as [`Error::FrameTooLong`], and splitting
+    /// resumes after their terminator.
+    pub fn push(&mut self, mut data: &[u8], mut on_frame: impl FnMut(Result<&mut [u8], Error>)) {
+        while let Some(end) = data.iter().position(|&b| b == 0) {
+            self.extend(&data[..=end]);
+            if self.overflowed {
+                on_frame(Err(Error::FrameTooLong));
+            } else {
+                on_frame(Ok(&mut self.buf[..]));
+            }
+            self.buf.clear();
+            self.overflowed = false;
+            data = &data[end + 1..];
+        }
+
+        self.extend(data);
+    }
+
+    fn extend(&mut self, data: &[u8]) {
+        if self.overflowed {
+            return
+        }
+        if self.buf.len() + data.len() > self.max_frame_len {
+            self.buf.clear();
+            self.overflowed = true;
+            return
+        }
+        self.buf.extend_from_slice(data);
+    }
+}
+
+/// Decodes a stream of messages of type `M`
+pub struct Decoder<M> {
+    frames: FrameSplitter,
+    _msg: PhantomData<fn() -> M>,
+}
+
+impl<M: DeserializeOwned + MaxSize> Decoder<M> {
+    pub fn new() -> Self {
+        Self {
+            frames: FrameSplitter::new(max_frame_len::<M>()),
+            _msg: PhantomData,
+        }
+    }
+
+    /// Appends newly read bytes, calling `on_msg` for every frame they complete
+    pub fn push(&mut self, data: &[u8], mut on_msg: impl FnMut(Result<M, Error>)) {
+        self.frames.push(data, |frame| on_msg(frame.and_then(|frame| decode(frame))));
+    }
+}
+
+impl<M: DeserializeOwned + MaxSize> Default for Decoder<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use messages::{
+        Command, DriveStatus, Message, Mode, Move, ParamInfo, ParamRange, ParamValue, Request,
+        RequestError, Response, Status,
+    };
+    use serde::Deserialize;
+
+    fn enable() -> Message {
+        Message::Command(Command::Enable)
+    }
+
+    fn forward() -> Message {
+        Message::Command(Command::Move(Move {
+            translate: [0.0, 1.0].into(),
+            rotate: 0.25,
+        }))
+    }
+
+    fn frame(message: &Message) -> Vec<u8> {
+        Encoder::new().encode(message).unwrap().to_vec()
+    }
+
+    fn status() -> Status {
+        Status {
+            mode: Mode::Enabled,
+            drive: DriveStatus::Recovering { consecutive_errors: 3 },
+            drive_errors: 7,
+            link_losses: 1,
+            superseded_moves: 1234,
+            motor_transactions_per_tick: 1.5,
+        }
+    }
+
+    /// A parameter using as much of every field as it can
+    fn largest_param() -> ParamInfo {
+        let mut choices = heapless::Vec::new();
+        while choices.push("c".repeat(messages::PARAM_CHOICE_NAME_MAX_LEN).as_str().into()).is_ok() {}
+        ParamInfo {
+            name: "n".repeat(messages::PARAM_NAME_MAX_LEN).as_str().into(),
+            value: ParamValue::Choice(u8::MAX),
+            default: ParamValue::Choice(0),
+            range: Some(ParamRange {
+                min: ParamValue::Int(i32::MIN),
+                max: ParamValue::Int(i32::MAX),
+            }),
+            choices,
+        }
+    }
+
+    /// One of every kind of message
+    fn every_message() -> Vec<Message> {
+        let param_name: messages::ParamName = "failsafe.decel_ms".into();
+        let mut params = heapless::Vec::new();
+        while params.push(largest_param()).is_ok() {}
+        vec![
+            forward(),
+            enable(),
+            Message::Command(Command::Disable),
+            Message::Command(Command::EStop),
+            Message::Command(Command::ClearEStop),
+            Message::Status(status()),
+            Message::Status(Status { drive: DriveStatus::Ok, ..status() }),
+            Message::Status(Status { mode: Mode::EStopped, drive: DriveStatus::SafeStopped, ..status() }),
+            Message::Request { id: 0, request: Request::Ping },
+            Message::Request { id: 1, request: Request::GetParam(param_name.clone()) },
+            Message::Request { id: 2, request: Request::SetParam(param_name, ParamValue::Int(-250)) },
+            Message::Request { id: 3, request: Request::SetParam("log.level".into(), ParamValue::Choice(2)) },
+            Message::Request { id: 4, request: Request::SetParam("drive.invert".into(), ParamValue::Bool(true)) },
+            Message::Request { id: 5, request: Request::ListParams { start: 2 } },
+            Message::Request { id: u32::MAX, request: Request::SaveParams },
+            Message::Response { id: 0, response: Response::Pong },
+            Message::Response { id: 1, response: Response::Param(ParamValue::Float(0.2)) },
+            Message::Response { id: 2, response: Response::Error(RequestError::UnknownParam) },
+            Message::Response { id: 3, response: Response::Error(RequestError::InvalidValue) },
+            Message::Response { id: 4, response: Response::Error(RequestError::SaveFailed) },
+            Message::Response { id: 5, response: Response::ParamList { total: u16::MAX, params } },
+            Message::Response { id: 6, response: Response::ParamList { total: 0, params: heapless::Vec::new() } },
+            Message::Response { id: u32::MAX, response: Response::Saved },
+        ]
+    }
+
+    /// Nothing but non-zero bytes, the worst case for COBS
+    #[derive(Serialize, Deserialize, MaxSize, Debug, PartialEq)]
+    struct Payload(heapless::Vec<u8, 600>);
+
+    fn decode_stream(decoder: &mut Decoder<Message>, data: &[u8]) -> Vec<Result<Message, Error>> {
+        let mut decoded = Vec::new();
+        decoder.push(data, |msg| decoded.push(msg));
+        decoded
+    }
+
+    #[test]
+    fn reassembles_frame_split_into_single_bytes() {
+        let mut decoder = Decoder::new();
+        let frame = frame(&forward());
+        let (last, start) = frame.split_last().unwrap();
+
+        for byte in start {
+            assert!(decode_stream(&mut decoder, &[*byte]).is_empty());
+        }
+        let decoded = decode_stream(&mut decoder, &[*last]);
+        assert!(matches!(&decoded[..], [Ok(msg)] if *msg == forward()));
+    }
+
+    #[test]
+    fn splits_frames_sharing_a_read() {
+        let mut decoder = Decoder::new();
+        let mut data = frame(&enable());
+        data.extend(frame(&forward()));
+        // the start of a third frame stays buffered until the rest of it arrives
+        let third = frame(&Message::Command(Command::Disable));
+        data.extend(&third[..1]);
+
+        let decoded = decode_stream(&mut decoder, &data);
+        assert!(matches!(&decoded[..], [Ok(a), Ok(b)] if *a == enable() && *b == forward()));
+
+        let decoded = decode_stream(&mut decoder, &third[1..]);
+        assert!(matches!(&decoded[..], [Ok(Message::Command(Command::Disable))]));
+    }
+
+    #[test]
+    fn recovers_after_corrupt_frame() {
+        let mut decoder = Decoder::new();
+        let mut corrupt = frame(&forward());
+        // a COBS code pointing past the end of the frame
+        corrupt[0] = 0xFE;
+        let mut data = corrupt;
+        data.extend(frame(&enable()));
+
+        let decoded = decode_stream(&mut decoder, &data);
+        assert!(matches!(&decoded[..], [Err(_), Ok(msg)] if *msg == enable()));
+    }
+
+    #[test]
+    fn recovers_after_overlong_frame() {
+        let mut decoder = Decoder::<Message>::new();
+        let mut data = vec![0xFF; max_frame_len::<Message>() + 10];
+        data.push(0);
+        data.extend(frame(&Message::Status(messages::Status {
+            mode: Mode::Disabled,
+            drive: messages::DriveStatus::Ok,
+            drive_errors: 0,
+            link_losses: 0,
+            superseded_moves: 0,
+            motor_transactions_per_tick: 0.0,
+        })));
+
+        let decoded = decode_stream(&mut decoder, &data);
+        assert!(matches!(&decoded[..], [Err(Error::FrameTooLong), Ok(Message::Status(_))]));
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        let mut encoder = Encoder::new();
+        for message in every_message() {
+            let mut frame = encoder.encode(&message).unwrap().to_vec();
+            assert!(frame.len() <= max_frame_len::<Message>(), "{:?}", message);
+            assert_eq!(frame.iter().position(|&b| b == 0), Some(frame.len() - 1));
+            assert_eq!(decode::<Message>(&mut frame).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn streams_every_message() {
+        let mut encoder = Encoder::new();
+        let mut data = Vec::new();
+        for message in every_message() {
+            data.extend(encoder.encode(&message).unwrap());
+        }
+
+        let decoded = decode_stream(&mut Decoder::new(), &data);
+        let decoded: Vec<Message> = decoded.into_iter().map(Result::unwrap).collect();
+        assert_eq!(decoded, every_message());
+    }
+
+    #[test]
+    fn worst_case_payload_fits_max_len() {
+        let mut encoder = Encoder::<Payload>::new();
+        for len in [0, 1, 252, 253, 254, 255, 507, 508, 509, 600] {
+            let payload = Payload((0..len).map(|i| (i % 255 + 1) as u8).collect());
+            let mut serialized = [0u8; Payload::POSTCARD_MAX_SIZE];
+            let serialized_len = postcard::to_slice(&payload, &mut serialized).unwrap().len();
+
+            let mut frame = encoder.encode(&payload).unwrap().to_vec();
+            assert!(frame.len() <= max_encoded_len(serialized_len), "{} byte payload", len);
+            assert!(frame.len() <= max_frame_len::<Payload>(), "{} byte payload", len);
+            assert_eq!(decode::<Payload>(&mut frame).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn unknown_variant_is_unknown_message() {
+        // a message from a newer peer, with a variant index past the ones known here
+        let mut frame = Encoder::<u8>::new().encode(&0x7F).unwrap().to_vec();
+        assert!(matches!(decode::<Message>(&mut frame), Err(Error::UnknownMessage)));
+
+        let mut frame = Encoder::<(u8, u8)>::new().encode(&(0, 0x7F)).unwrap().to_vec();
+        assert!(matches!(decode::<Message>(&mut frame), Err(Error::UnknownMessage)));
+    }
+
+    #[test]
+    fn unterminated_frame_is_rejected() {
+        let mut frame = frame(&enable());
+        frame.pop();
+        assert!(matches!(decode::<Message>(&mut frame), Err(Error::Unterminated)));
+    }
+}
@@ -0,0 +1,92 @@
+//! Measurements of how well the robot has been answering.
+//!
+//! Every request feeds into [`Health`], and a [`Pinger`] sends requests periodically so the
+//! measurements stay current while nothing else is being asked.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::Client;
+
+/// Number of recent requests packet loss is measured over
+const LOSS_WINDOW: usize = 20;
+
+#[derive(Clone, Debug, Default)]
+pub struct Health {
+    /// When anything was last received from the robot
+    pub last_received: Option<Instant>,
+    /// Round-trip time of the most recently answered request
+    pub rtt: Option<Duration>,
+    /// Smoothed difference between consecutive round-trip times, as defined for RTP in RFC 3550
+    pub jitter: Option<Duration>,
+    pub requests_answered: u64,
+    pub requests_timed_out: u64,
+    /// Whether each of the most recent requests was answered, oldest first
+    recent: VecDeque<bool>,
+}
+
+impl Health {
+    pub fn since_last_received(&self) -> Option<Duration> {
+        self.last_received.map(|time| time.elapsed())
+    }
+
+    /// Fraction of the recent requests that went unanswered, or `None` until one finishes
+    pub fn loss(&self) -> Option<f32> {
+        if self.recent.is_empty() {
+            return None
+        }
+        let lost = self.recent.iter().filter(|&&answered| !answered).count();
+        Some(lost as f32 / self.recent.len() as f32)
+    }
+
+    pub(crate) fn answered(&mut self, rtt: Duration) {
+        if let Some(last) = self.rtt {
+            let difference = (rtt.max(last) - rtt.min(last)).as_secs_f64();
+            let jitter = self.jitter.unwrap_or_default().as_secs_f64();
+            self.jitter = Some(Duration::from_secs_f64(jitter + (difference - jitter) / 16.0));
+        }
+        self.rtt = Some(rtt);
+        self.requests_answered += 1;
+        self.record(true);
+    }
+
+    pub(crate) fn timed_out(&mut self) {
+        self.requests_timed_out += 1;
+        self.record(false);
+    }
+
+    fn record(&mut self, answered: bool) {
+        if self.recent.len() == LOSS_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(answered);
+    }
+}
+
+/// Handle to a task pinging the robot, which stops once this is dropped
+pub struct Pinger {
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Pinger {
+    pub(crate) fn start(client: Client, period: Duration) -> Self {
+        let task = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(period);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                // failures are already counted in the client's health
+                if let Err(e) = client.ping().await {
+                    log::debug!("Health check ping failed: {}", e);
+                }
+            }
+        });
+        Self { task }
+    }
+}
+
+impl Drop for Pinger {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
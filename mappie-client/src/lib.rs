@@ -0,0 +1,447 @@
+//! Async client for the robot's control protocol, independent of any user interface.
+//!
+//! A [`Client`] owns the UDP socket to one robot. A background task reads everything the robot
+//! sends, keeping the latest status and handing responses to the requests waiting for them, so
+//! clones of the client can send commands and requests concurrently. Commands that have to keep
+//! flowing, like movement, are sent at a fixed rate by a [`CommandSender`].
+//!
+//! The robot reports its status to whichever operator it heard from last, so statuses only
+//! arrive while this client keeps sending something.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use messages::rpc::PendingRequests;
+use messages::{Command, Message, Mode, ParamInfo, Request, RequestError, RequestId, Response, Status};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::sync::{oneshot, watch};
+
+pub mod health;
+pub mod sender;
+
+pub use health::{Health, Pinger};
+pub use sender::{CommandSender, SendStats};
+
+/// How long to wait for the robot to answer a request, unless configured otherwise
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often [`Client::set_mode`] resends its command until the robot reports the new mode
+const MODE_RESEND_PERIOD: Duration = Duration::from_millis(50);
+
+/// How often the ping checking that an address is live is resent while connecting
+const HANDSHAKE_RESEND_PERIOD: Duration = Duration::from_millis(250);
+
+/// Id of the pings sent while connecting. Request ids count up from zero, so a late answer to
+/// one of these won't be mistaken for the answer to a real request.
+const HANDSHAKE_ID: RequestId = RequestId::MAX;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to resolve {0}")]
+    Resolve(String, #[source] io::Error),
+    #[error("{0} didn't resolve to any usable addresses")]
+    NoAddresses(String),
+    #[error("The robot didn't answer at any of the addresses {0} resolved to ({1:?})")]
+    NoAnswer(String, Vec<SocketAddr>),
+    #[error("Failed to open a socket to {0}")]
+    Socket(SocketAddr, #[source] io::Error),
+    #[error("Failed to send to the robot")]
+    Send(#[source] io::Error),
+    #[error("Failed to serialize a message")]
+    Encode(#[from] framing::Error),
+    #[error("The robot didn't answer within {0:?}")]
+    Timeout(Duration),
+    #[error("The robot refused the request: {0:?}")]
+    Refused(RequestError),
+    #[error("Unexpected response from the robot: {0:?}")]
+    UnexpectedResponse(Box<Response>),
+    #[error("The robot is still {0:?}")]
+    ModeNotReached(Mode),
+}
+
+impl Error {
+    /// Error for a response that doesn't answer the request it belongs to
+    pub fn unexpected(response: Response) -> Self {
+        match response {
+            Response::Error(e) => Error::Refused(e),
+            response => Error::UnexpectedResponse(Box::new(response)),
+        }
+    }
+}
+
+/// Whether the robot being in `mode` means `command` took effect
+pub fn mode_reached(command: Command, mode: Mode) -> bool {
+    match command {
+        Command::Enable => mode == Mode::Enabled,
+        Command::Disable => mode != Mode::Enabled,
+        Command::EStop => mode == Mode::EStopped,
+        Command::ClearEStop => mode != Mode::EStopped,
+        Command::Move(_) => true,
+    }
+}
+
+/// Connection to a robot. Clones share the connection, which closes once every clone is dropped.
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Address the client was asked to connect to, resolved again on reconnect
+    target: String,
+    /// Local address to send from, if not left to the OS
+    local: Option<IpAddr>,
+    request_timeout: Duration,
+    socket: watch::Sender<Arc<UdpSocket>>,
+    encoder: Mutex<framing::Encoder<Message>>,
+    shared: Arc<Shared>,
+}
+
+/// State updated by the receiving task
+struct Shared {
+    requests: Mutex<PendingRequests<oneshot::Sender<Response>>>,
+    status: watch::Sender<Option<Status>>,
+    health: Mutex<Health>,
+}
+
+impl Client {
+    /// Connects to `target`, given as an address or hostname with a port. Every address it
+    /// resolves to is tried in turn until the robot answers a ping at one of them.
+    ///
+    /// `local` picks the address, and so the interface, to send from. Otherwise the OS picks one
+    /// of the right address family.
+    pub async fn connect(target: &str, local: Option<IpAddr>, request_timeout: Duration) -> Result<Self, Error> {
+        let socket = open(target, local, request_timeout).await?;
+        let (socket_tx, socket_rx) = watch::channel(Arc::new(socket));
+        let shared = Arc::new(Shared {
+            requests: Mutex::new(PendingRequests::new(request_timeout)),
+            status: watch::channel(None).0,
+            health: Mutex::new(Health::default()),
+        });
+        tokio::spawn(receive(socket_rx, Arc::clone(&shared)));
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                target: target.to_string(),
+                local,
+                request_timeout,
+                socket: socket_tx,
+                encoder: Mutex::new(framing::Encoder::new()),
+                shared,
+            }),
+        })
+    }
+
+    /// Resolves the robot's address again and replaces the socket once the robot answers, e.g.
+    /// after the robot moved to another address or the network changed. Command senders follow
+    /// the new socket.
+    pub async fn reconnect(&self) -> Result<(), Error> {
+        let socket = open(&self.inner.target, self.inner.local, self.inner.request_timeout).await?;
+        self.inner.socket.send_replace(Arc::new(socket));
+        Ok(())
+    }
+
+    pub fn target(&self) -> &str {
+        &self.inner.target
+    }
+
+    /// The address the robot resolved to
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.socket.borrow().peer_addr().ok()
+    }
+
+    /// The latest status the robot reported, if any
+    pub fn status(&self) -> Option<Status> {
+        *self.inner.shared.status.borrow()
+    }
+
+    /// Receives every status the robot reports from now on
+    pub fn subscribe(&self) -> watch::Receiver<Option<Status>> {
+        self.inner.shared.status.subscribe()
+    }
+
+    /// The local address the client was asked to connect from, if any
+    pub fn local(&self) -> Option<IpAddr> {
+        self.inner.local
+    }
+
+    pub fn health(&self) -> Health {
+        lock(&self.inner.shared.health).clone()
+    }
+
+    /// Starts pinging the robot every `period`, until the pinger is dropped, so its health is
+    /// measured even when nothing else is requested
+    pub fn start_pinging(&self, period: Duration) -> Pinger {
+        Pinger::start(self.clone(), period)
+    }
+
+    /// Starts sending movement and mode changes at a fixed rate, until the sender is dropped. The
+    /// rate is limited as in [`CommandSender::set_rate`].
+    pub fn command_sender(&self, rate_hz: f32) -> CommandSender {
+        CommandSender::start(self.inner.socket.subscribe(), rate_hz)
+    }
+
+    /// Sends a single command. Datagrams can be lost, so anything that matters should be resent
+    /// until the robot's status shows it arrived.
+    pub async fn send(&self, command: Command) -> Result<(), Error> {
+        self.send_message(&Message::Command(command)).await
+    }
+
+    /// Sends a request and waits for the robot to answer it
+    pub async fn request(&self, request: Request) -> Result<Response, Error> {
+        let shared = &self.inner.shared;
+        let (answer_tx, answer_rx) = oneshot::channel();
+        let sent = Instant::now();
+        let id = lock(&shared.requests).start(sent, answer_tx);
+        if let Err(e) = self.send_message(&Message::Request { id, request }).await {
+            lock(&shared.requests).resolve(id);
+            return Err(e)
+        }
+
+        match tokio::time::timeout(self.inner.request_timeout, answer_rx).await {
+            Ok(Ok(response)) => {
+                lock(&shared.health).answered(sent.elapsed());
+                Ok(response)
+            }
+            _ => {
+                lock(&shared.requests).resolve(id);
+                lock(&shared.health).timed_out();
+                Err(Error::Timeout(self.inner.request_timeout))
+            }
+        }
+    }
+
+    /// Checks that the robot is answering, returning the round-trip time
+    pub async fn ping(&self) -> Result<Duration, Error> {
+        let sent = Instant::now();
+        match self.request(Request::Ping).await? {
+            Response::Pong => Ok(sent.elapsed()),
+            response => Err(Error::unexpected(response)),
+        }
+    }
+
+    /// Waits for a status reported after this is called. Pings the robot first, so it reports
+    /// to this client.
+    pub async fn next_status(&self) -> Result<Status, Error> {
+        let mut statuses = self.subscribe();
+        statuses.borrow_and_update();
+        self.ping().await?;
+
+        let timeout = self.inner.request_timeout;
+        match tokio::time::timeout(timeout, statuses.changed()).await {
+            Ok(Ok(())) => statuses.borrow().ok_or(Error::Timeout(timeout)),
+            _ => Err(Error::Timeout(timeout)),
+        }
+    }
+
+    /// Sends a mode change until the robot reports it, returning the robot's new mode
+    pub async fn set_mode(&self, command: Command) -> Result<Mode, Error> {
+        let mut statuses = self.subscribe();
+        statuses.borrow_and_update();
+        let deadline = tokio::time::Instant::now() + self.inner.request_timeout;
+        loop {
+            self.send(command).await?;
+
+            let resend = std::cmp::min(tokio::time::Instant::now() + MODE_RESEND_PERIOD, deadline);
+            while let Ok(Ok(())) = tokio::time::timeout_at(resend, statuses.changed()).await {
+                if let Some(status) = *statuses.borrow_and_update() {
+                    if mode_reached(command, status.mode) {
+                        return Ok(status.mode)
+                    }
+                }
+            }
+
+            if tokio::time::Instant::now() >= deadline {
+                return Err(match self.status() {
+                    Some(status) => Error::ModeNotReached(status.mode),
+                    None => Error::Timeout(self.inner.request_timeout),
+                })
+            }
+        }
+    }
+
+    /// Reads every parameter, one page at a time
+    pub async fn params(&self) -> Result<Vec<ParamInfo>, Error> {
+        let mut params = Vec::new();
+        loop {
+            let start = u16::try_from(params.len()).unwrap_or(u16::MAX);
+            match self.request(Request::ListParams { start }).await? {
+                Response::ParamList { total, params: page } => {
+                    if page.is_empty() || start >= total {
+                        return Ok(params)
+                    }
+                    params.extend(page);
+                    if params.len() >= usize::from(total) {
+                        return Ok(params)
+                    }
+                }
+                response => return Err(Error::unexpected(response)),
+            }
+        }
+    }
+
+    async fn send_message(&self, msg: &Message) -> Result<(), Error> {
+        let bytes = lock(&self.inner.encoder).encode(msg)?.to_vec();
+        let socket = Arc::clone(&self.inner.socket.borrow());
+        socket.send(&bytes).await.map_err(Error::Send)?;
+        Ok(())
+    }
+}
+
+/// Opens a socket to the first address `target` resolves to that the robot answers at
+async fn open(target: &str, local: Option<IpAddr>, timeout: Duration) -> Result<UdpSocket, Error> {
+    let remotes: Vec<SocketAddr> = tokio::net::lookup_host(target).await
+        .map_err(|e| Error::Resolve(target.to_string(), e))?
+        // sockets can only send to addresses of their own family
+        .filter(|remote| match local {
+            Some(local) => local.is_ipv4() == remote.is_ipv4(),
+            None => true,
+        })
+        .collect();
+    if remotes.is_empty() {
+        return Err(Error::NoAddresses(target.to_string()))
+    }
+    open_first(target, remotes, local, timeout).await
+}
+
+/// Opens a socket to the first of `remotes` the robot answers at, trying them in order
+async fn open_first(target: &str, remotes: Vec<SocketAddr>, local: Option<IpAddr>, timeout: Duration) -> Result<UdpSocket, Error> {
+    for &remote in &remotes {
+        let local = local.unwrap_or(match remote {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        let socket = UdpSocket::bind((local, 0)).await
+            .map_err(|e| Error::Socket(remote, e))?;
+        socket.connect(remote).await
+            .map_err(|e| Error::Socket(remote, e))?;
+
+        // connecting a UDP socket succeeds whether or not anything is listening
+        match tokio::time::timeout(timeout, handshake(&socket)).await {
+            Ok(Ok(())) => return Ok(socket),
+            Ok(Err(e)) => log::debug!("Robot isn't reachable at {}: {}", remote, e),
+            Err(_) => log::debug!("Robot didn't answer at {}", remote),
+        }
+    }
+    Err(Error::NoAnswer(target.to_string(), remotes))
+}
+
+/// Pings the robot until it answers
+async fn handshake(socket: &UdpSocket) -> io::Result<()> {
+    let mut encoder = framing::Encoder::new();
+    let msg = Message::Request { id: HANDSHAKE_ID, request: Request::Ping };
+    let ping = match encoder.encode(&msg) {
+        Ok(bytes) => bytes,
+        Err(e) => panic!("Unexpected serialization error ({}) on message: {:?}", e, msg),
+    };
+    let mut buffer = vec![0; framing::max_frame_len::<Message>()];
+    let mut resend = tokio::time::interval(HANDSHAKE_RESEND_PERIOD);
+
+    loop {
+        let len = tokio::select! {
+            _ = resend.tick() => {
+                socket.send(ping).await?;
+                continue
+            }
+            // fails right away if the robot's host says nothing is listening
+            received = socket.recv(&mut buffer) => received?,
+        };
+
+        if let Ok(Message::Response { id: HANDSHAKE_ID, response: Response::Pong }) = framing::decode(&mut buffer[..len]) {
+            return Ok(())
+        }
+    }
+}
+
+/// Reads messages from the robot until every clone of the client is dropped
+async fn receive(mut socket: watch::Receiver<Arc<UdpSocket>>, shared: Arc<Shared>) {
+    let mut buffer = vec![0; framing::max_frame_len::<Message>()];
+    loop {
+        let connection = Arc::clone(&socket.borrow_and_update());
+        let len = tokio::select! {
+            received = connection.recv(&mut buffer) => match received {
+                Ok(len) => len,
+                // e.g. nothing is listening at the robot's address yet
+                Err(e) => {
+                    log::debug!("Failed to receive from robot: {}", e);
+                    continue
+                }
+            },
+            changed = socket.changed() => {
+                if changed.is_err() {
+                    return
+                }
+                continue
+            }
+        };
+        lock(&shared.health).last_received = Some(Instant::now());
+
+        match framing::decode(&mut buffer[..len]) {
+            Ok(Message::Status(status)) => {
+                shared.status.send_replace(Some(status));
+            }
+            Ok(Message::Response { id, response }) => match lock(&shared.requests).resolve(id) {
+                // the requester may have stopped waiting since
+                Some(answer) => drop(answer.send(response)),
+                None => log::debug!("Ignoring late response {:?}", response),
+            },
+            Ok(msg) => log::debug!("Ignoring unexpected message from robot: {:?}", msg),
+            Err(framing::Error::UnknownMessage) => {
+                log::debug!("Skipping unknown message from robot");
+            }
+            Err(e) => log::warn!("Received invalid message from robot: {}", e),
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers pings at a loopback address until dropped
+    async fn ponger() -> (SocketAddr, tokio::task::JoinHandle<()>) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let mut encoder = framing::Encoder::new();
+            let mut buffer = vec![0; framing::max_frame_len::<Message>()];
+            loop {
+                let (len, from) = socket.recv_from(&mut buffer).await.unwrap();
+                if let Ok(Message::Request { id, request: Request::Ping }) = framing::decode(&mut buffer[..len]) {
+                    let pong = encoder.encode(&Message::Response { id, response: Response::Pong }).unwrap();
+                    socket.send_to(pong, from).await.unwrap();
+                }
+            }
+        });
+        (addr, task)
+    }
+
+    #[tokio::test]
+    async fn skips_addresses_the_robot_doesnt_answer_at() {
+        // nothing is listening here, so the host refuses
+        let refused = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap().local_addr().unwrap();
+        // something is listening here, but never answers
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let (live, ponger) = ponger().await;
+
+        let remotes = vec![refused, silent.local_addr().unwrap(), live];
+        let socket = open_first("robot", remotes, None, Duration::from_millis(200)).await.unwrap();
+        assert_eq!(socket.peer_addr().unwrap(), live);
+        ponger.abort();
+    }
+
+    #[tokio::test]
+    async fn fails_when_no_address_answers() {
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let remotes = vec![silent.local_addr().unwrap()];
+        let result = open_first("robot", remotes.clone(), None, Duration::from_millis(100)).await;
+        assert!(matches!(result, Err(Error::NoAnswer(target, tried)) if target == "robot" && tried == remotes));
+    }
+}
@@ -0,0 +1,198 @@
+//! Sends commands to the robot at a fixed rate, independent of how often the UI redraws.
+//!
+//! The caller only updates the latest commands through [`CommandSender`], and a background task
+//! sends whatever is current on every tick. A mode change is sent every tick until it's cleared,
+//! since datagrams can be lost.
+
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use messages::{Command, Message, Move};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+
+pub const DEFAULT_SEND_RATE_HZ: f32 = 50.0;
+pub const MIN_SEND_RATE_HZ: f32 = 1.0;
+pub const MAX_SEND_RATE_HZ: f32 = 200.0;
+
+/// How often the measured send rate is updated
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Outgoing {
+    movement: Move,
+    mode_request: Option<Command>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SendStats {
+    /// Messages handed to the network
+    pub sent: u64,
+    /// Messages dropped because the socket's send buffer was full
+    pub skipped: u64,
+    /// Messages that failed to send for any other reason
+    pub errors: u64,
+    /// Messages sent per second, measured over the last second
+    pub rate_hz: f32,
+    pub last_error: Option<String>,
+}
+
+/// Handle to the sending task, which stops once this is dropped
+pub struct CommandSender {
+    outgoing: watch::Sender<Outgoing>,
+    rate_hz: watch::Sender<f32>,
+    stats: Arc<Mutex<SendStats>>,
+}
+
+impl CommandSender {
+    pub(crate) fn start(socket: watch::Receiver<Arc<UdpSocket>>, rate_hz: f32) -> Self {
+        let (outgoing_tx, outgoing_rx) = watch::channel(Outgoing {
+            movement: Move::stop(),
+            mode_request: None,
+        });
+        let (rate_tx, rate_rx) = watch::channel(rate_hz);
+        let stats = Arc::new(Mutex::new(SendStats::default()));
+        tokio::spawn(send_commands(socket, outgoing_rx, rate_rx, Arc::clone(&stats)));
+
+        Self {
+            outgoing: outgoing_tx,
+            rate_hz: rate_tx,
+            stats,
+        }
+    }
+
+    /// Replaces the movement sent on every tick
+    pub fn set_move(&self, movement: Move) {
+        self.outgoing.send_if_modified(|outgoing| {
+            let modified = outgoing.movement != movement;
+            outgoing.movement = movement;
+            modified
+        });
+    }
+
+    /// Replaces the mode change sent on every tick, if any
+    pub fn set_mode_request(&self, mode_request: Option<Command>) {
+        self.outgoing.send_if_modified(|outgoing| {
+            let modified = outgoing.mode_request != mode_request;
+            outgoing.mode_request = mode_request;
+            modified
+        });
+    }
+
+    /// Changes how often commands are sent. Rates outside of [`MIN_SEND_RATE_HZ`] and
+    /// [`MAX_SEND_RATE_HZ`] are clamped to them, and NaN falls back to [`DEFAULT_SEND_RATE_HZ`].
+    pub fn set_rate(&self, rate_hz: f32) {
+        self.rate_hz.send_replace(rate_hz);
+    }
+
+    pub fn stats(&self) -> SendStats {
+        lock(&self.stats).clone()
+    }
+}
+
+fn lock(stats: &Mutex<SendStats>) -> std::sync::MutexGuard<'_, SendStats> {
+    stats.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn period(rate_hz: f32) -> Duration {
+    // clamping passes NaN through, and it can't be turned into a duration
+    let rate_hz = if rate_hz.is_nan() { DEFAULT_SEND_RATE_HZ } else { rate_hz };
+    Duration::from_secs_f32(1.0 / rate_hz.clamp(MIN_SEND_RATE_HZ, MAX_SEND_RATE_HZ))
+}
+
+fn interval(rate_hz: f32) -> tokio::time::Interval {
+    let mut interval = tokio::time::interval(period(rate_hz));
+    // sending a burst of stale commands to catch up doesn't help anyone
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+async fn send_commands(
+    socket: watch::Receiver<Arc<UdpSocket>>,
+    outgoing: watch::Receiver<Outgoing>,
+    mut rate_hz: watch::Receiver<f32>,
+    stats: Arc<Mutex<SendStats>>,
+) {
+    let mut encoder = framing::Encoder::<Message>::new();
+    let mut ticker = interval(*rate_hz.borrow_and_update());
+    let mut window_start = Instant::now();
+    let mut window_sent = 0;
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            changed = rate_hz.changed() => {
+                if changed.is_err() {
+                    return
+                }
+                ticker = interval(*rate_hz.borrow_and_update());
+                continue
+            }
+        }
+
+        // either the handle or the client was dropped
+        if outgoing.has_changed().is_err() || socket.has_changed().is_err() {
+            return
+        }
+        let Outgoing { movement, mode_request } = *outgoing.borrow();
+        // follows the client when it reconnects
+        let connection = Arc::clone(&socket.borrow());
+
+        let commands = mode_request.into_iter().chain([Command::Move(movement)]);
+        for command in commands {
+            let msg = Message::Command(command);
+            let bytes = match encoder.encode(&msg) {
+                Ok(bytes) => bytes,
+                Err(e) => panic!("Unexpected serialization error ({}) on message: {:?}", e, msg),
+            };
+
+            let result = connection.try_send(bytes);
+            let mut stats = lock(&stats);
+            match result {
+                Ok(_) => {
+                    stats.sent += 1;
+                    window_sent += 1;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => stats.skipped += 1,
+                Err(e) => {
+                    let error = e.to_string();
+                    // only log new kinds of errors, since they tend to repeat every tick
+                    if stats.last_error.as_ref() != Some(&error) {
+                        log::error!("Failed to send command to robot: {}", error);
+                    }
+                    stats.errors += 1;
+                    stats.last_error = Some(error);
+                }
+            }
+        }
+
+        let elapsed = window_start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            lock(&stats).rate_hz = window_sent as f32 / elapsed.as_secs_f32();
+            window_start = Instant::now();
+            window_sent = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn period_follows_rate() {
+        assert_eq!(period(50.0), Duration::from_millis(20));
+        assert_eq!(period(0.0), period(MIN_SEND_RATE_HZ));
+        assert_eq!(period(-5.0), period(MIN_SEND_RATE_HZ));
+        assert_eq!(period(1e6), period(MAX_SEND_RATE_HZ));
+    }
+
+    #[test]
+    fn period_survives_non_finite_rates() {
+        assert_eq!(period(f32::NAN), period(DEFAULT_SEND_RATE_HZ));
+        assert_eq!(period(f32::INFINITY), period(MAX_SEND_RATE_HZ));
+        assert_eq!(period(f32::NEG_INFINITY), period(MIN_SEND_RATE_HZ));
+    }
+}
@@ -0,0 +1,279 @@
+//! Runs the client against a simulated robot listening on loopback.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use mappie_client::{Client, Error};
+use messages::{
+    Command, DriveStatus, Message, Mode, ParamInfo, ParamName, ParamValue, Request, RequestError, Response,
+    Status, PARAM_LIST_PAGE_LEN,
+};
+use tokio::net::UdpSocket;
+
+const TIMEOUT: Duration = Duration::from_millis(300);
+
+struct State {
+    mode: Mode,
+    params: Vec<ParamInfo>,
+    /// Whether requests go unanswered, as if they were lost
+    ignore_requests: bool,
+    /// Number of enable commands still to drop, as if they were lost
+    enables_to_drop: u32,
+    enables: u32,
+    list_requests: u32,
+    /// Where each movement command came from
+    move_sources: Vec<SocketAddr>,
+}
+
+/// Robot answering requests and reporting its status after every message, until dropped
+struct Robot {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Robot {
+    async fn start(params: Vec<ParamInfo>) -> Self {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State {
+            mode: Mode::Disabled,
+            params,
+            ignore_requests: false,
+            enables_to_drop: 0,
+            enables: 0,
+            list_requests: 0,
+            move_sources: Vec::new(),
+        }));
+        let task = tokio::spawn(serve(socket, Arc::clone(&state)));
+        Self { addr, state, task }
+    }
+
+    fn target(&self) -> String {
+        self.addr.to_string()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Drop for Robot {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(socket: UdpSocket, state: Arc<Mutex<State>>) {
+    let mut encoder = framing::Encoder::new();
+    let mut buffer = vec![0; framing::max_frame_len::<Message>()];
+    loop {
+        let (len, from) = socket.recv_from(&mut buffer).await.unwrap();
+        let msg = framing::decode(&mut buffer[..len]).unwrap();
+
+        let (response, status) = {
+            let mut state = state.lock().unwrap();
+            let response = match msg {
+                Message::Command(command) => {
+                    handle_command(&mut state, from, command);
+                    None
+                }
+                Message::Request { .. } if state.ignore_requests => None,
+                Message::Request { id, request } => Some(Message::Response { id, response: answer(&mut state, request) }),
+                msg => panic!("Unexpected message from client: {:?}", msg),
+            };
+            (response, status(state.mode))
+        };
+
+        for msg in response.into_iter().chain([Message::Status(status)]) {
+            socket.send_to(encoder.encode(&msg).unwrap(), from).await.unwrap();
+        }
+    }
+}
+
+fn handle_command(state: &mut State, from: SocketAddr, command: Command) {
+    match command {
+        Command::Move(_) => state.move_sources.push(from),
+        Command::Enable => {
+            state.enables += 1;
+            if state.enables_to_drop > 0 {
+                state.enables_to_drop -= 1;
+            } else {
+                state.mode = Mode::Enabled;
+            }
+        }
+        Command::Disable => state.mode = Mode::Disabled,
+        Command::EStop => state.mode = Mode::EStopped,
+        Command::ClearEStop => state.mode = Mode::Disabled,
+    }
+}
+
+fn answer(state: &mut State, request: Request) -> Response {
+    match request {
+        Request::Ping => Response::Pong,
+        Request::ListParams { start } => {
+            state.list_requests += 1;
+            let params = state.params.iter().skip(start.into()).take(PARAM_LIST_PAGE_LEN).cloned().collect();
+            Response::ParamList { total: state.params.len() as u16, params }
+        }
+        Request::GetParam(name) => match state.params.iter().find(|param| param.name == name) {
+            Some(param) => Response::Param(param.value),
+            None => Response::Error(RequestError::UnknownParam),
+        },
+        Request::SetParam(name, value) => match state.params.iter_mut().find(|param| param.name == name) {
+            Some(param) => {
+                param.value = value;
+                Response::Param(value)
+            }
+            None => Response::Error(RequestError::UnknownParam),
+        },
+        Request::SaveParams => Response::Saved,
+    }
+}
+
+fn status(mode: Mode) -> Status {
+    Status {
+        mode,
+        drive: DriveStatus::Ok,
+        drive_errors: 0,
+        link_losses: 0,
+        superseded_moves: 0,
+        motor_transactions_per_tick: 0.0,
+    }
+}
+
+fn name(name: &str) -> ParamName {
+    let mut param_name = ParamName::new();
+    param_name.push_str(name).unwrap();
+    param_name
+}
+
+fn param(param_name: &str, value: i32) -> ParamInfo {
+    ParamInfo {
+        name: name(param_name),
+        value: ParamValue::Int(value),
+        default: ParamValue::Int(value),
+        range: None,
+        choices: heapless::Vec::new(),
+    }
+}
+
+async fn connect(robot: &Robot) -> Client {
+    Client::connect(&robot.target(), None, TIMEOUT).await.unwrap()
+}
+
+#[tokio::test]
+async fn connects_and_pings() {
+    let robot = Robot::start(Vec::new()).await;
+    let client = connect(&robot).await;
+    assert_eq!(client.remote_addr(), Some(robot.addr));
+
+    client.ping().await.unwrap();
+    let health = client.health();
+    assert_eq!(health.requests_answered, 1);
+    assert_eq!(health.requests_timed_out, 0);
+    assert!(health.rtt.is_some());
+}
+
+#[tokio::test]
+async fn fails_to_connect_when_nothing_answers() {
+    let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let target = silent.local_addr().unwrap().to_string();
+    let result = Client::connect(&target, None, TIMEOUT).await;
+    assert!(matches!(result, Err(Error::NoAnswer(..))), "{:?}", result.err());
+}
+
+#[tokio::test]
+async fn answers_requests() {
+    let robot = Robot::start(vec![param("speed", 3)]).await;
+    let client = connect(&robot).await;
+
+    let response = client.request(Request::GetParam(name("speed"))).await.unwrap();
+    assert_eq!(response, Response::Param(ParamValue::Int(3)));
+
+    let response = client.request(Request::SetParam(name("speed"), ParamValue::Int(5))).await.unwrap();
+    assert_eq!(response, Response::Param(ParamValue::Int(5)));
+    assert_eq!(robot.state().params[0].value, ParamValue::Int(5));
+
+    let response = client.request(Request::GetParam(name("missing"))).await.unwrap();
+    assert_eq!(response, Response::Error(RequestError::UnknownParam));
+}
+
+#[tokio::test]
+async fn times_out_unanswered_requests() {
+    let robot = Robot::start(Vec::new()).await;
+    let client = connect(&robot).await;
+    robot.state().ignore_requests = true;
+
+    let result = client.ping().await;
+    assert!(matches!(result, Err(Error::Timeout(TIMEOUT))), "{:?}", result);
+    let health = client.health();
+    assert_eq!(health.requests_timed_out, 1);
+    assert_eq!(health.loss(), Some(1.0));
+
+    // requests are answered again once the robot is
+    robot.state().ignore_requests = false;
+    client.ping().await.unwrap();
+    assert_eq!(client.health().requests_answered, 1);
+}
+
+#[tokio::test]
+async fn resends_mode_changes_until_reported() {
+    let robot = Robot::start(Vec::new()).await;
+    let client = connect(&robot).await;
+    robot.state().enables_to_drop = 3;
+
+    assert_eq!(client.set_mode(Command::Enable).await.unwrap(), Mode::Enabled);
+    assert_eq!(robot.state().enables, 4);
+    assert_eq!(client.status().map(|status| status.mode), Some(Mode::Enabled));
+}
+
+#[tokio::test]
+async fn reports_mode_changes_that_dont_happen() {
+    let robot = Robot::start(Vec::new()).await;
+    let client = connect(&robot).await;
+    robot.state().enables_to_drop = u32::MAX;
+
+    let result = client.set_mode(Command::Enable).await;
+    assert!(matches!(result, Err(Error::ModeNotReached(Mode::Disabled))), "{:?}", result);
+    assert!(robot.state().enables > 1);
+}
+
+#[tokio::test]
+async fn reads_every_page_of_params() {
+    let params: Vec<_> = (0..5).map(|i| param(&format!("param{}", i), i)).collect();
+    let robot = Robot::start(params.clone()).await;
+    let client = connect(&robot).await;
+
+    assert_eq!(client.params().await.unwrap(), params);
+    assert_eq!(robot.state().list_requests, 3);
+}
+
+#[tokio::test]
+async fn command_sender_follows_reconnects() {
+    let robot = Robot::start(Vec::new()).await;
+    let client = connect(&robot).await;
+    let _sender = client.command_sender(100.0);
+
+    let first = wait_for_move(&robot, None).await;
+    client.reconnect().await.unwrap();
+    let second = wait_for_move(&robot, Some(first)).await;
+    assert_ne!(first, second);
+}
+
+/// Waits for a movement command sent from anywhere other than `except`, returning where it came
+/// from
+async fn wait_for_move(robot: &Robot, except: Option<SocketAddr>) -> SocketAddr {
+    tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            let source = robot.state().move_sources.iter().rev().copied().find(|&source| Some(source) != except);
+            if let Some(source) = source {
+                return source
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("No movement commands arrived")
+}
@@ -0,0 +1,483 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use eyre::{eyre, Result, WrapErr};
+use mappie_client::Client;
+use messages::{Command, Mode, Move, ParamInfo, ParamValue, Request, RequestError, Response};
+use serde_json::json;
+
+/// How many ticks the final stop of a timed move is sent for, in case some are lost
+const STOP_REPEATS: u32 = 3;
+
+#[derive(Parser, Clone, Debug)]
+#[command(author, version, about)]
+/// Sends one-off commands and requests to the robot, for scripting and quick checks.
+///
+/// Works with anything listening on the robot's control port, including a robot running on
+/// localhost.
+struct Args {
+    /// Robot to talk to, as an address or hostname and port
+    #[arg(short, long, default_value = "rpi:9090")]
+    robot: String,
+
+    /// Print results as JSON instead of text
+    #[arg(short, long)]
+    json: bool,
+
+    /// Local address to send from, which picks the network interface to use
+    #[arg(short, long)]
+    bind: Option<IpAddr>,
+
+    /// How long to wait for the robot to answer, in milliseconds
+    #[arg(short, long, default_value_t = 1000)]
+    timeout_ms: u64,
+
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum Cmd {
+    /// Check that the robot is reachable and measure the round-trip time
+    Ping {
+        /// Number of pings to send
+        #[arg(short, long, default_value_t = 1)]
+        count: u32,
+
+        /// Time between pings, in milliseconds
+        #[arg(short, long, default_value_t = 200)]
+        interval_ms: u64,
+    },
+    /// Print the robot's mode and telemetry
+    Status,
+    /// Allow the robot to drive
+    Enable,
+    /// Stop the robot and ignore movement until it's enabled again
+    Disable,
+    /// Immediately stop the robot and latch the e-stop
+    Estop,
+    /// Release a latched e-stop, leaving the robot disabled
+    ClearEstop,
+    /// Drive the robot, which must be enabled first.
+    ///
+    /// Without a duration a single command is sent, and the robot stops on its own once its
+    /// command timeout expires.
+    Move {
+        /// Sideways speed, from -1 to 1
+        #[arg(short, long, default_value_t = 0.0, allow_negative_numbers = true, value_parser = speed)]
+        x: f32,
+
+        /// Forward speed, from -1 to 1
+        #[arg(short, long, default_value_t = 0.0, allow_negative_numbers = true, value_parser = speed)]
+        y: f32,
+
+        /// Rotation speed, from -1 to 1
+        #[arg(short = 'w', long, default_value_t = 0.0, allow_negative_numbers = true, value_parser = speed)]
+        rotate: f32,
+
+        /// Keep driving for this long, in milliseconds, then stop
+        #[arg(short, long)]
+        duration_ms: Option<u64>,
+
+        /// How often the command is resent while driving for a duration
+        #[arg(long, default_value_t = 50.0)]
+        rate_hz: f32,
+    },
+    /// Read and change the robot's parameters
+    #[command(subcommand)]
+    Param(ParamCmd),
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum ParamCmd {
+    /// Print every parameter with its value, default and range
+    List,
+    /// Print one parameter's value
+    Get { name: String },
+    /// Change a parameter. Choices can be given by name.
+    Set {
+        name: String,
+        #[arg(allow_negative_numbers = true)]
+        value: String,
+    },
+    /// Save the current values to the robot's config file
+    Save,
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let args = Args::parse();
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .wrap_err("Asynchronous runtime construction failed")?
+        .block_on(run(args))
+}
+
+async fn run(args: Args) -> Result<()> {
+    let robot = Client::connect(&args.robot, args.bind, Duration::from_millis(args.timeout_ms)).await?;
+    let json = args.json;
+
+    match args.command {
+        Cmd::Ping { count, interval_ms } => ping(&robot, json, count, Duration::from_millis(interval_ms)).await,
+        Cmd::Status => {
+            let status = robot.next_status().await?;
+            if json {
+                println!("{}", serde_json::to_string(&status)?);
+            } else {
+                println!("mode: {:?}", status.mode);
+                println!("drive: {:?}", status.drive);
+                println!("drive errors: {}", status.drive_errors);
+                println!("link losses: {}", status.link_losses);
+                println!("superseded moves: {}", status.superseded_moves);
+                println!("motor I2C transactions per tick: {:.2}", status.motor_transactions_per_tick);
+            }
+            Ok(())
+        }
+        Cmd::Enable => set_mode(&robot, json, Command::Enable).await,
+        Cmd::Disable => set_mode(&robot, json, Command::Disable).await,
+        Cmd::Estop => set_mode(&robot, json, Command::EStop).await,
+        Cmd::ClearEstop => set_mode(&robot, json, Command::ClearEStop).await,
+        Cmd::Move { x, y, rotate, duration_ms, rate_hz } => {
+            let movement = Move {
+                translate: [x, y].into(),
+                rotate,
+            };
+            drive(&robot, json, movement, duration_ms.map(Duration::from_millis), rate_hz).await
+        }
+        Cmd::Param(cmd) => param(&robot, json, cmd).await,
+    }
+}
+
+async fn ping(robot: &Client, json: bool, count: u32, interval: Duration) -> Result<()> {
+    let addr = robot.remote_addr().map_or_else(|| robot.target().to_string(), |addr| addr.to_string());
+    let mut rtts = Vec::new();
+    for i in 0..count {
+        if i > 0 {
+            tokio::time::sleep(interval).await;
+        }
+
+        let rtt = match robot.ping().await {
+            Ok(rtt) => Some(rtt),
+            Err(mappie_client::Error::Timeout(_)) => None,
+            Err(e) => return Err(e.into()),
+        };
+        if !json {
+            match rtt {
+                Some(rtt) => println!("reply from {}: time={:.2} ms", addr, millis(rtt)),
+                None => println!("no reply from {}", addr),
+            }
+        }
+        rtts.push(rtt);
+    }
+
+    let replies: Vec<f64> = rtts.iter().flatten().copied().map(millis).collect();
+    if json {
+        let rtts: Vec<_> = rtts.iter().map(|rtt| rtt.map(millis)).collect();
+        println!("{}", json!({
+            "robot": addr,
+            "sent": count,
+            "received": replies.len(),
+            "rtt_ms": rtts,
+        }));
+    } else if count > 1 {
+        print!("{} sent, {} received", count, replies.len());
+        if !replies.is_empty() {
+            let min = replies.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = replies.iter().copied().fold(0.0, f64::max);
+            let avg = replies.iter().sum::<f64>() / replies.len() as f64;
+            print!(", rtt min/avg/max = {:.2}/{:.2}/{:.2} ms", min, avg, max);
+        }
+        println!();
+    }
+
+    if replies.is_empty() {
+        Err(eyre!("The robot at {} didn't answer", addr))
+    } else {
+        Ok(())
+    }
+}
+
+async fn set_mode(robot: &Client, json: bool, command: Command) -> Result<()> {
+    let mode = robot.set_mode(command).await?;
+    if json {
+        println!("{}", json!({ "mode": mode }));
+    } else {
+        println!("mode: {:?}", mode);
+    }
+    Ok(())
+}
+
+async fn drive(robot: &Client, json: bool, movement: Move, duration: Option<Duration>, rate_hz: f32) -> Result<()> {
+    let mode = robot.next_status().await?.mode;
+    if mode != Mode::Enabled {
+        return Err(eyre!("The robot is {:?}, so it won't move until it's enabled", mode))
+    }
+
+    let Some(duration) = duration else {
+        robot.send(Command::Move(movement)).await?;
+        if json {
+            println!("{}", json!({ "moves_sent": 1 }));
+        }
+        return Ok(())
+    };
+
+    if rate_hz.is_nan() || rate_hz <= 0.0 {
+        return Err(eyre!("The send rate must be positive"))
+    }
+    let sender = robot.command_sender(rate_hz);
+    sender.set_move(movement);
+    let interrupted = tokio::select! {
+        _ = tokio::time::sleep(duration) => false,
+        _ = tokio::signal::ctrl_c() => true,
+    };
+    let sent = sender.stats().sent;
+
+    sender.set_move(Move::stop());
+    tokio::time::sleep(Duration::from_secs_f32(1.0 / rate_hz) * STOP_REPEATS).await;
+
+    if json {
+        println!("{}", json!({ "moves_sent": sent, "interrupted": interrupted }));
+    } else if interrupted {
+        println!("interrupted after {} moves, stopped", sent);
+    } else {
+        println!("sent {} moves, stopped", sent);
+    }
+    Ok(())
+}
+
+async fn param(robot: &Client, json: bool, cmd: ParamCmd) -> Result<()> {
+    match cmd {
+        ParamCmd::List => {
+            let params = robot.params().await?;
+            if json {
+                let params: Vec<_> = params.iter().map(param_json).collect();
+                println!("{}", serde_json::Value::from(params));
+            } else {
+                for param in &params {
+                    print!("{} = {} (default {}", param.name, display(param, param.value), display(param, param.default));
+                    if let Some(range) = param.range {
+                        print!(", {} to {}", display(param, range.min), display(param, range.max));
+                    }
+                    if !param.choices.is_empty() {
+                        let choices: Vec<_> = param.choices.iter().map(|choice| choice.as_str()).collect();
+                        print!(", one of {}", choices.join(", "));
+                    }
+                    println!(")");
+                }
+            }
+        }
+        ParamCmd::Get { name } => {
+            let info = find_param(robot, &name).await?;
+            let value = match robot.request(Request::GetParam(info.name.clone())).await? {
+                Response::Param(value) => value,
+                response => return Err(response_error(&name, response)),
+            };
+            print_param(json, &info, value);
+        }
+        ParamCmd::Set { name, value } => {
+            let info = find_param(robot, &name).await?;
+            let value = parse_value(&info, &value)?;
+            let value = match robot.request(Request::SetParam(info.name.clone(), value)).await? {
+                Response::Param(value) => value,
+                response => return Err(response_error(&name, response)),
+            };
+            print_param(json, &info, value);
+        }
+        ParamCmd::Save => {
+            match robot.request(Request::SaveParams).await? {
+                Response::Saved => {}
+                response => return Err(response_error("parameters", response)),
+            }
+            if json {
+                println!("{}", json!({ "saved": true }));
+            } else {
+                println!("saved");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Looks a parameter up in the robot's list, which is needed to know how to parse and print its
+/// value
+async fn find_param(robot: &Client, name: &str) -> Result<ParamInfo> {
+    robot.params().await?
+        .into_iter()
+        .find(|param| param.name == name)
+        .ok_or_else(|| eyre!("The robot doesn't have a parameter named {}", name))
+}
+
+/// Parses a speed for `move`, which the robot only accepts from -1 to 1
+fn speed(value: &str) -> Result<f32, String> {
+    let speed: f32 = value.parse().map_err(|e| format!("{}", e))?;
+    if (-1.0..=1.0).contains(&speed) {
+        Ok(speed)
+    } else {
+        Err("must be from -1 to 1".to_string())
+    }
+}
+
+/// Parses `value` as the same type as the parameter's current value
+fn parse_value(param: &ParamInfo, value: &str) -> Result<ParamValue> {
+    let parsed = match param.value {
+        ParamValue::Bool(_) => value.parse().ok().map(ParamValue::Bool),
+        ParamValue::Int(_) => value.parse().ok().map(ParamValue::Int),
+        ParamValue::Float(_) => value.parse().ok().map(ParamValue::Float),
+        ParamValue::Choice(_) => param.choices.iter()
+            .position(|choice| choice == value)
+            .or_else(|| value.parse().ok())
+            .and_then(|i| u8::try_from(i).ok())
+            .map(ParamValue::Choice),
+    };
+    parsed.ok_or_else(|| eyre!("{:?} isn't a valid value for {}, which is currently {}",
+        value, param.name, display(param, param.value)))
+}
+
+fn print_param(json: bool, param: &ParamInfo, value: ParamValue) {
+    if json {
+        println!("{}", json!({ "name": param.name.as_str(), "value": value_json(param, value) }));
+    } else {
+        println!("{} = {}", param.name, display(param, value));
+    }
+}
+
+fn param_json(param: &ParamInfo) -> serde_json::Value {
+    let choices: Vec<_> = param.choices.iter().map(|choice| choice.as_str()).collect();
+    json!({
+        "name": param.name.as_str(),
+        "value": value_json(param, param.value),
+        "default": value_json(param, param.default),
+        "min": param.range.map(|range| value_json(param, range.min)),
+        "max": param.range.map(|range| value_json(param, range.max)),
+        "choices": choices,
+    })
+}
+
+/// Values as plain JSON, with choices by name
+fn value_json(param: &ParamInfo, value: ParamValue) -> serde_json::Value {
+    match value {
+        ParamValue::Bool(b) => b.into(),
+        ParamValue::Int(i) => i.into(),
+        // going through the shortest decimal keeps e.g. 0.2 from printing as 0.20000000298
+        ParamValue::Float(f) => f.to_string().parse::<f64>().map_or(serde_json::Value::Null, Into::into),
+        ParamValue::Choice(i) => match param.choices.get(usize::from(i)) {
+            Some(choice) => choice.as_str().into(),
+            None => i.into(),
+        },
+    }
+}
+
+fn display(param: &ParamInfo, value: ParamValue) -> String {
+    match value {
+        ParamValue::Bool(b) => b.to_string(),
+        ParamValue::Int(i) => i.to_string(),
+        ParamValue::Float(f) => f.to_string(),
+        ParamValue::Choice(i) => param.choices.get(usize::from(i))
+            .map_or_else(|| i.to_string(), |choice| choice.to_string()),
+    }
+}
+
+fn response_error(what: &str, response: Response) -> eyre::Report {
+    match response {
+        Response::Error(e) => eyre!("The robot refused the request for {}: {}", what, describe(e)),
+        response => mappie_client::Error::unexpected(response).into(),
+    }
+}
+
+fn describe(e: RequestError) -> &'static str {
+    match e {
+        RequestError::UnknownParam => "it doesn't have that parameter",
+        RequestError::InvalidValue => "the value has the wrong type or is out of range",
+        RequestError::SaveFailed => "it couldn't write its config file",
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use messages::{ParamName, ParamRange};
+
+    fn param(name: &str, value: ParamValue, choices: &[&str]) -> ParamInfo {
+        let mut param_name = ParamName::new();
+        param_name.push_str(name).unwrap();
+        ParamInfo {
+            name: param_name,
+            value,
+            default: value,
+            range: None,
+            choices: choices.iter().map(|&choice| choice.into()).collect(),
+        }
+    }
+
+    fn level() -> ParamInfo {
+        param("log.level", ParamValue::Choice(2), &["error", "warn", "info"])
+    }
+
+    #[test]
+    fn parses_choices_by_name_or_index() {
+        assert_eq!(parse_value(&level(), "warn").unwrap(), ParamValue::Choice(1));
+        assert_eq!(parse_value(&level(), "0").unwrap(), ParamValue::Choice(0));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(parse_value(&level(), "loud").is_err());
+        assert!(parse_value(&level(), "300").is_err());
+        assert!(parse_value(&param("enabled", ParamValue::Bool(true), &[]), "yes").is_err());
+        assert!(parse_value(&param("count", ParamValue::Int(1), &[]), "1.5").is_err());
+        assert!(parse_value(&param("speed", ParamValue::Float(1.0), &[]), "fast").is_err());
+    }
+
+    #[test]
+    fn parses_values_as_the_current_type() {
+        assert_eq!(parse_value(&param("enabled", ParamValue::Bool(true), &[]), "false").unwrap(), ParamValue::Bool(false));
+        assert_eq!(parse_value(&param("count", ParamValue::Int(1), &[]), "-3").unwrap(), ParamValue::Int(-3));
+        assert_eq!(parse_value(&param("speed", ParamValue::Float(1.0), &[]), "2").unwrap(), ParamValue::Float(2.0));
+    }
+
+    #[test]
+    fn floats_print_as_their_shortest_decimal() {
+        let speed = param("speed", ParamValue::Float(0.2), &[]);
+        assert_eq!(value_json(&speed, ParamValue::Float(0.2)).to_string(), "0.2");
+        assert_eq!(display(&speed, ParamValue::Float(0.2)), "0.2");
+    }
+
+    #[test]
+    fn choices_print_by_name() {
+        assert_eq!(value_json(&level(), ParamValue::Choice(1)), json!("warn"));
+        assert_eq!(display(&level(), ParamValue::Choice(1)), "warn");
+        // unknown choices fall back to their index
+        assert_eq!(value_json(&level(), ParamValue::Choice(7)), json!(7));
+        assert_eq!(display(&level(), ParamValue::Choice(7)), "7");
+    }
+
+    #[test]
+    fn param_json_includes_range() {
+        let mut count = param("count", ParamValue::Int(3), &[]);
+        count.range = Some(ParamRange { min: ParamValue::Int(0), max: ParamValue::Int(10) });
+        assert_eq!(param_json(&count), json!({
+            "name": "count",
+            "value": 3,
+            "default": 3,
+            "min": 0,
+            "max": 10,
+            "choices": [],
+        }));
+    }
+
+    #[test]
+    fn move_speeds_must_be_in_range() {
+        let parse = |args: &[&str]| Args::try_parse_from(["mappie-ctl", "move"].iter().chain(args));
+        assert!(parse(&["-x", "1", "-y", "-1", "-w", "0.5"]).is_ok());
+        assert!(parse(&["-x", "1.5"]).is_err());
+        assert!(parse(&["-y", "-2"]).is_err());
+        assert!(parse(&["-w", "NaN"]).is_err());
+    }
+}
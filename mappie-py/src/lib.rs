@@ -0,0 +1,355 @@
+//! Python bindings for driving the robot and reading its telemetry.
+//!
+//! Messages cross into Python as the same dicts `mappie-ctl --json` prints, e.g. a status is
+//! `{"mode": "Enabled", "drive": "Ok", ...}` and a command is `{"Command": "Enable"}`. The
+//! [`Client`] is synchronous: each call blocks until the robot answers, while a background thread
+//! keeps receiving statuses and sending movement.
+//!
+//! Build a wheel with `maturin build --release` from this directory, or install it into the
+//! current virtualenv with `maturin develop`. The tests in `tests/` run against a simulated robot
+//! with `pytest`, after installing the module.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use mappie_client::CommandSender;
+use messages::{Command, Message, Mode, ParamInfo, ParamValue, Request, Response};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyTimeoutError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+create_exception!(mappie_py, MappieError, PyException, "The robot couldn't carry out a call");
+
+/// Movement of the robot, with each speed from -1 to 1
+#[pyclass(name = "Move")]
+#[derive(Clone, Copy, Debug)]
+struct PyMove {
+    /// Sideways speed
+    #[pyo3(get, set)]
+    x: f32,
+    /// Forward speed
+    #[pyo3(get, set)]
+    y: f32,
+    #[pyo3(get, set)]
+    rotate: f32,
+}
+
+#[pymethods]
+impl PyMove {
+    #[new]
+    #[pyo3(signature = (x=0.0, y=0.0, rotate=0.0))]
+    fn new(x: f32, y: f32, rotate: f32) -> Self {
+        Self { x, y, rotate }
+    }
+
+    #[staticmethod]
+    fn stop() -> Self {
+        Self::new(0.0, 0.0, 0.0)
+    }
+
+    /// Encodes the command to drive with this movement, ready to send to the robot
+    fn encode<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        encode_message(py, &Message::Command(Command::Move((*self).into())))
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Move(x={}, y={}, rotate={})", self.x, self.y, self.rotate)
+    }
+}
+
+impl From<PyMove> for messages::Move {
+    fn from(movement: PyMove) -> Self {
+        Self {
+            translate: [movement.x, movement.y].into(),
+            rotate: movement.rotate,
+        }
+    }
+}
+
+/// Encodes a message into a frame. Accepts a `Move`, which is sent as a command, or a message
+/// dict.
+#[pyfunction]
+fn encode<'py>(message: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyBytes>> {
+    let py = message.py();
+    let message = match message.extract::<PyMove>() {
+        Ok(movement) => Message::Command(Command::Move(movement.into())),
+        Err(_) => from_py(message)?,
+    };
+    encode_message(py, &message)
+}
+
+/// Decodes a frame received from the robot into a message dict
+#[pyfunction]
+fn decode(py: Python<'_>, frame: Vec<u8>) -> PyResult<PyObject> {
+    let mut frame = frame;
+    let message: Message = framing::decode(&mut frame)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    to_py(py, &message)
+}
+
+fn encode_message<'py>(py: Python<'py>, message: &Message) -> PyResult<Bound<'py, PyBytes>> {
+    let mut encoder = framing::Encoder::new();
+    let frame = encoder.encode(message)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Ok(PyBytes::new_bound(py, frame))
+}
+
+/// Synchronous connection to a robot
+#[pyclass]
+struct Client {
+    runtime: tokio::runtime::Runtime,
+    client: mappie_client::Client,
+    /// Started by the first call to `drive`
+    sender: Option<CommandSender>,
+}
+
+#[pymethods]
+impl Client {
+    /// Connects to `target`, an address or hostname with a port, once the robot answers a ping.
+    /// `timeout` is how long to wait for the robot to answer, in seconds, and `bind` is the local
+    /// address to send from.
+    #[new]
+    #[pyo3(signature = (target, timeout=1.0, bind=None))]
+    fn new(py: Python<'_>, target: &str, timeout: f64, bind: Option<&str>) -> PyResult<Self> {
+        let timeout = Duration::try_from_secs_f64(timeout)
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let local = bind.map(|local| local.parse::<IpAddr>())
+            .transpose()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        // a worker thread keeps receiving statuses between calls
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let client = py.allow_threads(|| runtime.block_on(mappie_client::Client::connect(target, local, timeout)))
+            .map_err(error)?;
+
+        Ok(Self {
+            runtime,
+            client,
+            sender: None,
+        })
+    }
+
+    /// Checks that the robot is answering, returning the round-trip time in seconds
+    fn ping(&self, py: Python<'_>) -> PyResult<f64> {
+        let rtt = py.allow_threads(|| self.runtime.block_on(self.client.ping())).map_err(error)?;
+        Ok(rtt.as_secs_f64())
+    }
+
+    /// The latest status the robot reported, or `None` before the first one. The robot only
+    /// reports to the last operator it heard from, so this stays stale unless this client is
+    /// sending something.
+    fn status(&self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        self.client.status().map(|status| to_py(py, &status)).transpose()
+    }
+
+    /// Waits for the robot to report a new status
+    fn next_status(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let status = py.allow_threads(|| self.runtime.block_on(self.client.next_status())).map_err(error)?;
+        to_py(py, &status)
+    }
+
+    /// Allows the robot to drive, returning its new mode
+    fn enable(&self, py: Python<'_>) -> PyResult<String> {
+        self.set_mode(py, Command::Enable)
+    }
+
+    /// Stops the robot, returning its new mode
+    fn disable(&self, py: Python<'_>) -> PyResult<String> {
+        self.set_mode(py, Command::Disable)
+    }
+
+    /// Immediately stops the robot and latches the e-stop, returning its new mode
+    fn estop(&self, py: Python<'_>) -> PyResult<String> {
+        self.set_mode(py, Command::EStop)
+    }
+
+    /// Releases a latched e-stop, returning the robot's new mode
+    fn clear_estop(&self, py: Python<'_>) -> PyResult<String> {
+        self.set_mode(py, Command::ClearEStop)
+    }
+
+    /// Sends a single movement. The robot stops on its own once its command timeout expires.
+    fn send_move(&self, py: Python<'_>, movement: PyMove) -> PyResult<()> {
+        py.allow_threads(|| self.runtime.block_on(self.client.send(Command::Move(movement.into()))))
+            .map_err(error)
+    }
+
+    /// Keeps sending `movement` in the background at `rate_hz`, until it's replaced by another
+    /// call or `stop`
+    #[pyo3(signature = (movement, rate_hz=mappie_client::sender::DEFAULT_SEND_RATE_HZ))]
+    fn drive(&mut self, movement: PyMove, rate_hz: f32) {
+        let sender = self.sender.get_or_insert_with(|| {
+            let _runtime = self.runtime.enter();
+            self.client.command_sender(rate_hz)
+        });
+        sender.set_rate(rate_hz);
+        sender.set_move(movement.into());
+    }
+
+    /// Keeps sending a stopped movement in the background
+    fn stop(&self) {
+        if let Some(sender) = &self.sender {
+            sender.set_move(messages::Move::stop());
+        }
+    }
+
+    /// Describes every parameter, as dicts of the parameters' names, values, defaults and ranges
+    fn params(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let params = py.allow_threads(|| self.runtime.block_on(self.client.params())).map_err(error)?;
+        to_py(py, &params)
+    }
+
+    /// Reads a parameter. Choices are returned by name.
+    fn get_param(&self, py: Python<'_>, name: &str) -> PyResult<PyObject> {
+        let param = self.find_param(py, name)?;
+        let request = Request::GetParam(param.name.clone());
+        let value = self.param_request(py, request)?;
+        Ok(value_to_py(py, &param, value))
+    }
+
+    /// Changes a parameter, returning its new value. Choices can be given by name or index.
+    fn set_param(&self, py: Python<'_>, name: &str, value: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+        let param = self.find_param(py, name)?;
+        let request = Request::SetParam(param.name.clone(), value_from_py(&param, value)?);
+        let value = self.param_request(py, request)?;
+        Ok(value_to_py(py, &param, value))
+    }
+
+    /// Saves the current parameter values to the robot's config file
+    fn save_params(&self, py: Python<'_>) -> PyResult<()> {
+        match py.allow_threads(|| self.runtime.block_on(self.client.request(Request::SaveParams))) {
+            Ok(Response::Saved) => Ok(()),
+            Ok(response) => Err(error(mappie_client::Error::unexpected(response))),
+            Err(e) => Err(error(e)),
+        }
+    }
+
+    /// How well the robot has been answering, with times in seconds
+    fn health(&self, py: Python<'_>) -> PyResult<PyObject> {
+        #[derive(Serialize)]
+        struct Health {
+            since_last_received: Option<f64>,
+            rtt: Option<f64>,
+            jitter: Option<f64>,
+            /// Fraction of the recent requests that went unanswered
+            loss: Option<f32>,
+            requests_answered: u64,
+            requests_timed_out: u64,
+        }
+
+        let health = self.client.health();
+        to_py(py, &Health {
+            since_last_received: health.since_last_received().map(|since| since.as_secs_f64()),
+            rtt: health.rtt.map(|rtt| rtt.as_secs_f64()),
+            jitter: health.jitter.map(|jitter| jitter.as_secs_f64()),
+            loss: health.loss(),
+            requests_answered: health.requests_answered,
+            requests_timed_out: health.requests_timed_out,
+        })
+    }
+
+    /// Resolves the robot's address again and replaces the socket
+    fn reconnect(&self, py: Python<'_>) -> PyResult<()> {
+        py.allow_threads(|| self.runtime.block_on(self.client.reconnect())).map_err(error)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Client({:?})", self.client.target())
+    }
+}
+
+impl Client {
+    fn set_mode(&self, py: Python<'_>, command: Command) -> PyResult<String> {
+        let mode: Mode = py.allow_threads(|| self.runtime.block_on(self.client.set_mode(command)))
+            .map_err(error)?;
+        Ok(format!("{:?}", mode))
+    }
+
+    /// Looks a parameter up in the robot's list, which is needed to convert its value
+    fn find_param(&self, py: Python<'_>, name: &str) -> PyResult<ParamInfo> {
+        let params = py.allow_threads(|| self.runtime.block_on(self.client.params())).map_err(error)?;
+        params.into_iter()
+            .find(|param| param.name == name)
+            .ok_or_else(|| MappieError::new_err(format!("The robot doesn't have a parameter named {}", name)))
+    }
+
+    fn param_request(&self, py: Python<'_>, request: Request) -> PyResult<ParamValue> {
+        match py.allow_threads(|| self.runtime.block_on(self.client.request(request))) {
+            Ok(Response::Param(value)) => Ok(value),
+            Ok(response) => Err(error(mappie_client::Error::unexpected(response))),
+            Err(e) => Err(error(e)),
+        }
+    }
+}
+
+fn value_to_py(py: Python<'_>, param: &ParamInfo, value: ParamValue) -> PyObject {
+    match value {
+        ParamValue::Bool(b) => b.into_py(py),
+        ParamValue::Int(i) => i.into_py(py),
+        // going through the shortest decimal keeps e.g. 0.2 from becoming 0.20000000298
+        ParamValue::Float(f) => f.to_string().parse::<f64>().unwrap_or(f64::NAN).into_py(py),
+        ParamValue::Choice(i) => match param.choices.get(usize::from(i)) {
+            Some(choice) => choice.as_str().into_py(py),
+            None => i.into_py(py),
+        },
+    }
+}
+
+/// Converts `value` to the same type as the parameter's current value
+fn value_from_py(param: &ParamInfo, value: &Bound<'_, PyAny>) -> PyResult<ParamValue> {
+    Ok(match param.value {
+        ParamValue::Bool(_) => ParamValue::Bool(value.extract()?),
+        ParamValue::Int(_) => ParamValue::Int(value.extract()?),
+        ParamValue::Float(_) => ParamValue::Float(value.extract()?),
+        ParamValue::Choice(_) => match value.extract::<String>() {
+            Ok(name) => {
+                let i = param.choices.iter()
+                    .position(|choice| choice.as_str() == name)
+                    .ok_or_else(|| PyValueError::new_err(format!("{} isn't one of {}'s choices", name, param.name)))?;
+                ParamValue::Choice(i as u8)
+            }
+            Err(_) => ParamValue::Choice(value.extract()?),
+        },
+    })
+}
+
+/// Converts through JSON, so Python sees the same structure as `mappie-ctl --json`
+fn to_py<T: Serialize>(py: Python<'_>, value: &T) -> PyResult<PyObject> {
+    let json = serde_json::to_string(value)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Ok(py.import_bound("json")?.call_method1("loads", (json,))?.unbind())
+}
+
+fn from_py<T: DeserializeOwned>(value: &Bound<'_, PyAny>) -> PyResult<T> {
+    let json: String = value.py().import_bound("json")?.call_method1("dumps", (value,))?.extract()?;
+    serde_json::from_str(&json).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+fn error(e: mappie_client::Error) -> PyErr {
+    let mut message = e.to_string();
+    let mut source = std::error::Error::source(&e);
+    while let Some(cause) = source {
+        message = format!("{}: {}", message, cause);
+        source = cause.source();
+    }
+
+    match e {
+        mappie_client::Error::Timeout(_) => PyTimeoutError::new_err(message),
+        _ => MappieError::new_err(message),
+    }
+}
+
+#[pymodule]
+fn mappie_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyMove>()?;
+    m.add_class::<Client>()?;
+    m.add_function(wrap_pyfunction!(encode, m)?)?;
+    m.add_function(wrap_pyfunction!(decode, m)?)?;
+    m.add("MappieError", m.py().get_type_bound::<MappieError>())?;
+    Ok(())
+}
@@ -0,0 +1,141 @@
+"""A simulated robot on loopback, speaking the same protocol as the real one."""
+
+import socket
+import threading
+
+import pytest
+
+import mappie_py
+
+PARAMS = [
+    {
+        "name": "max_speed",
+        "value": {"Float": 0.2},
+        "default": {"Float": 0.2},
+        "range": {"min": {"Float": 0.0}, "max": {"Float": 1.0}},
+        "choices": [],
+    },
+    {
+        "name": "drive_mode",
+        "value": {"Choice": 0},
+        "default": {"Choice": 0},
+        "range": None,
+        "choices": ["tank", "mecanum"],
+    },
+    {
+        "name": "log_level",
+        "value": {"Choice": 2},
+        "default": {"Choice": 2},
+        "range": None,
+        "choices": ["error", "warn", "info", "debug", "trace"],
+    },
+]
+
+# must match messages::PARAM_LIST_PAGE_LEN
+PARAM_LIST_PAGE_LEN = 2
+
+
+class SimulatedRobot:
+    """Answers requests and reports its status after every message, until stopped"""
+
+    def __init__(self):
+        self.socket = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        self.socket.bind(("127.0.0.1", 0))
+        self.socket.settimeout(0.05)
+        self.target = "127.0.0.1:{}".format(self.socket.getsockname()[1])
+        self.mode = "Disabled"
+        self.params = [dict(param) for param in PARAMS]
+        # whether requests go unanswered, as if they were lost
+        self.ignore_requests = False
+        self.moves = []
+        self.lock = threading.Lock()
+        self.running = True
+        self.thread = threading.Thread(target=self.serve, daemon=True)
+        self.thread.start()
+
+    def stop(self):
+        self.running = False
+        self.thread.join()
+        self.socket.close()
+
+    def last_move(self):
+        with self.lock:
+            return self.moves[-1] if self.moves else None
+
+    def serve(self):
+        while self.running:
+            try:
+                frame, sender = self.socket.recvfrom(1024)
+            except socket.timeout:
+                continue
+
+            with self.lock:
+                reply = self.handle(mappie_py.decode(frame))
+                status = {
+                    "Status": {
+                        "mode": self.mode,
+                        "drive": "Ok",
+                        "drive_errors": 0,
+                        "link_losses": 0,
+                        "superseded_moves": 0,
+                        "motor_transactions_per_tick": 0.0,
+                    }
+                }
+            for message in [reply, status]:
+                if message is not None:
+                    self.socket.sendto(mappie_py.encode(message), sender)
+
+    def handle(self, message):
+        if "Command" in message:
+            command = message["Command"]
+            if isinstance(command, dict):
+                self.moves.append(command["Move"])
+            else:
+                self.mode = {
+                    "Enable": "Enabled",
+                    "Disable": "Disabled",
+                    "EStop": "EStopped",
+                    "ClearEStop": "Disabled",
+                }[command]
+            return None
+
+        if self.ignore_requests:
+            return None
+        request = message["Request"]
+        return {"Response": {"id": request["id"], "response": self.answer(request["request"])}}
+
+    def answer(self, request):
+        if request == "Ping":
+            return "Pong"
+        if request == "SaveParams":
+            return "Saved"
+        if "ListParams" in request:
+            start = request["ListParams"]["start"]
+            page = self.params[start:start + PARAM_LIST_PAGE_LEN]
+            return {"ParamList": {"total": len(self.params), "params": page}}
+        if "GetParam" in request:
+            param = self.find(request["GetParam"])
+            return {"Param": param["value"]} if param else {"Error": "UnknownParam"}
+        if "SetParam" in request:
+            name, value = request["SetParam"]
+            param = self.find(name)
+            if param is None:
+                return {"Error": "UnknownParam"}
+            param["value"] = value
+            return {"Param": value}
+        raise ValueError("Unexpected request {}".format(request))
+
+    def find(self, name):
+        return next((param for param in self.params if param["name"] == name), None)
+
+
+@pytest.fixture
+def robot():
+    robot = SimulatedRobot()
+    yield robot
+    robot.stop()
+
+
+@pytest.fixture
+def client(robot):
+    return mappie_py.Client(robot.target, timeout=0.3)
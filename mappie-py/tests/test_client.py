@@ -0,0 +1,79 @@
+import time
+
+import pytest
+
+import mappie_py
+from mappie_py import Move
+
+
+def wait_for(condition, timeout=2.0):
+    deadline = time.monotonic() + timeout
+    while time.monotonic() < deadline:
+        if condition():
+            return
+        time.sleep(0.01)
+    pytest.fail("Timed out waiting for the robot")
+
+
+def test_ping(client):
+    assert 0 < client.ping() < 0.3
+    health = client.health()
+    assert health["requests_answered"] == 1
+    assert health["requests_timed_out"] == 0
+
+
+def test_connecting_to_nothing_fails():
+    with pytest.raises(mappie_py.MappieError):
+        mappie_py.Client("127.0.0.1:9", timeout=0.1)
+
+
+def test_enable_and_disable(client, robot):
+    assert client.enable() == "Enabled"
+    assert robot.mode == "Enabled"
+    assert client.status()["mode"] == "Enabled"
+    assert client.disable() == "Disabled"
+
+
+def test_drive_and_stop(client, robot):
+    client.drive(Move(y=0.5, rotate=-0.25), rate_hz=100)
+    wait_for(lambda: robot.last_move() == {"translate": [0.0, 0.5], "rotate": -0.25})
+
+    client.stop()
+    wait_for(lambda: robot.last_move() == {"translate": [0.0, 0.0], "rotate": 0.0})
+
+
+def test_params_are_read_past_the_first_page(client, robot):
+    names = [param["name"] for param in client.params()]
+    assert names == [param["name"] for param in robot.params]
+
+
+def test_get_and_set_params(client, robot):
+    assert client.get_param("max_speed") == 0.2
+    assert client.set_param("max_speed", 0.5) == 0.5
+    assert robot.find("max_speed")["value"] == {"Float": 0.5}
+
+
+def test_choices_are_named(client, robot):
+    assert client.get_param("drive_mode") == "tank"
+    assert client.set_param("drive_mode", "mecanum") == "mecanum"
+    assert robot.find("drive_mode")["value"] == {"Choice": 1}
+
+    # or given by index
+    assert client.set_param("drive_mode", 0) == "tank"
+
+
+def test_unknown_choices_are_rejected(client):
+    with pytest.raises(ValueError):
+        client.set_param("drive_mode", "hover")
+
+
+def test_unknown_params_are_rejected(client):
+    with pytest.raises(mappie_py.MappieError):
+        client.get_param("missing")
+
+
+def test_unanswered_requests_time_out(client, robot):
+    robot.ignore_requests = True
+    with pytest.raises(TimeoutError):
+        client.ping()
+    assert client.health()["requests_timed_out"] == 1
@@ -0,0 +1,61 @@
+import pytest
+
+import mappie_py
+from mappie_py import Move
+
+
+def test_move_encodes_as_command():
+    message = mappie_py.decode(Move(x=0.5, y=-1.0, rotate=0.25).encode())
+    assert message == {"Command": {"Move": {"translate": [0.5, -1.0], "rotate": 0.25}}}
+
+
+def test_stop_is_all_zeros():
+    stop = Move.stop()
+    assert (stop.x, stop.y, stop.rotate) == (0.0, 0.0, 0.0)
+    assert mappie_py.encode(stop) == Move().encode()
+
+
+def test_encode_accepts_moves():
+    movement = Move(y=1.0)
+    assert mappie_py.encode(movement) == movement.encode()
+
+
+@pytest.mark.parametrize(
+    "message",
+    [
+        {"Command": "Enable"},
+        {"Command": "EStop"},
+        {"Request": {"id": 7, "request": "Ping"}},
+        {"Request": {"id": 8, "request": {"SetParam": ["drive_mode", {"Choice": 1}]}}},
+        {"Response": {"id": 8, "response": {"Param": {"Int": -3}}}},
+        {"Response": {"id": 9, "response": {"Error": "UnknownParam"}}},
+        {
+            "Status": {
+                "mode": "Enabled",
+                "drive": {"Recovering": {"consecutive_errors": 2}},
+                "drive_errors": 5,
+                "link_losses": 1,
+                "superseded_moves": 0,
+                "motor_transactions_per_tick": 1.5,
+            }
+        },
+    ],
+)
+def test_messages_round_trip(message):
+    assert mappie_py.decode(mappie_py.encode(message)) == message
+
+
+def test_frames_end_in_a_delimiter():
+    assert mappie_py.encode({"Command": "Disable"}).endswith(b"\x00")
+
+
+def test_encode_rejects_unknown_messages():
+    with pytest.raises(ValueError):
+        mappie_py.encode({"Command": "Fly"})
+
+
+def test_decode_rejects_corrupt_frames():
+    frame = bytearray(mappie_py.encode({"Command": "Enable"}))
+    frame[1] ^= 0xFF
+    with pytest.raises(ValueError):
+        mappie_py.decode(bytes(frame))
@@ -1,16 +1,40 @@
+use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
 
+pub mod rpc;
+
+pub use rpc::RequestId;
+
+/// Every message exchanged between operators and the robot.
+///
+/// Peers skip messages they don't recognize, so new kinds of messages can be added without
+/// breaking older peers. Variants here, and in every enum they contain, must only ever be
+/// appended: reordering or removing them changes the wire format.
+// messages are handled one at a time, so the size of the largest variant doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Deserialize, Serialize, MaxSize, Clone, Debug, PartialEq)]
+pub enum Message {
+    /// Sent by operators, without expecting a reply
+    Command(Command),
+    /// Sent by the robot periodically and whenever its state changes
+    Status(Status),
+    /// Sent by operators, and answered with a [`Message::Response`] carrying the same id
+    Request { id: RequestId, request: Request },
+    Response { id: RequestId, response: Response },
+}
+
 #[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
 pub struct Move {
     pub translate: mint::Vector2<f32>,
     pub rotate: f32,
 }
 
-impl Move {
-    // f32s are always encoded as u32s (no length variation) on the wire
-    // TODO: calculate the actual overhead of COBS encoding + termination
-    pub const POSTCARD_COBS_BUFFER_MAX_SIZE: usize = std::mem::size_of::<Move>() * 2 + 1;
+// mint doesn't implement MaxSize, but f32s are always encoded in 4 bytes
+impl MaxSize for Move {
+    const POSTCARD_MAX_SIZE: usize = 3 * f32::POSTCARD_MAX_SIZE;
+}
 
+impl Move {
     pub fn stop() -> Self {
         Self {
             translate: mint::Vector2::from([0.0, 0.0]),
@@ -19,7 +43,163 @@ impl Move {
     }
 }
 
+/// Messages sent from operators to the robot
+#[derive(Deserialize, Serialize, MaxSize, Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Move(Move),
+    /// Allow the robot to drive
+    Enable,
+    /// Stop the robot and ignore movement until it's enabled again
+    Disable,
+    /// Immediately stop the robot and keep it stopped, even across restarts, until the e-stop is
+    /// cleared
+    EStop,
+    /// Release a latched e-stop. The robot stays disabled until it's enabled again.
+    ClearEStop,
+}
+
+/// The robot's operating mode
+#[derive(Deserialize, Serialize, MaxSize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Disabled,
+    Enabled,
+    EStopped,
+}
+
+/// Longest name a parameter can have, in bytes
+pub const PARAM_NAME_MAX_LEN: usize = 32;
+
+pub type ParamName = heapless::String<PARAM_NAME_MAX_LEN>;
+
+/// Most choices a [`ParamValue::Choice`] parameter can have
+pub const PARAM_CHOICES_MAX: usize = 8;
+
+/// Longest name a parameter's choice can have, in bytes
+pub const PARAM_CHOICE_NAME_MAX_LEN: usize = 16;
+
+pub type ParamChoiceName = heapless::String<PARAM_CHOICE_NAME_MAX_LEN>;
+
+#[derive(Deserialize, Serialize, MaxSize, Clone, Copy, Debug, PartialEq)]
+pub enum ParamValue {
+    Bool(bool),
+    Int(i32),
+    Float(f32),
+    /// Index into the parameter's [`ParamInfo::choices`]
+    Choice(u8),
+}
+
+/// Inclusive bounds on a parameter's value, of the same type as the value
+#[derive(Deserialize, Serialize, MaxSize, Clone, Copy, Debug, PartialEq)]
+pub struct ParamRange {
+    pub min: ParamValue,
+    pub max: ParamValue,
+}
+
+#[derive(Deserialize, Serialize, MaxSize, Clone, Debug, PartialEq)]
+pub struct ParamInfo {
+    pub name: ParamName,
+    pub value: ParamValue,
+    /// The value used when nothing else is configured
+    pub default: ParamValue,
+    pub range: Option<ParamRange>,
+    /// Names of the values a [`ParamValue::Choice`] can take, empty for other parameters
+    pub choices: heapless::Vec<ParamChoiceName, PARAM_CHOICES_MAX>,
+}
+
+/// Most parameters described by a single [`Response::ParamList`]
+pub const PARAM_LIST_PAGE_LEN: usize = 2;
+
+/// Operations that the robot answers with a [`Response`]
+#[derive(Deserialize, Serialize, MaxSize, Clone, Debug, PartialEq)]
+pub enum Request {
+    /// Answered with [`Response::Pong`] right away, to check that the robot is reachable
+    Ping,
+    /// Answered with the parameter's current value
+    GetParam(ParamName),
+    /// Answered with the parameter's value after the change
+    SetParam(ParamName, ParamValue),
+    /// Answered with a page of parameters, starting from the `start`th one
+    ListParams { start: u16 },
+    /// Writes the current parameter values to the robot's config file, so they're restored when
+    /// it restarts
+    SaveParams,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Deserialize, Serialize, MaxSize, Clone, Debug, PartialEq)]
+pub enum Response {
+    Pong,
+    Param(ParamValue),
+    Error(RequestError),
+    /// `params` is empty once `start` reaches `total`
+    ParamList { total: u16, params: heapless::Vec<ParamInfo, PARAM_LIST_PAGE_LEN> },
+    Saved,
+}
+
+/// Reasons the robot couldn't carry out a [`Request`]
+#[derive(Deserialize, Serialize, MaxSize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestError {
+    UnknownParam,
+    /// The value has the wrong type or is out of the parameter's range
+    InvalidValue,
+    SaveFailed,
+}
+
+/// Port robots announce themselves to with a [`Beacon`], unless configured otherwise
+pub const DISCOVERY_PORT: u16 = 9091;
+
+/// Longest robot name a [`Beacon`] can carry, in bytes
+pub const ROBOT_NAME_MAX_LEN: usize = 32;
+
+/// Periodically broadcast by robots so operators can find them without knowing their address.
+/// Beacons are sent on their own port, so they aren't [`Message`]s.
+#[derive(Deserialize, Serialize, MaxSize, Clone, Debug, PartialEq)]
+pub struct Beacon {
+    pub name: heapless::String<ROBOT_NAME_MAX_LEN>,
+    /// Version of the robot's control software
+    pub version: heapless::String<16>,
+    /// Port the robot accepts control messages on, at the address the beacon came from
+    pub port: u16,
+    pub transport: Transport,
+}
+
+/// How a robot accepts control messages
+#[derive(Deserialize, Serialize, MaxSize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Each message is sent as its own datagram
+    Udp,
+    /// Operators connect and stream messages
+    Tcp,
+}
+
 #[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
 pub struct SensorReading {
 
 }
+
+/// Robot state reported back to operators
+#[derive(Deserialize, Serialize, MaxSize, Clone, Copy, Debug, PartialEq)]
+pub struct Status {
+    pub mode: Mode,
+    pub drive: DriveStatus,
+    /// Total number of failed drive updates since the robot started
+    pub drive_errors: u32,
+    /// Number of times the robot stopped because commands stopped arriving
+    pub link_losses: u32,
+    /// Number of movement commands dropped because a newer one arrived before they were used
+    pub superseded_moves: u32,
+    /// Average number of I2C transactions the motor hat used per main loop iteration, over the
+    /// last few iterations
+    pub motor_transactions_per_tick: f32,
+}
+
+#[derive(Deserialize, Serialize, MaxSize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DriveStatus {
+    /// Throttles are being written normally
+    Ok,
+    /// Recent throttle writes failed and the motor hat is being reinitialized
+    Recovering { consecutive_errors: u32 },
+    /// Too many throttle writes failed in a row. The motors are stopped until the operator
+    /// centers the controls.
+    SafeStopped,
+}
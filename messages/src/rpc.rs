@@ -0,0 +1,144 @@
+//! Matching of responses to the requests that caused them.
+//!
+//! Requests are sent over unreliable links, so either the request or its response may never
+//! arrive. [`PendingRequests`] hands out the correlation id for each request, and gives up on
+//! requests once they time out. Responses that arrive after that are ignored.
+//!
+//! Time is always passed in by the caller, so this works the same with any runtime.
+
+use std::time::{Duration, Instant};
+
+/// Correlates a [`crate::Message::Response`] with its [`crate::Message::Request`]
+pub type RequestId = u32;
+
+struct Pending<T> {
+    id: RequestId,
+    deadline: Instant,
+    context: T,
+}
+
+/// Requests still waiting for a response, along with whatever context the caller needs to
+/// handle the response
+pub struct PendingRequests<T> {
+    timeout: Duration,
+    next_id: RequestId,
+    pending: Vec<Pending<T>>,
+}
+
+impl<T> PendingRequests<T> {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            next_id: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Tracks a new request sent at `now`, returning the id it must be sent with
+    pub fn start(&mut self, now: Instant, context: T) -> RequestId {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.pending.push(Pending {
+            id,
+            deadline: now + self.timeout,
+            context,
+        });
+        id
+    }
+
+    /// Stops tracking the request a response belongs to, returning its context. Responses to
+    /// requests that already timed out, or were never sent, return `None`.
+    pub fn resolve(&mut self, id: RequestId) -> Option<T> {
+        let index = self.pending.iter().position(|pending| pending.id == id)?;
+        Some(self.pending.swap_remove(index).context)
+    }
+
+    /// Stops tracking requests that have gone unanswered for longer than the timeout, returning
+    /// their contexts
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let mut expired = Vec::new();
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].deadline <= now {
+                expired.push(self.pending.swap_remove(i).context);
+            } else {
+                i += 1;
+            }
+        }
+        expired
+    }
+
+    /// Number of requests waiting for a response
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn resolves_by_id() {
+        let now = Instant::now();
+        let mut requests = PendingRequests::new(TIMEOUT);
+        let first = requests.start(now, "first");
+        let second = requests.start(now, "second");
+        assert_ne!(first, second);
+
+        assert_eq!(requests.resolve(second), Some("second"));
+        assert_eq!(requests.resolve(second), None);
+        assert_eq!(requests.resolve(first), Some("first"));
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn ignores_unknown_ids() {
+        let mut requests = PendingRequests::new(TIMEOUT);
+        let id = requests.start(Instant::now(), ());
+        assert_eq!(requests.resolve(id.wrapping_add(1)), None);
+        assert_eq!(requests.len(), 1);
+    }
+
+    #[test]
+    fn expires_at_the_deadline() {
+        let now = Instant::now();
+        let mut requests = PendingRequests::new(TIMEOUT);
+        requests.start(now, "early");
+        requests.start(now + TIMEOUT / 2, "late");
+
+        assert!(requests.expire(now + TIMEOUT - Duration::from_nanos(1)).is_empty());
+        assert_eq!(requests.expire(now + TIMEOUT), vec!["early"]);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests.expire(now + TIMEOUT * 2), vec!["late"]);
+    }
+
+    #[test]
+    fn late_responses_are_ignored() {
+        let now = Instant::now();
+        let mut requests = PendingRequests::new(TIMEOUT);
+        let id = requests.start(now, ());
+        requests.expire(now + TIMEOUT);
+
+        assert_eq!(requests.resolve(id), None);
+    }
+
+    #[test]
+    fn ids_wrap_around() {
+        let now = Instant::now();
+        let mut requests = PendingRequests::new(TIMEOUT);
+        requests.next_id = RequestId::MAX;
+
+        let last = requests.start(now, "last");
+        let wrapped = requests.start(now, "wrapped");
+        assert_eq!((last, wrapped), (RequestId::MAX, 0));
+        assert_eq!(requests.resolve(0), Some("wrapped"));
+        assert_eq!(requests.resolve(RequestId::MAX), Some("last"));
+    }
+}
@@ -0,0 +1,763 @@
+//! Maps the operator's inputs to the actions they perform, so any gamepad or keyboard can drive
+//! the robot.
+//!
+//! Each action is bound to a single gamepad axis, gamepad button, key, or pair of keys. Axis
+//! actions are shaped by inversion, a deadzone and a response curve, and can be ramped so keys
+//! drive as smoothly as a stick. Bindings are kept per gamepad model in the operator's config
+//! directory, so every controller on the desk keeps its own layout. Without a gamepad, the
+//! keyboard drives.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use egui::Key;
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::gamepad::{Axis, Button, GamepadState, AXES, BUTTONS};
+
+/// How much slower the robot drives while the speed mode action is held
+pub const SLOW_SPEED_SCALE: f32 = 0.3;
+
+/// How far an axis has to move from where it rested to be picked up when binding
+const CAPTURE_AXIS_THRESHOLD: f32 = 0.5;
+
+/// Profile used when no gamepad is connected
+const KEYBOARD_PROFILE: &str = "keyboard";
+
+/// How long keys take to ramp up to full speed, by default
+const DEFAULT_KEY_RAMP_SECS: f32 = 0.25;
+
+/// Largest deadzone an axis can have, leaving some of its travel to drive with
+const MAX_DEADZONE: f32 = 0.9;
+
+/// Longest time an axis can take to ramp up to full speed, in seconds
+const MAX_RAMP_SECS: f32 = 2.0;
+
+/// Longest time ramping accounts for between frames, so a stalled UI doesn't make values jump
+const MAX_RAMP_STEP: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Action {
+    TranslateX,
+    TranslateY,
+    Rotate,
+    /// Drives slower while held
+    SpeedMode,
+    EStop,
+    /// Toggles between enabled and disabled
+    Enable,
+}
+
+impl Action {
+    pub const ALL: [Action; 6] = [
+        Action::TranslateX,
+        Action::TranslateY,
+        Action::Rotate,
+        Action::SpeedMode,
+        Action::EStop,
+        Action::Enable,
+    ];
+
+    /// Whether the action takes an analog value, rather than being triggered
+    pub fn is_axis(self) -> bool {
+        matches!(self, Action::TranslateX | Action::TranslateY | Action::Rotate)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Action::TranslateX => "Translate X",
+            Action::TranslateY => "Translate Y",
+            Action::Rotate => "Rotate",
+            Action::SpeedMode => "Slow mode",
+            Action::EStop => "E-stop",
+            Action::Enable => "Enable/disable",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Input {
+    Unbound,
+    GamepadAxis(Axis),
+    GamepadButton(Button),
+    Key(Key),
+    /// Drives an axis negative with one key and positive with the other
+    KeyPair {
+        negative: Key,
+        positive: Key,
+    },
+    /// A modifier key, which can only be held since egui doesn't report when they're pressed
+    Modifier(Modifier),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Modifier {
+    Shift,
+    Ctrl,
+    Alt,
+}
+
+impl Modifier {
+    fn held(self, modifiers: egui::Modifiers) -> bool {
+        match self {
+            Modifier::Shift => modifiers.shift,
+            Modifier::Ctrl => modifiers.ctrl,
+            Modifier::Alt => modifiers.alt,
+        }
+    }
+
+    /// The first modifier held, if any
+    fn find_held(modifiers: egui::Modifiers) -> Option<Self> {
+        [Modifier::Shift, Modifier::Ctrl, Modifier::Alt].into_iter()
+            .find(|modifier| modifier.held(modifiers))
+    }
+}
+
+impl Input {
+    fn describe(self) -> String {
+        match self {
+            Input::Unbound => "Unbound".to_string(),
+            Input::GamepadAxis(axis) => format!("{:?}", axis),
+            Input::GamepadButton(button) => format!("{:?}", button),
+            Input::Key(key) => key.name().to_string(),
+            Input::KeyPair { negative, positive } => format!("{} / {}", negative.name(), positive.name()),
+            Input::Modifier(modifier) => format!("{:?}", modifier),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// Finer control near the center, at the cost of coarser control near the edges
+    Quadratic,
+    Cubic,
+}
+
+impl Curve {
+    const ALL: [Curve; 3] = [Curve::Linear, Curve::Quadratic, Curve::Cubic];
+
+    fn apply(self, value: f32) -> f32 {
+        match self {
+            Curve::Linear => value,
+            Curve::Quadratic => value * value.abs(),
+            Curve::Cubic => value * value * value,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Binding {
+    pub input: Input,
+    pub invert: bool,
+    /// Values closer than this to 0 are treated as 0, and the rest rescaled to start from 0
+    pub deadzone: f32,
+    pub curve: Curve,
+    /// Seconds the value takes to go from 0 to full, or 0 to follow the input immediately
+    pub ramp: f32,
+}
+
+impl Default for Binding {
+    fn default() -> Self {
+        Self::new(Input::Unbound)
+    }
+}
+
+impl Binding {
+    fn new(input: Input) -> Self {
+        Self {
+            input,
+            invert: false,
+            deadzone: 0.0,
+            curve: Curve::default(),
+            ramp: 0.0,
+        }
+    }
+
+    fn ramped(input: Input) -> Self {
+        Self {
+            ramp: DEFAULT_KEY_RAMP_SECS,
+            ..Self::new(input)
+        }
+    }
+
+    /// Brings settings edited outside of the UI back within the ranges the UI allows
+    fn sanitize(&mut self) {
+        self.deadzone = if self.deadzone.is_finite() { self.deadzone.clamp(0.0, MAX_DEADZONE) } else { 0.0 };
+        self.ramp = if self.ramp.is_finite() { self.ramp.clamp(0.0, MAX_RAMP_SECS) } else { 0.0 };
+    }
+
+    /// The shaped value of the input, from -1 to 1
+    fn value(&self, sources: &Sources) -> f32 {
+        let raw = match self.input {
+            Input::Unbound => 0.0,
+            Input::GamepadAxis(axis) => sources.gamepad.map_or(0.0, |gamepad| gamepad.axis(axis)),
+            Input::GamepadButton(button) => sources.gamepad.map_or(0.0, |gamepad| gamepad.button(button).value),
+            Input::Key(key) => sources.key_value(key),
+            Input::KeyPair { negative, positive } => sources.key_value(positive) - sources.key_value(negative),
+            Input::Modifier(modifier) => match sources.keys {
+                Some(keys) if modifier.held(keys.modifiers()) => 1.0,
+                _ => 0.0,
+            },
+        };
+        let value = if self.invert { -raw } else { raw };
+        self.curve.apply(apply_deadzone(value.clamp(-1.0, 1.0), self.deadzone))
+    }
+
+    /// Whether the input went down since the previous frame
+    fn pressed(&self, sources: &Sources) -> bool {
+        match self.input {
+            Input::GamepadButton(button) => sources.gamepad.is_some_and(|gamepad| gamepad.button(button).pressed),
+            Input::Key(key) => sources.keys.is_some_and(|keys| keys.pressed(key)),
+            Input::Unbound | Input::GamepadAxis(_) | Input::KeyPair { .. } | Input::Modifier(_) => false,
+        }
+    }
+
+    fn held(&self, sources: &Sources) -> bool {
+        self.value(sources) > 0.5
+    }
+}
+
+fn apply_deadzone(value: f32, deadzone: f32) -> f32 {
+    if value.abs() <= deadzone {
+        0.0
+    } else {
+        value.signum() * (value.abs() - deadzone) / (1.0 - deadzone)
+    }
+}
+
+/// Keyboard state that bindings can read, so they work the same in any kind of interface
+pub trait Keys {
+    fn down(&self, key: Key) -> bool;
+    /// Whether the key was pressed this frame, ignoring key-repeats
+    fn pressed(&self, key: Key) -> bool;
+    fn modifiers(&self) -> egui::Modifiers;
+}
+
+impl Keys for egui::InputState {
+    fn down(&self, key: Key) -> bool {
+        self.key_down(key)
+    }
+
+    fn pressed(&self, key: Key) -> bool {
+        self.events.iter().any(|event| {
+            matches!(event, egui::Event::Key { key: k, pressed: true, repeat: false, .. } if *k == key)
+        })
+    }
+
+    fn modifiers(&self) -> egui::Modifiers {
+        self.modifiers
+    }
+}
+
+/// Everything bindings can read input from
+struct Sources<'a> {
+    gamepad: Option<&'a GamepadState>,
+    /// Missing while the keyboard is being used for something else, like typing in a text field
+    keys: Option<&'a dyn Keys>,
+}
+
+impl Sources<'_> {
+    fn key_value(&self, key: Key) -> f32 {
+        match self.keys {
+            Some(keys) if keys.down(key) => 1.0,
+            _ => 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bindings(BTreeMap<Action, Binding>);
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self(BTreeMap::from([
+            (Action::TranslateX, Binding::new(Input::GamepadAxis(Axis::LeftStickX))),
+            (Action::TranslateY, Binding::new(Input::GamepadAxis(Axis::LeftStickY))),
+            (Action::Rotate, Binding::new(Input::GamepadAxis(Axis::RightStickX))),
+            (Action::SpeedMode, Binding::new(Input::GamepadButton(Button::LeftTrigger))),
+            (Action::EStop, Binding::new(Input::GamepadButton(Button::East))),
+            (Action::Enable, Binding::new(Input::GamepadButton(Button::Start))),
+        ]))
+    }
+}
+
+impl Bindings {
+    /// Bindings for driving without a gamepad
+    pub fn keyboard() -> Self {
+        Self(BTreeMap::from([
+            (Action::TranslateX, Binding::ramped(Input::KeyPair { negative: Key::A, positive: Key::D })),
+            (Action::TranslateY, Binding::ramped(Input::KeyPair { negative: Key::S, positive: Key::W })),
+            (Action::Rotate, Binding::ramped(Input::KeyPair { negative: Key::Q, positive: Key::E })),
+            (Action::SpeedMode, Binding::new(Input::Modifier(Modifier::Shift))),
+            (Action::EStop, Binding::new(Input::Key(Key::Space))),
+            (Action::Enable, Binding::new(Input::Key(Key::Enter))),
+        ]))
+    }
+
+    fn default_for(gamepad: Option<&GamepadState>) -> Self {
+        match gamepad {
+            Some(_) => Self::default(),
+            None => Self::keyboard(),
+        }
+    }
+
+    pub fn get(&self, action: Action) -> Binding {
+        self.0.get(&action).copied().unwrap_or_default()
+    }
+
+    fn get_mut(&mut self, action: Action) -> &mut Binding {
+        self.0.entry(action).or_default()
+    }
+
+    fn sanitize(&mut self) {
+        for binding in self.0.values_mut() {
+            binding.sanitize();
+        }
+    }
+}
+
+/// What the operator asked for on this frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ActionState {
+    pub translate: (f32, f32),
+    pub rotate: f32,
+    pub slow: bool,
+    pub estop: bool,
+    /// Whether to toggle between enabled and disabled
+    pub toggle_enable: bool,
+}
+
+/// Waiting for the operator to press the input to bind to an action
+struct Capture {
+    action: Action,
+    /// Where each axis rested when capturing started, so axes that don't rest at 0 (like some
+    /// triggers) aren't picked up immediately
+    resting: Vec<f32>,
+    /// The negative key of a pair, once it's been pressed
+    negative: Option<Key>,
+}
+
+/// Settings panel for editing bindings, which also applies them to the operator's input
+pub struct ControlsPanel {
+    profiles: Profiles,
+    capture: Option<Capture>,
+    /// Result of the last operation, and whether it failed
+    message: Option<(String, bool)>,
+    /// Current value of each ramped axis action
+    ramped: BTreeMap<Action, f32>,
+    last_read: Option<Instant>,
+}
+
+impl ControlsPanel {
+    pub fn new() -> Self {
+        Self::with_profiles(Profiles::load())
+    }
+
+    fn with_profiles(profiles: Profiles) -> Self {
+        let message = profiles.error.as_ref().map(|e| (e.clone(), true));
+        Self {
+            profiles,
+            capture: None,
+            message,
+            ramped: BTreeMap::new(),
+            last_read: None,
+        }
+    }
+
+    /// Reads the operator's input from egui through the bindings for `gamepad`
+    pub fn read(&mut self, ctx: &egui::Context, gamepad: Option<&GamepadState>) -> ActionState {
+        let typing = ctx.wants_keyboard_input();
+        let (state, ramping) = ctx.input(|input| {
+            let keys: Option<&dyn Keys> = if typing { None } else { Some(input) };
+            self.read_keys(keys, gamepad)
+        });
+
+        // nothing else wakes the UI while a key is held still
+        if ramping {
+            crate::gui_framework::request_redraw();
+        }
+        state
+    }
+
+    /// Reads the operator's input through the bindings for `gamepad`, also returning whether any
+    /// values are still ramping.
+    ///
+    /// If `gamepad` is disconnected, nothing moves the robot until it's reconnected, but the
+    /// keyboard can still e-stop it.
+    pub fn read_keys(&mut self, keys: Option<&dyn Keys>, gamepad: Option<&GamepadState>) -> (ActionState, bool) {
+        if gamepad.is_some_and(|gamepad| !gamepad.connected) {
+            let (state, _) = self.read_keys(keys, None);
+            self.ramped.clear();
+            let state = ActionState {
+                estop: state.estop,
+                ..ActionState::default()
+            };
+            return (state, false)
+        }
+
+        let now = Instant::now();
+        let elapsed = self.last_read.map_or(Duration::ZERO, |last| now - last).min(MAX_RAMP_STEP);
+        self.last_read = Some(now);
+
+        let bindings = self.profiles.get(gamepad);
+        let mut ramping = false;
+        let sources = Sources { gamepad, keys };
+        let mut axis = |action: Action| {
+            let binding = bindings.get(action);
+            let target = binding.value(&sources);
+            if binding.ramp <= 0.0 {
+                self.ramped.remove(&action);
+                return target
+            }
+
+            let value = self.ramped.entry(action).or_insert(0.0);
+            let step = elapsed.as_secs_f32() / binding.ramp;
+            *value += (target - *value).clamp(-step, step);
+            ramping |= *value != target;
+            *value
+        };
+
+        let scale = if bindings.get(Action::SpeedMode).held(&sources) { SLOW_SPEED_SCALE } else { 1.0 };
+        let mut state = ActionState {
+            translate: (scale * axis(Action::TranslateX), scale * axis(Action::TranslateY)),
+            rotate: scale * axis(Action::Rotate),
+            slow: scale != 1.0,
+            estop: bindings.get(Action::EStop).pressed(&sources),
+            toggle_enable: bindings.get(Action::Enable).pressed(&sources),
+        };
+        // the input being bound shouldn't also do whatever it's currently bound to
+        if self.capture.is_some() {
+            state.estop = false;
+            state.toggle_enable = false;
+        }
+        (state, ramping)
+    }
+
+    pub fn draw(&mut self, ui: &mut egui::Ui, gamepad: Option<&GamepadState>) {
+        egui::CollapsingHeader::new("Controls").show(ui, |ui| {
+            match gamepad {
+                Some(gamepad) => ui.label(format!("Bindings for {}", gamepad.name)),
+                None => ui.label("Keyboard bindings, used while no gamepad is connected"),
+            };
+            if let Some((message, is_error)) = &self.message {
+                let color = if *is_error { egui::Color32::RED } else { egui::Color32::GREEN };
+                ui.label(egui::RichText::new(message).color(color));
+            }
+
+            if let Some(capture) = &self.capture {
+                let prompt = match (capture.action.is_axis(), capture.negative) {
+                    (true, None) => "Move an axis in the positive direction, press a button, or press the key for the negative direction",
+                    (true, Some(_)) => "Press the key for the positive direction",
+                    (false, _) => "Press a button, key, or modifier",
+                };
+                ui.label(egui::RichText::new(format!("{} (Escape cancels)", prompt)).strong());
+            }
+
+            let mut bindings = self.profiles.get(gamepad);
+            let mut changed = self.capture_input(ui, gamepad, &mut bindings);
+            egui::Grid::new("bindings").striped(true).show(ui, |ui| {
+                for action in Action::ALL {
+                    changed |= self.draw_binding(ui, action, bindings.get_mut(action));
+                    ui.end_row();
+                }
+            });
+
+            if ui.button("Reset to defaults").clicked() {
+                bindings = Bindings::default_for(gamepad);
+                changed = true;
+            }
+            if changed {
+                self.profiles.set(gamepad, bindings);
+                self.message = self.profiles.save().err().map(|e| (format!("{:#}", e), true));
+            }
+        });
+    }
+
+    /// Draws a row for a single action, returning whether the operator changed its binding
+    fn draw_binding(&mut self, ui: &mut egui::Ui, action: Action, binding: &mut Binding) -> bool {
+        ui.label(action.label());
+        ui.label(binding.input.describe());
+
+        let capturing = self.capture.as_ref().is_some_and(|capture| capture.action == action);
+        if capturing {
+            if ui.button("Cancel").clicked() {
+                self.capture = None;
+            }
+        } else if ui.button("Bind").clicked() {
+            self.capture = Some(Capture {
+                action,
+                resting: Vec::new(),
+                negative: None,
+            });
+        }
+
+        let mut changed = false;
+        if ui.add_enabled(binding.input != Input::Unbound, egui::Button::new("Clear")).clicked() {
+            binding.input = Input::Unbound;
+            changed = true;
+        }
+        if action.is_axis() {
+            changed |= ui.checkbox(&mut binding.invert, "Invert").changed();
+            ui.label("Deadzone: ");
+            changed |= ui.add(egui::DragValue::new(&mut binding.deadzone)
+                .clamp_range(0.0..=MAX_DEADZONE)
+                .speed(0.01)).changed();
+            egui::ComboBox::from_id_source(("curve", action))
+                .selected_text(format!("{:?}", binding.curve))
+                .show_ui(ui, |ui| {
+                    for curve in Curve::ALL {
+                        changed |= ui.selectable_value(&mut binding.curve, curve, format!("{:?}", curve)).changed();
+                    }
+                });
+            ui.label("Ramp: ");
+            changed |= ui.add(egui::DragValue::new(&mut binding.ramp)
+                .clamp_range(0.0..=MAX_RAMP_SECS)
+                .speed(0.01)
+                .suffix(" s")).changed();
+        }
+        changed
+    }
+
+    /// Binds the input the operator pressed to the action being captured, returning whether a
+    /// binding changed
+    fn capture_input(&mut self, ui: &egui::Ui, gamepad: Option<&GamepadState>, bindings: &mut Bindings) -> bool {
+        let Some(mut capture) = self.capture.take() else { return false };
+        let axis_values: Vec<f32> = AXES.iter()
+            .map(|&axis| gamepad.map_or(0.0, |gamepad| gamepad.axis(axis)))
+            .collect();
+        // the first frame only records where the axes rest
+        if capture.resting.is_empty() {
+            capture.resting = axis_values;
+            self.capture = Some(capture);
+            return false
+        }
+
+        let (key, modifier) = ui.input(|input| {
+            let key = Key::ALL.iter().copied().find(|&key| input.pressed(key));
+            (key, Modifier::find_held(input.modifiers))
+        });
+        let button = gamepad.and_then(|gamepad| {
+            BUTTONS.iter().copied().find(|&button| gamepad.button(button).pressed)
+        });
+        let axis = AXES.iter().zip(&axis_values).zip(&capture.resting)
+            .find(|((_, value), resting)| (*value - *resting).abs() > CAPTURE_AXIS_THRESHOLD)
+            .map(|((&axis, value), resting)| (axis, value < resting));
+
+        let is_axis = capture.action.is_axis();
+        let binding = bindings.get_mut(capture.action);
+        let input = match (key, button, axis, modifier) {
+            (Some(Key::Escape), ..) => return false,
+            (Some(key), ..) if is_axis => match capture.negative {
+                None => {
+                    capture.negative = Some(key);
+                    self.capture = Some(capture);
+                    return false
+                }
+                Some(negative) => Input::KeyPair { negative, positive: key },
+            },
+            (Some(key), ..) => Input::Key(key),
+            (None, Some(button), ..) => Input::GamepadButton(button),
+            (None, None, Some((axis, inverted)), _) if is_axis => {
+                binding.invert = inverted;
+                Input::GamepadAxis(axis)
+            }
+            (None, None, _, Some(modifier)) if !is_axis => Input::Modifier(modifier),
+            _ => {
+                self.capture = Some(capture);
+                return false
+            }
+        };
+        if !matches!(input, Input::GamepadAxis(_)) {
+            binding.invert = false;
+        }
+        binding.input = input;
+        true
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Profile {
+    /// Name of the gamepad model, for anyone reading the file
+    name: String,
+    bindings: Bindings,
+}
+
+/// Bindings for each gamepad model, stored as JSON in the operator's config directory
+struct Profiles {
+    path: PathBuf,
+    /// Keyed by gamepad UUID
+    profiles: BTreeMap<String, Profile>,
+    /// Why the profiles couldn't be loaded
+    error: Option<String>,
+}
+
+impl Profiles {
+    fn load() -> Self {
+        Self::load_from(config::path("bindings.json"))
+    }
+
+    fn load_from(path: PathBuf) -> Self {
+        let (profiles, error) = match config::read::<BTreeMap<String, Profile>>(&path, "input bindings") {
+            Ok(mut profiles) => {
+                // the file may have been edited by hand
+                for profile in profiles.values_mut() {
+                    profile.bindings.sanitize();
+                }
+                (profiles, None)
+            }
+            Err(e) => {
+                log::warn!("{:#}", e);
+                (BTreeMap::new(), Some(format!("{:#}", e)))
+            }
+        };
+        Self { path, profiles, error }
+    }
+
+    fn key(gamepad: Option<&GamepadState>) -> String {
+        match gamepad {
+            Some(gamepad) => gamepad.uuid.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            None => KEYBOARD_PROFILE.to_string(),
+        }
+    }
+
+    fn get(&self, gamepad: Option<&GamepadState>) -> Bindings {
+        self.profiles.get(&Self::key(gamepad))
+            .map(|profile| profile.bindings.clone())
+            .unwrap_or_else(|| Bindings::default_for(gamepad))
+    }
+
+    fn set(&mut self, gamepad: Option<&GamepadState>, bindings: Bindings) {
+        let name = gamepad.map_or_else(|| KEYBOARD_PROFILE.to_string(), |gamepad| gamepad.name.clone());
+        self.profiles.insert(Self::key(gamepad), Profile { name, bindings });
+    }
+
+    fn save(&self) -> Result<()> {
+        config::write(&self.path, &self.profiles, "input bindings")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keys held down, with nothing pressed this frame
+    struct HeldKeys(Vec<Key>);
+
+    impl Keys for HeldKeys {
+        fn down(&self, key: Key) -> bool {
+            self.0.contains(&key)
+        }
+
+        fn pressed(&self, _key: Key) -> bool {
+            false
+        }
+
+        fn modifiers(&self) -> egui::Modifiers {
+            egui::Modifiers::NONE
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mappie-bindings-{}-{}.json", name, std::process::id()))
+    }
+
+    fn keyboard_panel() -> ControlsPanel {
+        ControlsPanel::with_profiles(Profiles::load_from(temp_path("missing")))
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-6, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn deadzone_rescales_from_its_edge() {
+        assert_eq!(apply_deadzone(0.1, 0.2), 0.0);
+        assert_eq!(apply_deadzone(-0.2, 0.2), 0.0);
+        assert_close(apply_deadzone(0.6, 0.2), 0.5);
+        assert_close(apply_deadzone(-0.6, 0.2), -0.5);
+        assert_close(apply_deadzone(1.0, 0.2), 1.0);
+        assert_close(apply_deadzone(-1.0, 0.9), -1.0);
+        assert_close(apply_deadzone(0.3, 0.0), 0.3);
+    }
+
+    #[test]
+    fn curves_keep_sign_and_range() {
+        for curve in Curve::ALL {
+            assert_eq!(curve.apply(0.0), 0.0);
+            assert_eq!(curve.apply(1.0), 1.0);
+            assert_eq!(curve.apply(-1.0), -1.0);
+        }
+        assert_close(Curve::Linear.apply(-0.5), -0.5);
+        assert_close(Curve::Quadratic.apply(-0.5), -0.25);
+        assert_close(Curve::Cubic.apply(0.5), 0.125);
+    }
+
+    #[test]
+    fn hand_edited_settings_are_clamped() {
+        let path = temp_path("clamped");
+        let mut bindings = Bindings::keyboard();
+        bindings.get_mut(Action::TranslateX).deadzone = 1.0;
+        bindings.get_mut(Action::TranslateY).deadzone = -0.5;
+        bindings.get_mut(Action::Rotate).ramp = 30.0;
+        let profiles = BTreeMap::from([(KEYBOARD_PROFILE.to_string(), Profile {
+            name: KEYBOARD_PROFILE.to_string(),
+            bindings,
+        })]);
+        config::write(&path, &profiles, "input bindings").unwrap();
+
+        let loaded = Profiles::load_from(path.clone());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.error, None);
+        let bindings = loaded.get(None);
+        assert_eq!(bindings.get(Action::TranslateX).deadzone, MAX_DEADZONE);
+        assert_eq!(bindings.get(Action::TranslateY).deadzone, 0.0);
+        assert_eq!(bindings.get(Action::Rotate).ramp, MAX_RAMP_SECS);
+
+        // a full-scale key press still drives at full speed, never at infinity
+        let mut panel = ControlsPanel::with_profiles(loaded);
+        let (state, _) = panel.read_keys(Some(&HeldKeys(vec![Key::D])), None);
+        assert!(state.translate.0.is_finite());
+    }
+
+    #[test]
+    fn keys_ramp_up_and_down() {
+        let mut panel = keyboard_panel();
+        let forward = HeldKeys(vec![Key::W]);
+
+        // the first read only starts the clock
+        let (state, ramping) = panel.read_keys(Some(&forward), None);
+        assert_eq!(state.translate, (0.0, 0.0));
+        assert!(ramping);
+
+        panel.last_read = Some(Instant::now() - Duration::from_millis(50));
+        let (state, ramping) = panel.read_keys(Some(&forward), None);
+        let expected = 0.05 / DEFAULT_KEY_RAMP_SECS;
+        assert!(state.translate.1 >= expected && state.translate.1 < expected + 0.05, "{:?}", state);
+        assert!(ramping);
+
+        // stalls only count for the longest ramping step
+        panel.last_read = Some(Instant::now() - Duration::from_secs(10));
+        let (state, _) = panel.read_keys(Some(&forward), None);
+        let expected = expected + MAX_RAMP_STEP.as_secs_f32() / DEFAULT_KEY_RAMP_SECS;
+        assert!(state.translate.1 >= expected && state.translate.1 < expected + 0.05, "{:?}", state);
+
+        for _ in 0..2 {
+            panel.last_read = Some(Instant::now() - MAX_RAMP_STEP);
+            panel.read_keys(Some(&forward), None);
+        }
+        let (state, ramping) = panel.read_keys(Some(&forward), None);
+        assert_eq!(state.translate, (0.0, 1.0));
+        assert!(!ramping);
+
+        panel.last_read = Some(Instant::now() - Duration::from_millis(50));
+        let (state, ramping) = panel.read_keys(Some(&HeldKeys(Vec::new())), None);
+        assert!(state.translate.1 < 1.0 && state.translate.1 > 0.5, "{:?}", state);
+        assert!(ramping);
+    }
+}
@@ -0,0 +1,39 @@
+//! Settings kept as JSON files in the operator's config directory.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use eyre::{Result, WrapErr};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Where the settings file named `file` is kept
+pub fn path(file: &str) -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_default()
+        .join("mappie")
+        .join(file)
+}
+
+/// Reads the settings in `path`, which are described by `what` in errors. A missing file holds the
+/// default settings.
+pub fn read<T: DeserializeOwned + Default>(path: &Path, what: &str) -> Result<T> {
+    match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .wrap_err_with(|| format!("Invalid {} in {}", what, path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(eyre::Report::new(e)
+            .wrap_err(format!("Unable to read {} from {}", what, path.display()))),
+    }
+}
+
+pub fn write<T: Serialize>(path: &Path, value: &T, what: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Unable to create {}", dir.display()))?;
+    }
+    let json = serde_json::to_vec_pretty(value)
+        .wrap_err_with(|| format!("Unable to serialize {}", what))?;
+    std::fs::write(path, json)
+        .wrap_err_with(|| format!("Unable to write {} to {}", what, path.display()))
+}
@@ -0,0 +1,190 @@
+//! Finds robots on the local network by listening for the beacons they send.
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::fd::OwnedFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use eyre::{Result, WrapErr};
+use messages::{Beacon, Transport};
+use tokio::net::UdpSocket;
+
+/// Robots are forgotten once they haven't been heard from for this long
+const ROBOT_EXPIRY: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct DiscoveredRobot {
+    pub name: String,
+    pub version: String,
+    /// Where the robot accepts control messages
+    pub addr: SocketAddr,
+    pub transport: Transport,
+    last_seen: Instant,
+}
+
+impl DiscoveredRobot {
+    /// Whether the robot can be controlled from here. Only datagram control is supported.
+    pub fn connectable(&self) -> bool {
+        self.transport == Transport::Udp
+    }
+
+    /// Describes the robot for lists of the robots found
+    pub fn describe(&self) -> String {
+        let mut description = format!("{} (v{}) at {}", self.name, self.version, self.addr);
+        if !self.connectable() {
+            description.push_str(&format!(" over {:?}, which isn't supported", self.transport));
+        }
+        description
+    }
+}
+
+#[derive(Default)]
+struct State {
+    robots: Vec<DiscoveredRobot>,
+    /// Why discovery stopped, if it did
+    error: Option<String>,
+}
+
+/// Handle to the background task listening for beacons
+#[derive(Clone)]
+pub struct Discovery {
+    state: Arc<Mutex<State>>,
+}
+
+impl Discovery {
+    /// Starts listening for beacons on `port`
+    pub fn start(port: u16) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        tokio::spawn({
+            let state = Arc::clone(&state);
+            async move {
+                if let Err(e) = listen(port, &state).await {
+                    log::warn!("Robot discovery stopped: {:#}", e);
+                    lock(&state).error = Some(format!("{:#}", e));
+                    crate::gui_framework::request_redraw();
+                }
+            }
+        });
+        Self { state }
+    }
+
+    /// Robots heard from recently, sorted by name
+    pub fn robots(&self) -> Vec<DiscoveredRobot> {
+        let mut state = lock(&self.state);
+        state.robots.retain(|robot| robot.last_seen.elapsed() < ROBOT_EXPIRY);
+        let mut robots = state.robots.clone();
+        robots.sort_by(|a, b| a.name.cmp(&b.name).then(a.addr.cmp(&b.addr)));
+        robots
+    }
+
+    pub fn error(&self) -> Option<String> {
+        lock(&self.state).error.clone()
+    }
+}
+
+fn lock(state: &Mutex<State>) -> std::sync::MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+async fn listen(port: u16, state: &Mutex<State>) -> Result<()> {
+    let socket = bind_shared(port)
+        .wrap_err_with(|| format!("Unable to listen for robots on port {}", port))?;
+    log::info!("Listening for robots on port {}", port);
+
+    let mut buf = [0u8; framing::max_frame_len::<Beacon>()];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await
+            .wrap_err("Failed to receive beacon")?;
+        let beacon: Beacon = match framing::decode(&mut buf[..len]) {
+            Ok(beacon) => beacon,
+            Err(e) => {
+                log::debug!("Ignoring invalid beacon from {}: {}", from, e);
+                continue
+            }
+        };
+
+        let addr = SocketAddr::new(from.ip(), beacon.port);
+        let robot = DiscoveredRobot {
+            name: beacon.name.to_string(),
+            version: beacon.version.to_string(),
+            addr,
+            transport: beacon.transport,
+            last_seen: Instant::now(),
+        };
+
+        let mut state = lock(state);
+        match state.robots.iter_mut().find(|known| known.addr == addr) {
+            Some(known) => {
+                let changed = known.name != robot.name || known.transport != robot.transport;
+                *known = robot;
+                if changed {
+                    crate::gui_framework::request_redraw();
+                }
+            }
+            None => {
+                log::info!("Discovered robot {} at {}", robot.name, addr);
+                state.robots.push(robot);
+                crate::gui_framework::request_redraw();
+            }
+        }
+    }
+}
+
+/// Binds to `port` on every IPv4 interface, allowing other operator interfaces on the same
+/// machine to do the same
+fn bind_shared(port: u16) -> Result<UdpSocket> {
+    use rustix::net::{AddressFamily, SocketType};
+
+    let fd: OwnedFd = rustix::net::socket(AddressFamily::INET, SocketType::DGRAM, None)?;
+    rustix::net::sockopt::set_socket_reuseaddr(&fd, true)?;
+    rustix::net::bind_v4(&fd, &SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))?;
+
+    let socket = std::net::UdpSocket::from(fd);
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_beacon(port: u16, name: &str, control_port: u16, transport: Transport) {
+        let beacon = Beacon {
+            name: name.into(),
+            version: "0.1.0".into(),
+            port: control_port,
+            transport,
+        };
+        let mut encoder = framing::Encoder::<Beacon>::new();
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.send_to(encoder.encode(&beacon).unwrap(), (Ipv4Addr::LOCALHOST, port)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lists_robots_announced_on_loopback() {
+        let port = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        let discovery = Discovery::start(port);
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let robots = loop {
+            // beacons sent before the listener is bound are lost, so keep sending like a robot would
+            send_beacon(port, "udp-robot", 9090, Transport::Udp);
+            send_beacon(port, "tcp-robot", 9092, Transport::Tcp);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            let robots = discovery.robots();
+            if robots.len() == 2 || Instant::now() > deadline {
+                break robots
+            }
+        };
+
+        assert_eq!(discovery.error(), None);
+        assert_eq!(robots.len(), 2);
+        assert_eq!(robots[0].name, "tcp-robot");
+        assert_eq!(robots[0].addr, SocketAddr::from((Ipv4Addr::LOCALHOST, 9092)));
+        assert!(!robots[0].connectable());
+        assert_eq!(robots[1].name, "udp-robot");
+        assert_eq!(robots[1].addr, SocketAddr::from((Ipv4Addr::LOCALHOST, 9090)));
+        assert!(robots[1].connectable());
+    }
+}
@@ -0,0 +1,473 @@
+//! State of every connected gamepad, as seen by the UI on each frame.
+//!
+//! Input arrives as [`GamepadSample`]s from the polling thread in [`crate::gui_framework`], and
+//! is folded into [`GamepadInput`] between frames. Buttons keep whether they're held, along with
+//! whether they were pressed or released since the previous frame, so a press and release that
+//! both happen between two frames still register.
+//!
+//! Only one gamepad drives at a time, picked with a [`GamepadSelector`], so a second controller on
+//! the desk can't interfere.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use eyre::{eyre, Result, WrapErr};
+pub use gilrs::{Axis, Button, GamepadId};
+
+/// Every button a gamepad can report, in display order
+pub const BUTTONS: [Button; 19] = [
+    Button::South,
+    Button::East,
+    Button::North,
+    Button::West,
+    Button::C,
+    Button::Z,
+    Button::LeftTrigger,
+    Button::LeftTrigger2,
+    Button::RightTrigger,
+    Button::RightTrigger2,
+    Button::Select,
+    Button::Start,
+    Button::Mode,
+    Button::LeftThumb,
+    Button::RightThumb,
+    Button::DPadUp,
+    Button::DPadDown,
+    Button::DPadLeft,
+    Button::DPadRight,
+];
+
+/// Every axis a gamepad can report, in display order
+pub const AXES: [Axis; 8] = [
+    Axis::LeftStickX,
+    Axis::LeftStickY,
+    Axis::LeftZ,
+    Axis::RightStickX,
+    Axis::RightStickY,
+    Axis::RightZ,
+    Axis::DPadX,
+    Axis::DPadY,
+];
+
+/// A single change in gamepad input, stamped with when it was received
+#[derive(Clone, Debug)]
+pub struct GamepadSample {
+    pub id: GamepadId,
+    pub event: GamepadEvent,
+    pub time: Instant,
+}
+
+#[derive(Clone, Debug)]
+pub enum GamepadEvent {
+    Connected {
+        name: String,
+        /// Identifies the model of gamepad, and stays the same across reconnections
+        uuid: [u8; 16],
+    },
+    Disconnected,
+    ButtonPressed(Button),
+    ButtonReleased(Button),
+    /// An analog button's value changed, from 0 (released) to 1 (fully pressed)
+    ButtonChanged(Button, f32),
+    AxisChanged(Axis, f32),
+}
+
+impl GamepadEvent {
+    /// Converts input from gilrs, dropping events that don't change the gamepad's state.
+    /// Connection events have to be built separately, since they need the gamepad's details.
+    pub fn from_gilrs(event: gilrs::EventType) -> Option<Self> {
+        use gilrs::EventType;
+
+        Some(match event {
+            EventType::ButtonPressed(button, _) => Self::ButtonPressed(button),
+            EventType::ButtonReleased(button, _) => Self::ButtonReleased(button),
+            EventType::ButtonChanged(button, value, _) => Self::ButtonChanged(button, value),
+            EventType::AxisChanged(axis, value, _) => Self::AxisChanged(axis, value),
+            EventType::Disconnected => Self::Disconnected,
+            EventType::Connected | EventType::ButtonRepeated(..) | EventType::Dropped => return None,
+        })
+    }
+}
+
+/// Polls gamepads on a dedicated thread, handing each change in input to `on_sample` as soon as
+/// it arrives
+pub fn spawn_poller(mut on_sample: impl FnMut(GamepadSample) + Send + 'static) -> Result<()> {
+    let (init_tx, init_rx) = std::sync::mpsc::sync_channel(1);
+    std::thread::Builder::new()
+        .name("gamepad input".to_string())
+        .spawn(move || {
+            let mut gilrs = match gilrs::Gilrs::new() {
+                Ok(gilrs) => {
+                    let _ = init_tx.send(Ok(()));
+                    gilrs
+                }
+                Err(e) => {
+                    let _ = init_tx.send(Err(eyre!("Unable to acquire gamepad input context: {}", e)));
+                    return
+                }
+            };
+
+            // gamepads that were plugged in before startup don't get connection events
+            for (id, gamepad) in gilrs.gamepads() {
+                let event = GamepadEvent::Connected {
+                    name: gamepad.name().to_string(),
+                    uuid: gamepad.uuid(),
+                };
+                on_sample(GamepadSample { id, event, time: Instant::now() });
+            }
+
+            loop {
+                let Some(gilrs::Event { id, event, .. }) = gilrs.next_event_blocking(None) else {
+                    continue
+                };
+                let time = Instant::now();
+                let event = match event {
+                    gilrs::EventType::Connected => {
+                        let gamepad = gilrs.gamepad(id);
+                        GamepadEvent::Connected {
+                            name: gamepad.name().to_string(),
+                            uuid: gamepad.uuid(),
+                        }
+                    }
+                    event => match GamepadEvent::from_gilrs(event) {
+                        Some(event) => event,
+                        None => continue,
+                    },
+                };
+                on_sample(GamepadSample { id, event, time });
+            }
+        })
+        .wrap_err("Failed to start gamepad input thread")?;
+
+    init_rx.recv()
+        .wrap_err("Gamepad input thread exited during initialization")?
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ButtonState {
+    pub held: bool,
+    /// Whether the button went down since the previous frame
+    pub pressed: bool,
+    /// Whether the button came up since the previous frame
+    pub released: bool,
+    /// How far the button is pressed, from 0 to 1. Digital buttons are only ever 0 or 1.
+    pub value: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct GamepadState {
+    pub id: GamepadId,
+    pub name: String,
+    pub uuid: [u8; 16],
+    pub connected: bool,
+    /// When input was last received from this gamepad
+    pub last_input: Option<Instant>,
+    buttons: HashMap<Button, ButtonState>,
+    axes: HashMap<Axis, f32>,
+}
+
+impl GamepadState {
+    fn new(id: GamepadId) -> Self {
+        Self {
+            id,
+            name: String::new(),
+            uuid: [0; 16],
+            connected: false,
+            last_input: None,
+            buttons: HashMap::new(),
+            axes: HashMap::new(),
+        }
+    }
+
+    pub fn button(&self, button: Button) -> ButtonState {
+        self.buttons.get(&button).copied().unwrap_or_default()
+    }
+
+    pub fn held(&self, button: Button) -> bool {
+        self.button(button).held
+    }
+
+    pub fn axis(&self, axis: Axis) -> f32 {
+        self.axes.get(&axis).copied().unwrap_or(0.0)
+    }
+
+    /// How far the lower triggers are pulled, from 0 to 1
+    pub fn triggers(&self) -> (f32, f32) {
+        (self.button(Button::LeftTrigger2).value, self.button(Button::RightTrigger2).value)
+    }
+
+    fn apply(&mut self, event: GamepadEvent) {
+        match event {
+            GamepadEvent::Connected { name, uuid } => {
+                self.name = name;
+                self.uuid = uuid;
+                self.connected = true;
+            }
+            GamepadEvent::Disconnected => {
+                self.connected = false;
+                // nothing is held on a gamepad that's gone, and releasing everything keeps
+                // anything driven by a held button from getting stuck
+                for state in self.buttons.values_mut() {
+                    if state.held {
+                        state.released = true;
+                    }
+                    state.held = false;
+                    state.value = 0.0;
+                }
+                self.axes.clear();
+            }
+            GamepadEvent::ButtonPressed(button) => self.set_held(button, true),
+            GamepadEvent::ButtonReleased(button) => self.set_held(button, false),
+            GamepadEvent::ButtonChanged(button, value) => {
+                self.buttons.entry(button).or_default().value = value;
+            }
+            GamepadEvent::AxisChanged(axis, value) => {
+                self.axes.insert(axis, value);
+            }
+        }
+    }
+
+    fn set_held(&mut self, button: Button, held: bool) {
+        let state = self.buttons.entry(button).or_default();
+        if held && !state.held {
+            state.pressed = true;
+        }
+        if !held && state.held {
+            state.released = true;
+        }
+        state.held = held;
+        // digital buttons don't report a separate value
+        if held && state.value == 0.0 {
+            state.value = 1.0;
+        } else if !held {
+            state.value = 0.0;
+        }
+    }
+
+    fn end_frame(&mut self) {
+        for state in self.buttons.values_mut() {
+            state.pressed = false;
+            state.released = false;
+        }
+    }
+}
+
+/// Input from every gamepad seen since the operator interface started
+#[derive(Clone, Debug, Default)]
+pub struct GamepadInput {
+    /// In the order they were first seen. Disconnected gamepads are kept, so they come back with
+    /// the same identity when reconnected.
+    pub gamepads: Vec<GamepadState>,
+    /// When the latest input change was received from a gamepad
+    pub timestamp: Option<Instant>,
+}
+
+impl GamepadInput {
+    pub fn connected(&self) -> impl Iterator<Item = &GamepadState> {
+        self.gamepads.iter().filter(|gamepad| gamepad.connected)
+    }
+
+    pub fn gamepad(&self, id: GamepadId) -> Option<&GamepadState> {
+        self.gamepads.iter().find(|gamepad| gamepad.id == id)
+    }
+
+    pub(crate) fn apply(&mut self, sample: GamepadSample) {
+        let index = match self.gamepads.iter().position(|gamepad| gamepad.id == sample.id) {
+            Some(index) => index,
+            None => {
+                self.gamepads.push(GamepadState::new(sample.id));
+                self.gamepads.len() - 1
+            }
+        };
+        let gamepad = &mut self.gamepads[index];
+        match &sample.event {
+            GamepadEvent::Connected { name, .. } => log::info!("Gamepad {} connected", name),
+            GamepadEvent::Disconnected => log::warn!("Gamepad {} disconnected", gamepad.name),
+            _ => {}
+        }
+
+        let is_input = !matches!(sample.event, GamepadEvent::Connected { .. } | GamepadEvent::Disconnected);
+        if is_input {
+            // only connected gamepads send input, even if the connection went unreported
+            gamepad.connected = true;
+            gamepad.last_input = Some(sample.time);
+        }
+        gamepad.apply(sample.event);
+        self.timestamp = Some(sample.time);
+    }
+
+    /// Forgets which buttons were pressed and released, once the UI has seen them
+    pub(crate) fn end_frame(&mut self) {
+        for gamepad in &mut self.gamepads {
+            gamepad.end_frame();
+        }
+    }
+}
+
+/// Who's driving the robot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Driver {
+    Gamepad(GamepadId),
+    /// The keyboard and on-screen joysticks
+    Keyboard,
+}
+
+/// Which gamepad drives the robot. Until the operator picks one, the first gamepad to connect
+/// is used. A gamepad stays picked while it's disconnected, so unplugging it stops the robot
+/// instead of handing control to another controller.
+#[derive(Clone, Debug, Default)]
+pub struct GamepadSelector {
+    driver: Option<Driver>,
+}
+
+impl GamepadSelector {
+    pub fn update(&mut self, input: &GamepadInput) {
+        if self.driver.is_none() {
+            if let Some(gamepad) = input.connected().next() {
+                log::info!("Driving with gamepad {}", gamepad.name);
+                self.driver = Some(Driver::Gamepad(gamepad.id));
+            }
+        }
+    }
+
+    pub fn driver(&self) -> Driver {
+        self.driver.unwrap_or(Driver::Keyboard)
+    }
+
+    /// The gamepad that's driving, whether or not it's still connected
+    pub fn selected<'a>(&self, input: &'a GamepadInput) -> Option<&'a GamepadState> {
+        match self.driver() {
+            Driver::Gamepad(id) => input.gamepad(id),
+            Driver::Keyboard => None,
+        }
+    }
+
+    pub fn draw(&mut self, ui: &mut egui::Ui, input: &GamepadInput) {
+        let describe = |driver: Driver| match driver {
+            Driver::Gamepad(id) => match input.gamepad(id) {
+                Some(gamepad) if gamepad.connected => gamepad.name.clone(),
+                Some(gamepad) => format!("{} (disconnected)", gamepad.name),
+                None => "Unknown gamepad".to_string(),
+            },
+            Driver::Keyboard => "Keyboard and on-screen joysticks".to_string(),
+        };
+
+        ui.horizontal(|ui| {
+            ui.label("Controller: ");
+            let mut driver = self.driver();
+            egui::ComboBox::from_id_source("controller")
+                .selected_text(describe(driver))
+                .show_ui(ui, |ui| {
+                    for gamepad in &input.gamepads {
+                        let option = Driver::Gamepad(gamepad.id);
+                        ui.selectable_value(&mut driver, option, describe(option));
+                    }
+                    ui.selectable_value(&mut driver, Driver::Keyboard, describe(Driver::Keyboard));
+                });
+            if driver != self.driver() {
+                log::info!("Driving with {}", describe(driver));
+                self.driver = Some(driver);
+            }
+        });
+    }
+}
+
+/// Shows the state of a gamepad, for checking that it's read correctly
+pub fn draw_debug(ui: &mut egui::Ui, gamepad: Option<&GamepadState>) {
+    let Some(gamepad) = gamepad else {
+        ui.label("No gamepad selected");
+        return
+    };
+
+    ui.label(format!("Gamepad: {}", gamepad.name));
+    for axis in AXES {
+        ui.label(format!("{:?}: {:.3}", axis, gamepad.axis(axis)));
+    }
+    ui.label(format!("Triggers: {:?}", gamepad.triggers()));
+    let held: Vec<_> = BUTTONS.iter()
+        .filter(|&&button| gamepad.held(button))
+        .map(|button| format!("{:?}", button))
+        .collect();
+    ui.label(format!("Held: {}", held.join(", ")));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// gilrs doesn't let ids be created directly, but they deserialize from their index
+    fn id(index: usize) -> GamepadId {
+        serde_json::from_value(serde_json::json!(index)).unwrap()
+    }
+
+    fn sample(id: GamepadId, event: GamepadEvent) -> GamepadSample {
+        GamepadSample { id, event, time: Instant::now() }
+    }
+
+    fn connected(index: usize) -> GamepadInput {
+        let mut input = GamepadInput::default();
+        input.apply(sample(id(index), GamepadEvent::Connected { name: "pad".to_string(), uuid: [0; 16] }));
+        input
+    }
+
+    #[test]
+    fn press_and_release_within_a_frame_report_both_edges() {
+        let mut input = connected(0);
+        input.apply(sample(id(0), GamepadEvent::ButtonPressed(Button::South)));
+        input.apply(sample(id(0), GamepadEvent::ButtonReleased(Button::South)));
+
+        let state = input.gamepad(id(0)).unwrap().button(Button::South);
+        assert!(state.pressed && state.released);
+        assert!(!state.held);
+    }
+
+    #[test]
+    fn edges_clear_at_the_end_of_the_frame() {
+        let mut input = connected(0);
+        input.apply(sample(id(0), GamepadEvent::ButtonPressed(Button::South)));
+        input.end_frame();
+
+        let state = input.gamepad(id(0)).unwrap().button(Button::South);
+        assert_eq!(state, ButtonState { held: true, pressed: false, released: false, value: 1.0 });
+
+        // repeated presses of a held button aren't new presses
+        input.apply(sample(id(0), GamepadEvent::ButtonPressed(Button::South)));
+        assert!(!input.gamepad(id(0)).unwrap().button(Button::South).pressed);
+    }
+
+    #[test]
+    fn disconnecting_releases_everything() {
+        let mut input = connected(0);
+        input.apply(sample(id(0), GamepadEvent::ButtonPressed(Button::South)));
+        input.apply(sample(id(0), GamepadEvent::ButtonChanged(Button::RightTrigger2, 0.7)));
+        input.apply(sample(id(0), GamepadEvent::AxisChanged(Axis::LeftStickY, 0.9)));
+        input.end_frame();
+
+        input.apply(sample(id(0), GamepadEvent::Disconnected));
+        let gamepad = input.gamepad(id(0)).unwrap();
+        assert!(!gamepad.connected);
+        assert_eq!(gamepad.button(Button::South), ButtonState { released: true, ..ButtonState::default() });
+        assert_eq!(gamepad.triggers(), (0.0, 0.0));
+        assert_eq!(gamepad.axis(Axis::LeftStickY), 0.0);
+        assert_eq!(input.connected().count(), 0);
+    }
+
+    #[test]
+    fn gamepads_keep_separate_state() {
+        let mut input = connected(0);
+        input.apply(sample(id(1), GamepadEvent::Connected { name: "other".to_string(), uuid: [1; 16] }));
+        input.apply(sample(id(0), GamepadEvent::ButtonPressed(Button::South)));
+        input.apply(sample(id(1), GamepadEvent::AxisChanged(Axis::LeftStickX, -0.5)));
+
+        let first = input.gamepad(id(0)).unwrap();
+        let second = input.gamepad(id(1)).unwrap();
+        assert!(first.held(Button::South));
+        assert!(!second.held(Button::South));
+        assert_eq!(first.axis(Axis::LeftStickX), 0.0);
+        assert_eq!(second.axis(Axis::LeftStickX), -0.5);
+
+        input.apply(sample(id(1), GamepadEvent::Disconnected));
+        assert!(input.gamepad(id(0)).unwrap().held(Button::South));
+        assert_eq!(input.connected().map(|gamepad| gamepad.id).collect::<Vec<_>>(), vec![id(0)]);
+    }
+}
@@ -1,5 +1,7 @@
 use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use eyre::{Result, WrapErr, eyre};
 use egui_winit::winit;
@@ -9,14 +11,17 @@ use once_cell::sync::OnceCell;
 use winit::event::{Event, WindowEvent};
 use winit::event_loop::EventLoopProxy;
 
+use crate::gamepad::{self, GamepadInput, GamepadSample};
+
 static ELOOP_PROXY: OnceCell<Mutex<EventLoopProxy<UserEvent>>> = OnceCell::new();
 static ASYNC_RUNTIME: OnceCell<tokio::runtime::Runtime> = OnceCell::new();
 
 fn proxy_send_best_effort(event: UserEvent) {
     let eloop = match ELOOP_PROXY.get() {
         Some(eloop) => eloop,
+        // expected when running headless
         None => {
-            log::warn!("Early redraw request on nonexistant event loop");
+            log::debug!("Early redraw request on nonexistant event loop");
             return
         },
     };
@@ -41,6 +46,20 @@ pub fn request_redraw() {
     proxy_send_best_effort(UserEvent::RequestRedraw);
 }
 
+/// Redraws once `delay` has passed, for things that change without any input, like timers. While
+/// one of these is pending, further ones are ignored.
+pub fn request_redraw_after(delay: Duration) {
+    static PENDING: AtomicBool = AtomicBool::new(false);
+    if PENDING.swap(true, Ordering::AcqRel) {
+        return
+    }
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        PENDING.store(false, Ordering::Release);
+        request_redraw();
+    });
+}
+
 pub fn spawn<F>(future: F) -> tokio::task::JoinHandle<F::Output>
 where
     F: Future + Send + 'static,
@@ -63,49 +82,10 @@ where
     rt.block_on(future)
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 enum UserEvent {
     RequestRedraw,
-}
-
-#[derive(Clone, Default)]
-pub struct GamepadInput {
-    pub dpad_down: bool,
-    pub dpad_right: bool,
-    pub dpad_up: bool,
-    pub dpad_left: bool,
-
-    pub left_stick: (f32, f32),
-    pub right_stick: (f32, f32),
-}
-
-impl GamepadInput {
-    fn button_state_mut(&mut self, button: gilrs::Button) -> Option<&mut bool> {
-        Some(match button {
-            gilrs::Button::DPadDown => &mut self.dpad_down,
-            gilrs::Button::DPadRight => &mut self.dpad_right,
-            gilrs::Button::DPadUp => &mut self.dpad_up,
-            gilrs::Button::DPadLeft => &mut self.dpad_left,
-            _ => return None,
-        })
-    }
-
-    fn axis_state_mut(&mut self, axis: gilrs::Axis) -> Option<&mut f32> {
-        Some(match axis {
-            gilrs::Axis::LeftStickX => &mut self.left_stick.0,
-            gilrs::Axis::LeftStickY => &mut self.left_stick.1,
-            gilrs::Axis::RightStickX => &mut self.right_stick.0,
-            gilrs::Axis::RightStickY => &mut self.right_stick.1,
-            _ => return None,
-        })
-    }
-
-    fn clear(&mut self) {
-        self.dpad_down = false;
-        self.dpad_right = false;
-        self.dpad_up = false;
-        self.dpad_left = false;
-    }
+    Gamepad(GamepadSample),
 }
 
 pub trait App {
@@ -189,38 +169,13 @@ where
 
     let mut gamepad_input_dirty = false;
     let mut gamepad = GamepadInput::default();
-    let mut gilrs = gilrs::Gilrs::new()
-        .map_err(|e| eyre!("Unable to acquire gamepad input context: {}", e))?;
     let mut graphics = once_cell::unsync::OnceCell::<GraphicsContext>::new();
     let event_loop = winit::event_loop::EventLoopBuilder::<UserEvent>::with_user_event().build()
         .context("Failed to acquire window event loop")?;
     ELOOP_PROXY.get_or_init(|| Mutex::new(event_loop.create_proxy()));
+    gamepad::spawn_poller(|sample| proxy_send_best_effort(UserEvent::Gamepad(sample)))?;
 
     event_loop.run(move |event, event_loop| {
-        while let Some(gamepad_event) = gilrs.next_event() {
-            match gamepad_event.event {
-                gilrs::EventType::AxisChanged(axis, value, _) => {
-                    if let Some(axis) = gamepad.axis_state_mut(axis) {
-                        *axis = value;
-                    }
-                    gamepad_input_dirty = true;
-                },
-                gilrs::EventType::ButtonPressed(button, _) => {
-                    if let Some(button) = gamepad.button_state_mut(button) {
-                        *button = true;
-                    }
-                    gamepad_input_dirty = true;
-                }
-                gilrs::EventType::ButtonReleased(button, _) => {
-                    if let Some(button) = gamepad.button_state_mut(button) {
-                        *button = false;
-                    }
-                    gamepad_input_dirty = true;
-                }
-                _ => {}
-            }
-        }
-
         match event {
             Event::WindowEvent { event: window_event, .. } => {
                 let graphics = match graphics.get_mut() {
@@ -253,7 +208,7 @@ where
                                 app.update(ctx, gamepad.clone())
                             });
 
-                            gamepad.clear();
+                            gamepad.end_frame();
                             gamepad_input_dirty = false;
                             graphics.pending_output.append(full_output);
                             let egui::FullOutput {
@@ -293,6 +248,15 @@ where
                     }
                 }
             }
+            Event::UserEvent(UserEvent::Gamepad(sample)) => {
+                gamepad.apply(sample);
+                gamepad_input_dirty = true;
+            }
+            Event::UserEvent(UserEvent::RequestRedraw) => {
+                if let Some(graphics) = graphics.get() {
+                    graphics.window.request_redraw();
+                }
+            }
             Event::Resumed => {
                 // lazy initialize the graphics context because it's required on Android
                 match graphics.get_or_try_init(move || rt.block_on(graphics_constructor(event_loop))) {
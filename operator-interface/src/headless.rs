@@ -0,0 +1,637 @@
+//! Terminal operator interface, for machines without a graphical session.
+//!
+//! Drives with the same gamepad handling, bindings and robot link as the graphical interface,
+//! showing the connection and the robot's status as a TUI. Terminals only report key releases
+//! when they support the kitty keyboard protocol. Elsewhere there's no telling how long a key was
+//! held, so the keyboard can only trigger actions like the e-stop, and driving needs a gamepad.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Stdout, Write};
+use std::net::IpAddr;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::event::{KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags};
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use egui::Key;
+use eyre::{Result, WrapErr};
+use mappie_client::Client;
+use messages::{Command, DriveStatus, Mode, Move, Status};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::{Frame, Terminal};
+use tokio::task::JoinHandle;
+
+use crate::bindings::{ActionState, ControlsPanel, Keys};
+use crate::discovery::Discovery;
+use crate::gamepad::{self, GamepadInput, GamepadSample, GamepadSelector};
+use crate::link::{RequestEvent, RobotLink};
+
+/// Time between updates of the robot's commands and the screen
+const TICK: Duration = Duration::from_millis(20);
+
+/// How long a key counts as down after it was last reported, when the terminal doesn't report
+/// releases. Longer than the usual delay before keys repeat, so key-repeats aren't mistaken for new
+/// presses. Such keys never drive, so this doesn't keep the robot moving.
+const KEY_HOLD: Duration = Duration::from_millis(600);
+
+/// Longest time to wait for a stop to be sent before disconnecting
+const DISCONNECT_STOP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Log lines kept for display
+const LOG_LINES: usize = 200;
+
+pub fn run(robot: Option<String>, port: u16, local: Option<IpAddr>) -> Result<()> {
+    let log = LogBuffer::default();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .target(env_logger::Target::Pipe(Box::new(log.clone())))
+        .write_style(env_logger::WriteStyle::Never)
+        .init();
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .wrap_err("Asynchronous runtime construction failed")?;
+    let _enable_async_spawn = rt.enter();
+
+    let (gamepad_tx, gamepad_rx) = mpsc::channel();
+    if let Err(e) = gamepad::spawn_poller(move |sample| {
+        let _ = gamepad_tx.send(sample);
+    }) {
+        log::warn!("Driving without gamepads: {:#}", e);
+    }
+
+    let mut terminal = TerminalGuard::enter()?;
+    let mut tui = Tui {
+        screen: Screen::Choosing,
+        port,
+        local,
+        discovery: Discovery::start(messages::DISCOVERY_PORT),
+        gamepads: GamepadInput::default(),
+        selector: GamepadSelector::default(),
+        controls: ControlsPanel::new(),
+        actions: ActionState::default(),
+        keys: TerminalKeys {
+            reports_releases: terminal.enhanced,
+            ..TerminalKeys::default()
+        },
+        error: None,
+        log,
+        quit: false,
+    };
+    if let Some(robot) = robot {
+        tui.connect(robot);
+    }
+
+    while !tui.quit {
+        let deadline = Instant::now() + TICK;
+        while let Ok(sample) = gamepad_rx.try_recv() {
+            tui.apply_gamepad(sample);
+        }
+        while event::poll(deadline.saturating_duration_since(Instant::now()))? {
+            if let Event::Key(key) = event::read()? {
+                tui.handle_key(key);
+            }
+        }
+
+        tui.update(&rt);
+        terminal.terminal.draw(|frame| tui.draw(frame))?;
+        tui.gamepads.end_frame();
+        tui.keys.end_frame();
+    }
+    Ok(())
+}
+
+enum Screen {
+    /// Picking a robot to connect to
+    Choosing,
+    Connecting {
+        target: String,
+        task: JoinHandle<Result<Client>>,
+    },
+    Driving {
+        target: String,
+        link: Box<RobotLink>,
+        /// Whether the robot had stopped answering as of the last tick
+        lost: bool,
+        /// When the operator asked to disconnect, and how many commands had been sent by then.
+        /// The link is kept until a stop has gone out.
+        disconnecting: Option<(Instant, u64)>,
+    },
+}
+
+struct Tui {
+    screen: Screen,
+    /// Port used for robots whose address doesn't include one
+    port: u16,
+    /// Local address to connect from, if not left to the OS
+    local: Option<IpAddr>,
+    discovery: Discovery,
+    gamepads: GamepadInput,
+    selector: GamepadSelector,
+    controls: ControlsPanel,
+    /// What the operator asked for on the last tick
+    actions: ActionState,
+    keys: TerminalKeys,
+    /// Why the last connection failed
+    error: Option<String>,
+    log: LogBuffer,
+    quit: bool,
+}
+
+impl Tui {
+    fn connect(&mut self, target: String) {
+        log::info!("Connecting to {}", target);
+        let task = tokio::spawn(crate::connect_to_robot(target.clone(), self.port, self.local));
+        self.screen = Screen::Connecting { target, task };
+        self.error = None;
+    }
+
+    fn apply_gamepad(&mut self, sample: GamepadSample) {
+        self.gamepads.apply(sample);
+        self.selector.update(&self.gamepads);
+    }
+
+    fn handle_key(&mut self, event: KeyEvent) {
+        let is_press = event.kind == KeyEventKind::Press;
+        // raw mode swallows the usual interrupt
+        if is_press && event.code == KeyCode::Char('c') && event.modifiers.contains(KeyModifiers::CONTROL) {
+            self.quit = true;
+            return
+        }
+
+        match &mut self.screen {
+            Screen::Choosing => match event.code {
+                KeyCode::Esc if is_press => self.quit = true,
+                KeyCode::Char(c) if is_press => {
+                    let robot = c.to_digit(10)
+                        .and_then(|i| (i as usize).checked_sub(1))
+                        .and_then(|i| self.discovery.robots().into_iter().nth(i));
+                    match robot {
+                        Some(robot) if robot.connectable() => self.connect(robot.addr.to_string()),
+                        Some(robot) => {
+                            self.error = Some(format!("Can't connect to {}, it only accepts {:?} control",
+                                robot.name, robot.transport));
+                        }
+                        None => {}
+                    }
+                }
+                _ => {}
+            },
+            Screen::Connecting { .. } => {
+                if is_press && event.code == KeyCode::Esc {
+                    self.screen = Screen::Choosing;
+                }
+            }
+            Screen::Driving { disconnecting: Some(_), .. } => {}
+            Screen::Driving { link, disconnecting, .. } => match event.code {
+                KeyCode::Esc if is_press => {
+                    link.set_move(Move::stop());
+                    *disconnecting = Some((Instant::now(), link.sender().stats().sent));
+                }
+                KeyCode::F(2) if is_press => link.request_mode(Command::ClearEStop),
+                _ => self.keys.handle(event),
+            },
+        }
+    }
+
+    fn update(&mut self, rt: &tokio::runtime::Runtime) {
+        if let Screen::Connecting { target, task } = &mut self.screen {
+            if !task.is_finished() {
+                return
+            }
+            let target = std::mem::take(target);
+            self.screen = match rt.block_on(task) {
+                Ok(Ok(client)) => {
+                    log::info!("Connected to {}", target);
+                    Screen::Driving {
+                        target,
+                        link: Box::new(RobotLink::new(client)),
+                        lost: false,
+                        disconnecting: None,
+                    }
+                }
+                Ok(Err(e)) => {
+                    self.error = Some(format!("Network connection failed: {:#}", e));
+                    Screen::Choosing
+                }
+                Err(e) => {
+                    self.error = Some(format!("Connection task got cancelled: {}", e));
+                    Screen::Choosing
+                }
+            };
+        }
+
+        let Screen::Driving { target, link, lost, disconnecting } = &mut self.screen else { return };
+        if let Some((since, sent)) = *disconnecting {
+            // the tick that was going out when the operator asked may still carry the old movement,
+            // so wait for the one after it
+            if link.sender().stats().sent >= sent + 2 || since.elapsed() >= DISCONNECT_STOP_TIMEOUT {
+                log::info!("Disconnected from {}", target);
+                self.screen = Screen::Choosing;
+                self.actions = ActionState::default();
+            }
+            return
+        }
+        for event in link.poll() {
+            match event {
+                RequestEvent::Answered(request, response) => {
+                    log::debug!("Unexpected response {:?} to request {:?}", response, request);
+                }
+                RequestEvent::TimedOut(request) => log::debug!("Robot didn't respond to {:?}", request),
+            }
+        }
+
+        if link.lost() != *lost {
+            *lost = !*lost;
+            if *lost {
+                log::warn!("Lost the link to {}, waiting for it to answer again", target);
+            } else {
+                log::info!("Link to {} restored", target);
+            }
+        }
+        if *lost {
+            // driving picks up from the operator's input once the robot answers again
+            link.set_move(Move::stop());
+            self.actions = ActionState::default();
+            return
+        }
+
+        let gamepad = self.selector.selected(&self.gamepads);
+        let (actions, _) = self.controls.read_keys(Some(&self.keys), gamepad);
+        if actions.estop {
+            link.request_mode(Command::EStop);
+        } else if actions.toggle_enable {
+            link.toggle_enable();
+        }
+        link.set_move(Move {
+            translate: [actions.translate.0, actions.translate.1].into(),
+            rotate: actions.rotate,
+        });
+        self.actions = actions;
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [header, body, log, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(10),
+            Constraint::Length(10),
+            Constraint::Length(1),
+        ]).areas(frame.size());
+
+        let (title, help_text) = match &self.screen {
+            Screen::Choosing => ("Mappie: choose a robot".to_string(), "1-9 connect · Esc/Ctrl-C quit"),
+            Screen::Connecting { target, .. } => (format!("Mappie: connecting to {}…", target), "Esc cancel · Ctrl-C quit"),
+            Screen::Driving { target, disconnecting: Some(_), .. } => (
+                format!("Mappie: stopping the robot and disconnecting from {}…", target),
+                "Ctrl-C quit",
+            ),
+            Screen::Driving { target, .. } => (
+                format!("Mappie: connected to {}", target),
+                "Esc disconnect · F2 clear e-stop · Ctrl-C quit",
+            ),
+        };
+        frame.render_widget(Paragraph::new(title).bold(), header);
+        frame.render_widget(Paragraph::new(help_text).dim(), help);
+
+        match &self.screen {
+            Screen::Choosing | Screen::Connecting { .. } => self.draw_robots(frame, body),
+            Screen::Driving { link, .. } => self.draw_driving(frame, body, link),
+        }
+
+        let lines: Vec<Line> = self.log.lines(log.height.saturating_sub(2).into())
+            .into_iter()
+            .map(Line::from)
+            .collect();
+        frame.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Log")), log);
+    }
+
+    fn draw_robots(&self, frame: &mut Frame, area: ratatui::layout::Rect) {
+        let mut lines = Vec::new();
+        if let Some(e) = &self.error {
+            lines.push(Line::from(e.clone()).red());
+        }
+        let robots = self.discovery.robots();
+        if robots.is_empty() {
+            lines.push(Line::from("No robots found yet"));
+        }
+        for (i, robot) in robots.iter().enumerate().take(9) {
+            lines.push(Line::from(format!("{}. {}", i + 1, robot.describe())));
+        }
+        if let Some(e) = self.discovery.error() {
+            lines.push(Line::from(format!("Robot discovery failed: {}", e)).yellow());
+        }
+        let block = Block::default().borders(Borders::ALL).title("Robots on this network");
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn draw_driving(&self, frame: &mut Frame, area: ratatui::layout::Rect, link: &RobotLink) {
+        let [status_area, input_area] = Layout::horizontal([Constraint::Percentage(50); 2]).areas(area);
+
+        let mut lines = health_lines(link);
+        lines.push(Line::default());
+        lines.extend(status_lines(link.status()));
+        let status = Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title("Robot"));
+        frame.render_widget(status, status_area);
+
+        let stats = link.sender().stats();
+        let mut lines = vec![
+            Line::from(format!("Commands sent: {} ({:.1}/s)", stats.sent, stats.rate_hz)),
+            Line::from(format!("Skipped: {}, failed: {}", stats.skipped, stats.errors)),
+        ];
+        if let Some(e) = &stats.last_error {
+            lines.push(Line::from(format!("Last send error: {}", e)).red());
+        }
+
+        lines.push(Line::default());
+        match self.selector.selected(&self.gamepads) {
+            Some(gamepad) if gamepad.connected => lines.push(Line::from(format!("Controller: {}", gamepad.name))),
+            Some(gamepad) => lines.push(Line::from(format!(
+                "Controller {} disconnected, so the robot is being told to stop", gamepad.name)).red().bold()),
+            None if self.keys.reports_releases => lines.push(Line::from("Controller: keyboard")),
+            None => {
+                lines.push(Line::from("Controller: none").red().bold());
+                lines.push(Line::from(
+                    "This terminal doesn't report key releases, so the keyboard can't drive. Connect a \
+                     gamepad, or use a terminal with the kitty keyboard protocol. Keys still e-stop and enable.").red());
+            }
+        }
+        let ActionState { translate, rotate, slow, .. } = self.actions;
+        lines.push(Line::from(format!("Translate: ({:+.2}, {:+.2})  Rotate: {:+.2}", translate.0, translate.1, rotate)));
+        if slow {
+            lines.push(Line::from("Speed: slow"));
+        }
+
+        let input = Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title("Operator"));
+        frame.render_widget(input, input_area);
+    }
+}
+
+fn health_lines(link: &RobotLink) -> Vec<Line<'static>> {
+    let orange = Color::Rgb(255, 100, 0);
+    let health = link.health();
+    let since = link.since_last_reply();
+
+    let millis = |duration: Option<Duration>| duration
+        .map_or_else(|| "-".to_string(), |duration| format!("{:.1} ms", duration.as_secs_f64() * 1000.0));
+    let loss = health.loss().map_or_else(|| "-".to_string(), |loss| format!("{:.0}%", loss * 100.0));
+    let mut lines = vec![Line::from(format!("RTT: {}  Jitter: {}  Loss: {}",
+        millis(health.rtt), millis(health.jitter), loss))];
+
+    let reply = Line::from(format!("Last reply: {:.1} s ago", since.as_secs_f32()));
+    if link.lost() {
+        lines.push(reply.red());
+        lines.push(Line::from("LINK LOST, the robot is being told to stop").red().bold());
+    } else if since >= crate::link::SLOW_REPLY {
+        lines.push(reply.fg(orange));
+    } else {
+        lines.push(reply);
+    }
+    lines
+}
+
+fn status_lines(status: Option<Status>) -> Vec<Line<'static>> {
+    let Some(status) = status else {
+        return vec![Line::from("Status: unknown")]
+    };
+
+    let orange = Color::Rgb(255, 100, 0);
+    let (mode, color) = match status.mode {
+        Mode::Disabled => ("Mode: disabled", orange),
+        Mode::Enabled => ("Mode: enabled", Color::Green),
+        Mode::EStopped => ("Mode: E-STOPPED", Color::Red),
+    };
+    let (drive, drive_color) = match status.drive {
+        DriveStatus::Ok => ("Drive: OK".to_string(), Color::Green),
+        DriveStatus::Recovering { consecutive_errors } => {
+            (format!("Drive: recovering ({} failed updates)", consecutive_errors), orange)
+        }
+        DriveStatus::SafeStopped => {
+            ("Drive: stopped after repeated faults, center the sticks to resume".to_string(), Color::Red)
+        }
+    };
+    vec![
+        Line::from(Span::styled(mode, Style::new().fg(color).add_modifier(Modifier::BOLD))),
+        Line::from(Span::styled(drive, Style::new().fg(drive_color))),
+        Line::from(format!("Drive errors: {}", status.drive_errors)),
+        Line::from(format!("Link losses: {}", status.link_losses)),
+        Line::from(format!("Superseded commands: {}", status.superseded_moves)),
+        Line::from(format!("Motor I2C transactions per tick: {:.2}", status.motor_transactions_per_tick)),
+    ]
+}
+
+/// Keyboard state built from terminal key events
+#[derive(Default)]
+struct TerminalKeys {
+    /// Keys that are down, with when they were last reported and the modifiers held with them
+    down: HashMap<Key, (Instant, KeyModifiers)>,
+    /// Keys pressed since the last tick
+    pressed: HashSet<Key>,
+    /// Whether the terminal reports releases, so keys don't have to time out
+    reports_releases: bool,
+}
+
+impl TerminalKeys {
+    fn handle(&mut self, event: KeyEvent) {
+        let Some(key) = egui_key(event.code) else { return };
+        match event.kind {
+            KeyEventKind::Press | KeyEventKind::Repeat => {
+                // without release events, repeats look just like presses
+                if event.kind == KeyEventKind::Press && !self.down.contains_key(&key) {
+                    self.pressed.insert(key);
+                }
+                self.down.insert(key, (Instant::now(), event.modifiers));
+            }
+            KeyEventKind::Release => {
+                self.down.remove(&key);
+            }
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.pressed.clear();
+        if !self.reports_releases {
+            self.down.retain(|_, (last_seen, _)| last_seen.elapsed() < KEY_HOLD);
+        }
+    }
+}
+
+impl Keys for TerminalKeys {
+    /// Only reported when the terminal reports releases, since otherwise there's no telling
+    /// whether a key is still held
+    fn down(&self, key: Key) -> bool {
+        self.reports_releases && self.down.contains_key(&key)
+    }
+
+    fn pressed(&self, key: Key) -> bool {
+        self.pressed.contains(&key)
+    }
+
+    fn modifiers(&self) -> egui::Modifiers {
+        if !self.reports_releases {
+            return egui::Modifiers::NONE
+        }
+        let held = self.down.values().fold(KeyModifiers::NONE, |held, (_, modifiers)| held | *modifiers);
+        egui::Modifiers {
+            alt: held.contains(KeyModifiers::ALT),
+            ctrl: held.contains(KeyModifiers::CONTROL),
+            shift: held.contains(KeyModifiers::SHIFT),
+            ..Default::default()
+        }
+    }
+}
+
+/// The egui key a terminal key code corresponds to, so bindings work the same in both interfaces
+fn egui_key(code: KeyCode) -> Option<Key> {
+    Some(match code {
+        KeyCode::Char(' ') => Key::Space,
+        KeyCode::Char(c) => return Key::from_name(&c.to_ascii_uppercase().to_string()),
+        KeyCode::Enter => Key::Enter,
+        KeyCode::Tab => Key::Tab,
+        KeyCode::Backspace => Key::Backspace,
+        KeyCode::Up => Key::ArrowUp,
+        KeyCode::Down => Key::ArrowDown,
+        KeyCode::Left => Key::ArrowLeft,
+        KeyCode::Right => Key::ArrowRight,
+        _ => return None,
+    })
+}
+
+/// Puts the terminal in raw mode on an alternate screen, restoring it when dropped
+struct TerminalGuard {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    /// Whether the terminal reports key releases
+    enhanced: bool,
+}
+
+impl TerminalGuard {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode().wrap_err("Unable to put the terminal in raw mode")?;
+        let mut stdout = std::io::stdout();
+        crossterm::execute!(stdout, EnterAlternateScreen)?;
+        let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced {
+            crossterm::execute!(stdout, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        } else {
+            log::warn!("Terminal doesn't report key releases, so the keyboard can't drive");
+        }
+
+        let terminal = Terminal::new(CrosstermBackend::new(stdout))
+            .wrap_err("Unable to set up the terminal")?;
+        Ok(Self { terminal, enhanced })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let stdout = self.terminal.backend_mut();
+        if self.enhanced {
+            let _ = crossterm::execute!(stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = crossterm::execute!(stdout, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+        let _ = self.terminal.show_cursor();
+    }
+}
+
+/// Keeps the latest log lines, since writing them to the terminal would draw over the TUI
+#[derive(Clone, Default)]
+struct LogBuffer {
+    inner: Arc<Mutex<LogLines>>,
+}
+
+#[derive(Default)]
+struct LogLines {
+    lines: VecDeque<String>,
+    /// Start of a line that hasn't been finished yet
+    partial: Vec<u8>,
+}
+
+impl LogBuffer {
+    /// The last `count` complete lines
+    fn lines(&self, count: usize) -> Vec<String> {
+        let inner = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        inner.lines.iter().skip(inner.lines.len().saturating_sub(count)).cloned().collect()
+    }
+}
+
+impl Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for &byte in buf {
+            if byte != b'\n' {
+                inner.partial.push(byte);
+                continue
+            }
+
+            let line = String::from_utf8_lossy(&inner.partial).into_owned();
+            inner.partial.clear();
+            if inner.lines.len() == LOG_LINES {
+                inner.lines.pop_front();
+            }
+            inner.lines.push_back(line);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyEventState;
+
+    fn key_event(c: char, kind: KeyEventKind) -> KeyEvent {
+        KeyEvent {
+            code: KeyCode::Char(c),
+            modifiers: KeyModifiers::NONE,
+            kind,
+            state: KeyEventState::NONE,
+        }
+    }
+
+    #[test]
+    fn keys_without_releases_never_drive() {
+        let mut keys = TerminalKeys::default();
+        keys.handle(key_event('w', KeyEventKind::Press));
+        assert!(keys.pressed(Key::W));
+        assert!(!keys.down(Key::W));
+
+        // repeats arrive as presses, but aren't new ones
+        keys.end_frame();
+        keys.handle(key_event('w', KeyEventKind::Press));
+        assert!(!keys.pressed(Key::W));
+        assert!(!keys.down(Key::W));
+    }
+
+    #[test]
+    fn keys_with_releases_drive_until_released() {
+        let mut keys = TerminalKeys {
+            reports_releases: true,
+            ..TerminalKeys::default()
+        };
+        keys.handle(key_event('w', KeyEventKind::Press));
+        assert!(keys.pressed(Key::W));
+        assert!(keys.down(Key::W));
+
+        keys.end_frame();
+        keys.handle(key_event('w', KeyEventKind::Repeat));
+        assert!(!keys.pressed(Key::W));
+        assert!(keys.down(Key::W));
+
+        keys.end_frame();
+        keys.handle(key_event('w', KeyEventKind::Release));
+        assert!(!keys.down(Key::W));
+    }
+}
@@ -0,0 +1,27 @@
+//! On-screen joystick, for driving with a mouse or touchscreen when there's no gamepad.
+
+/// Draws a joystick `size` points across that springs back to the center when let go. While it's
+/// held, returns its position from -1 to 1 on each axis, with up being positive Y like a gamepad
+/// stick.
+pub fn joystick(ui: &mut egui::Ui, size: f32) -> Option<(f32, f32)> {
+    let (rect, response) = ui.allocate_exact_size(egui::vec2(size, size), egui::Sense::drag());
+    let center = rect.center();
+    let radius = size / 2.0;
+    let knob_radius = radius * 0.3;
+    let travel = radius - knob_radius;
+
+    let value = response.interact_pointer_pos()
+        .filter(|_| response.is_pointer_button_down_on())
+        .map(|pos| {
+            let offset = (pos - center) / travel;
+            let offset = if offset.length() > 1.0 { offset.normalized() } else { offset };
+            (offset.x, -offset.y)
+        });
+
+    let knob = center + value.map_or(egui::Vec2::ZERO, |(x, y)| egui::vec2(x, -y) * travel);
+    let visuals = ui.style().interact(&response);
+    let painter = ui.painter();
+    painter.circle(center, radius, visuals.bg_fill, visuals.bg_stroke);
+    painter.circle(knob, knob_radius, visuals.fg_stroke.color, egui::Stroke::NONE);
+    value
+}
@@ -0,0 +1,142 @@
+//! Connection to a robot, shared by the graphical and terminal operator interfaces.
+//!
+//! Commands go out at a fixed rate through a [`CommandSender`], while responses to requests are
+//! collected until the interface polls for them.
+
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use mappie_client::{sender, Client, CommandSender, Health, Pinger};
+use messages::{Command, Mode, Move, Request, Response, Status};
+
+/// How often the robot is pinged to measure the link
+const PING_PERIOD: Duration = Duration::from_millis(500);
+
+/// How long the robot can go without sending anything before the link counts as lost. The robot
+/// sends its status at least every 100ms.
+pub const LINK_LOST_AFTER: Duration = Duration::from_secs(2);
+
+/// Time without a reply after which the link is shown as degraded
+pub const SLOW_REPLY: Duration = Duration::from_millis(500);
+
+/// Something that happened to a request since the last poll
+#[derive(Clone, Debug)]
+// these only live until the interface handles them, so boxing the response isn't worth it
+#[allow(clippy::large_enum_variant)]
+pub enum RequestEvent {
+    Answered(Request, Response),
+    TimedOut(Request),
+}
+
+pub struct RobotLink {
+    client: Client,
+    sender: CommandSender,
+    _pinger: Pinger,
+    connected_at: Instant,
+    /// Mode change that's sent until the robot reports it, since datagrams can be lost
+    mode_request: Option<Command>,
+    /// Requests are waited on by their own tasks, which report back here
+    events_tx: mpsc::Sender<RequestEvent>,
+    events_rx: mpsc::Receiver<RequestEvent>,
+}
+
+impl RobotLink {
+    pub fn new(client: Client) -> Self {
+        let (events_tx, events_rx) = mpsc::channel();
+        Self {
+            sender: client.command_sender(sender::DEFAULT_SEND_RATE_HZ),
+            _pinger: client.start_pinging(PING_PERIOD),
+            connected_at: Instant::now(),
+            client,
+            mode_request: None,
+            events_tx,
+            events_rx,
+        }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn sender(&self) -> &CommandSender {
+        &self.sender
+    }
+
+    pub fn health(&self) -> Health {
+        self.client.health()
+    }
+
+    /// Time since the robot last sent anything, or since connecting if it hasn't yet
+    pub fn since_last_reply(&self) -> Duration {
+        self.health().since_last_received()
+            .unwrap_or_else(|| self.connected_at.elapsed())
+    }
+
+    /// Whether the robot has gone quiet for long enough that it's probably unreachable
+    pub fn lost(&self) -> bool {
+        self.since_last_reply() >= LINK_LOST_AFTER
+    }
+
+    /// The latest status the robot reported, if any
+    pub fn status(&self) -> Option<Status> {
+        self.client.status()
+    }
+
+    pub fn mode(&self) -> Option<Mode> {
+        self.status().map(|status| status.mode)
+    }
+
+    /// Sends `command` until the robot reports the mode it asks for
+    pub fn request_mode(&mut self, command: Command) {
+        self.mode_request = Some(command);
+        self.sender.set_mode_request(self.mode_request);
+    }
+
+    /// Disables the robot if it's enabled, and enables it otherwise. Does nothing while the robot
+    /// is e-stopped, since that has to be cleared explicitly.
+    pub fn toggle_enable(&mut self) {
+        match self.mode() {
+            Some(Mode::EStopped) => {}
+            Some(Mode::Enabled) => self.request_mode(Command::Disable),
+            _ => self.request_mode(Command::Enable),
+        }
+    }
+
+    /// Replaces the movement sent to the robot on every tick
+    pub fn set_move(&self, movement: Move) {
+        self.sender.set_move(movement);
+    }
+
+    /// Sends a request, which is answered through [`Self::poll`]
+    pub fn request(&mut self, request: Request) {
+        let client = self.client.clone();
+        let events = self.events_tx.clone();
+        tokio::spawn(async move {
+            let event = match client.request(request.clone()).await {
+                Ok(response) => RequestEvent::Answered(request, response),
+                // requests that don't make it out time out, like ones lost on the network
+                Err(e) => {
+                    if !matches!(e, mappie_client::Error::Timeout(_)) {
+                        log::warn!("Failed to send {:?} to robot: {}", request, e);
+                    }
+                    RequestEvent::TimedOut(request)
+                }
+            };
+            // the interface stopped listening if this fails
+            let _ = events.send(event);
+            crate::gui_framework::request_redraw();
+        });
+    }
+
+    /// Returns what happened to outstanding requests since the last poll, and stops resending
+    /// the mode change once the robot reports it
+    pub fn poll(&mut self) -> Vec<RequestEvent> {
+        if let (Some(request), Some(mode)) = (self.mode_request, self.mode()) {
+            if mappie_client::mode_reached(request, mode) {
+                self.mode_request = None;
+                self.sender.set_mode_request(None);
+            }
+        }
+        self.events_rx.try_iter().collect()
+    }
+}
@@ -1,20 +1,55 @@
+mod bindings;
+mod config;
+mod discovery;
+mod gamepad;
 mod gui_framework;
+mod headless;
+mod joystick;
+mod link;
 mod oi;
+mod params;
 
 use std::net::{IpAddr, SocketAddr};
 
+use clap::Parser;
 use egui_winit::egui;
-use eyre::Result;
-use gui_framework::{App, GamepadInput};
+use eyre::{Result, WrapErr};
+use gamepad::{GamepadInput, GamepadSelector};
+use gui_framework::App;
+use mappie_client::Client;
 use thiserror::Error;
-use tokio::net::UdpSocket;
 // use tokio::net::TcpStream;
 use tokio::task::JoinHandle;
 use tokio::time::timeout;
 
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Run in the terminal instead of opening a window
+    #[arg(long)]
+    headless: bool,
+
+    /// Robot to connect to on startup when running headless, instead of picking a discovered one
+    #[arg(short = 'r', long)]
+    robot: Option<String>,
+
+    /// Port the robot listens for control messages on, if its address doesn't include one
+    #[arg(short = 'p', long, default_value_t = 9090)]
+    port: u16,
+
+    /// Local address to connect from, which picks the network interface to use
+    #[arg(short = 'b', long)]
+    bind: Option<IpAddr>,
+}
+
 fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.headless {
+        return headless::run(args.robot, args.port, args.bind)
+    }
+
     env_logger::init();
-    gui_framework::run(OperatorInterfaceApp::new())
+    gui_framework::run(OperatorInterfaceApp::new(args.bind))
 }
 
 #[derive(Error, Debug)]
@@ -26,18 +61,23 @@ struct OperatorInterfaceApp {
     debug_layout: bool,
     current_error: Option<Banner>,
     state: AppState,
+    discovery: discovery::Discovery,
+    gamepads: GamepadSelector,
 }
 
 impl OperatorInterfaceApp {
-    async fn new() -> Result<Self, AppError> {
+    async fn new(local: Option<IpAddr>) -> Result<Self, AppError> {
         Ok(Self {
             debug_layout: false,
             frame_count: 0,
             current_error: None,
+            discovery: discovery::Discovery::start(messages::DISCOVERY_PORT),
+            gamepads: GamepadSelector::default(),
             state: AppState::Connecting {
                 error: None,
                 address: "rpi:9090".to_string(),
                 port: 9090,
+                local: local.map(|local| local.to_string()).unwrap_or_default(),
                 connecting_task: None,
             },
         })
@@ -49,9 +89,11 @@ enum AppState {
         error: Option<Banner>,
         address: String,
         port: u16,
-        connecting_task: Option<JoinHandle<Result<UdpSocket>>>,
+        /// Local address to connect from, empty to let the OS pick
+        local: String,
+        connecting_task: Option<JoinHandle<Result<Client>>>,
     },
-    OperatorInterface(oi::OperatorInterface),
+    OperatorInterface(Box<oi::OperatorInterface>),
     FallbackError(eyre::Report),
 }
 
@@ -67,32 +109,61 @@ impl App for OperatorInterfaceApp {
                 e.draw(ui);
             }
 
+            self.gamepads.update(&gamepad);
+            self.gamepads.draw(ui, &gamepad);
+
             let mut next_state = None;
 
             'appstate: {
                 match &mut self.state {
                     AppState::Connecting {
-                        error, address, port, connecting_task
+                        error, address, port, local, connecting_task
                     } => {
                         if let Some(banner) = error {
                             banner.draw(ui);
                         }
 
                         ui.add_enabled_ui(connecting_task.is_none(), |ui| {
+                            ui.label("Robots on this network:");
+                            let robots = self.discovery.robots();
+                            if robots.is_empty() {
+                                ui.label("None found yet");
+                            }
+                            for robot in robots {
+                                ui.horizontal(|ui| {
+                                    ui.label(robot.describe());
+                                    if ui.add_enabled(robot.connectable(), egui::Button::new("Connect")).clicked() {
+                                        *address = robot.addr.ip().to_string();
+                                        *port = robot.addr.port();
+                                        *connecting_task = Some(spawn_connect(address.clone(), *port, local));
+                                    }
+                                });
+                            }
+                            if let Some(e) = self.discovery.error() {
+                                ui.label(egui::RichText::new(format!("Robot discovery failed: {}", e))
+                                    .color(egui::Color32::from_rgb(255, 100, 0)));
+                            }
+
+                            ui.separator();
+                            ui.label("Or enter the robot's address:");
                             ui.with_layout(egui::Layout::left_to_right(egui::Align::TOP), |ui| {
                                 ui.label("Address: ");
                                 ui.text_edit_singleline(address);
                                 ui.label("Port: ");
                                 ui.add(egui::widgets::DragValue::new(port).speed(1));
                             });
+                            ui.horizontal(|ui| {
+                                ui.label("Local address: ");
+                                ui.add(egui::TextEdit::singleline(local).hint_text("any"))
+                                    .on_hover_text("Address of the network interface to connect from");
+                            });
 
                             if connecting_task.is_some() {
                                 ui.label("Attempting connection...");
                             }
 
                             if ui.add(egui::Button::new("Connect")).clicked() {
-                                *connecting_task = Some(gui_framework::spawn(
-                                        connect_to_robot(address.clone(), *port)));
+                                *connecting_task = Some(spawn_connect(address.clone(), *port, local));
                             }
                         });
 
@@ -103,13 +174,7 @@ impl App for OperatorInterfaceApp {
                             });
                         }
 
-                        ui.label(format!("Left stick: {:?}", gamepad.left_stick));
-                        ui.label(format!("Right stick: {:?}", gamepad.right_stick));
-
-                        ui.label(format!("DPad Down: {}", gamepad.dpad_down));
-                        ui.label(format!("DPad Right: {}", gamepad.dpad_right));
-                        ui.label(format!("DPad Up: {}", gamepad.dpad_up));
-                        ui.label(format!("DPad Left: {}", gamepad.dpad_left));
+                        gamepad::draw_debug(ui, self.gamepads.selected(&gamepad));
 
                         let stream = if let Some(task) = connecting_task.take() {
                             if task.is_finished() {
@@ -121,8 +186,8 @@ impl App for OperatorInterfaceApp {
                         } else { break 'appstate };
 
                         match stream {
-                            Ok(Ok(s)) => {
-                                next_state = Some(AppState::OperatorInterface(oi::OperatorInterface::new(s)));
+                            Ok(Ok(client)) => {
+                                next_state = Some(AppState::OperatorInterface(Box::new(oi::OperatorInterface::new(client))));
                             },
                             Err(e) => {
                                 *error = Some(Banner::Error(eyre::Report::new(e)
@@ -134,7 +199,17 @@ impl App for OperatorInterfaceApp {
                         }
                     }
                     AppState::OperatorInterface(oi) => {
-                        oi.draw(ui, gamepad);
+                        if oi.draw(ui, &gamepad, &self.gamepads) {
+                            let client = oi.link.client();
+                            log::info!("Disconnected from {}", client.target());
+                            next_state = Some(AppState::Connecting {
+                                error: None,
+                                address: client.target().to_string(),
+                                port: client.remote_addr().map_or(9090, |addr| addr.port()),
+                                local: client.local().map(|local| local.to_string()).unwrap_or_default(),
+                                connecting_task: None,
+                            });
+                        }
                     }
                     AppState::FallbackError(e) => {
                         ui.label(format!("Uh oh! Fallback error: {}", e));
@@ -154,30 +229,39 @@ impl App for OperatorInterfaceApp {
     }
 }
 
-async fn connect_to_robot(addr: String, port: u16) -> Result<UdpSocket> {
-    let sockaddr = match addr.parse::<IpAddr>() {
-        Ok(a) => SocketAddr::new(a, port),
-        Err(_) => {
-            match tokio::net::lookup_host(addr.as_str()).await {
-                Ok(mut iter) => match iter.next() {
-                    Some(a) => a,
-                    None => eyre::bail!("Failed to find host '{}'", addr.as_str()),
-                }
-                Err(e) => {
-                    return Err(eyre::Report::new(e)
-                        .wrap_err("Host lookup failed"))
-                }
-            }
-        }
-    };
-
-    let conn: Result<UdpSocket> = timeout(std::time::Duration::from_secs(30),
-        async {
-            let sock = UdpSocket::bind("0.0.0.0:9090").await?;
-            sock.connect(sockaddr).await?;
-            Ok(sock)
-        }).await?;
-    Ok(conn?)
+/// Connects in the background, from the local address typed in `local` if there is one
+fn spawn_connect(addr: String, port: u16, local: &str) -> JoinHandle<Result<Client>> {
+    let local = local.trim().to_string();
+    gui_framework::spawn(async move {
+        let local = match local.as_str() {
+            "" => None,
+            local => Some(local.parse::<IpAddr>()
+                .wrap_err_with(|| format!("Invalid local address '{}'", local))?),
+        };
+        connect_to_robot(addr, port, local).await
+    })
+}
+
+/// Connects to the robot at `addr`, using `port` if the address doesn't include one
+async fn connect_to_robot(addr: String, port: u16, local: Option<IpAddr>) -> Result<Client> {
+    let target = robot_target(addr.trim(), port);
+
+    let client = timeout(std::time::Duration::from_secs(30),
+        Client::connect(&target, local, mappie_client::DEFAULT_REQUEST_TIMEOUT)).await?;
+    Ok(client?)
+}
+
+/// Adds `port` to `addr` unless it already has one
+fn robot_target(addr: &str, port: u16) -> String {
+    if addr.parse::<SocketAddr>().is_ok() {
+        return addr.to_string()
+    }
+    match addr.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        // a hostname, which only has a colon if it's followed by a port
+        Err(_) if addr.contains(':') => addr.to_string(),
+        Err(_) => format!("{}:{}", addr, port),
+    }
 }
 
 #[derive(Debug)]
@@ -200,3 +284,27 @@ impl Banner {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_the_port_to_bare_addresses() {
+        assert_eq!(robot_target("192.168.1.5", 9090), "192.168.1.5:9090");
+        assert_eq!(robot_target("fe80::1", 9090), "[fe80::1]:9090");
+        assert_eq!(robot_target("[fe80::1]", 9090), "[fe80::1]:9090");
+    }
+
+    #[test]
+    fn keeps_given_ports() {
+        assert_eq!(robot_target("192.168.1.5:1234", 9090), "192.168.1.5:1234");
+        assert_eq!(robot_target("[fe80::1]:1234", 9090), "[fe80::1]:1234");
+    }
+
+    #[test]
+    fn adds_the_port_to_hostnames() {
+        assert_eq!(robot_target("mappie.local", 9090), "mappie.local:9090");
+        assert_eq!(robot_target("mappie.local:1234", 9090), "mappie.local:1234");
+    }
+}
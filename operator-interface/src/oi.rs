@@ -1,56 +1,221 @@
-use crate::gui_framework::GamepadInput;
-use messages::Move;
-use std::io::ErrorKind;
-// use tokio::net::TcpStream;
-use tokio::net::UdpSocket;
+use crate::bindings::{self, ControlsPanel};
+use crate::gamepad::{self, GamepadInput, GamepadSelector};
+use crate::joystick::joystick;
+use std::time::Duration;
+
+use crate::link::{self, RequestEvent, RobotLink};
+use crate::params::ParamPanel;
+use mappie_client::{sender, Client};
+use messages::{Command, DriveStatus, Mode, Move};
+
+/// Width of the on-screen joysticks, in points
+const JOYSTICK_SIZE: f32 = 120.0;
+
+/// How often the connection health is redrawn when nothing else changes
+const HEALTH_REDRAW_PERIOD: Duration = Duration::from_millis(250);
 
 pub struct OperatorInterface {
-    pub connection: UdpSocket,
-    pub buffer: [u8; messages::Move::POSTCARD_COBS_BUFFER_MAX_SIZE],
-    pub skipped_msgs_count: u32,
+    pub link: RobotLink,
+    pub send_rate_hz: f32,
+    pub params: ParamPanel,
+    pub controls: ControlsPanel,
+    /// Whether the robot had stopped answering as of the last draw
+    link_lost: bool,
 }
 
 impl OperatorInterface {
-    pub fn new(connection: UdpSocket) -> Self {
+    pub fn new(client: Client) -> Self {
         Self {
-            connection,
-            buffer: [0; messages::Move::POSTCARD_COBS_BUFFER_MAX_SIZE],
-            skipped_msgs_count: 0,
+            link: RobotLink::new(client),
+            send_rate_hz: sender::DEFAULT_SEND_RATE_HZ,
+            params: ParamPanel::new(),
+            controls: ControlsPanel::new(),
+            link_lost: false,
+        }
+    }
+
+    /// Draws the interface, returning whether the operator asked to go back to the connect screen
+    pub fn draw(&mut self, ui: &mut egui::Ui, gamepads: &GamepadInput, selector: &GamepadSelector) -> bool {
+        for event in self.link.poll() {
+            match event {
+                RequestEvent::Answered(request, response) => self.params.handle_response(request, response),
+                RequestEvent::TimedOut(request) => self.params.handle_timeout(request),
+            }
+        }
+        // keeps the time since the last reply counting, and notices when the link drops
+        crate::gui_framework::request_redraw_after(HEALTH_REDRAW_PERIOD);
+
+        if self.link.lost() != self.link_lost {
+            self.link_lost = !self.link_lost;
+            if self.link_lost {
+                log::warn!("Lost the link to {}", self.link.client().target());
+            } else {
+                log::info!("Link to {} restored", self.link.client().target());
+            }
+        }
+        if self.link_lost {
+            return self.draw_link_lost(ui)
+        }
+        let mode = self.link.mode();
+
+        let gamepad = selector.selected(gamepads);
+        let controller_lost = gamepad.is_some_and(|gamepad| !gamepad.connected);
+        if let Some(lost) = gamepad.filter(|gamepad| !gamepad.connected) {
+            crate::Banner::Warning(eyre::eyre!(
+                "Controller {} disconnected, so the robot is being told to stop. Reconnect it or \
+                 choose another controller.", lost.name)).draw(ui);
+        }
+        let actions = self.controls.read(ui.ctx(), gamepad);
+        if actions.estop {
+            self.link.request_mode(Command::EStop);
+        } else if actions.toggle_enable {
+            self.link.toggle_enable();
+        }
+
+        ui.horizontal(|ui| {
+            let estopped = mode == Some(Mode::EStopped);
+            if ui.add_enabled(!estopped, egui::Button::new("Enable")).clicked() {
+                self.link.request_mode(Command::Enable);
+            }
+            if ui.add_enabled(!estopped, egui::Button::new("Disable")).clicked() {
+                self.link.request_mode(Command::Disable);
+            }
+            let estop = egui::Button::new(egui::RichText::new("E-STOP").strong().color(egui::Color32::WHITE))
+                .fill(egui::Color32::RED);
+            if ui.add(estop).clicked() {
+                self.link.request_mode(Command::EStop);
+            }
+            if ui.add_enabled(estopped, egui::Button::new("Clear e-stop")).clicked() {
+                self.link.request_mode(Command::ClearEStop);
+            }
+        });
+
+        // the on-screen joysticks take over from bindings while they're held, except while the
+        // selected controller is gone, when nothing may move the robot
+        let scale = if actions.slow { bindings::SLOW_SPEED_SCALE } else { 1.0 };
+        let (translate, rotate) = ui.add_enabled_ui(!controller_lost, |ui| {
+            ui.horizontal(|ui| {
+                let translate = joystick(ui, JOYSTICK_SIZE)
+                    .map_or(actions.translate, |(x, y)| (scale * x, scale * y));
+                let rotate = joystick(ui, JOYSTICK_SIZE)
+                    .map_or(actions.rotate, |(x, _)| scale * x);
+                (translate, rotate)
+            }).inner
+        }).inner;
+
+        self.link.set_move(if controller_lost {
+            Move::stop()
+        } else {
+            Move {
+                translate: [translate.0, translate.1].into(),
+                rotate,
+            }
+        });
+
+        match self.link.status() {
+            None => {
+                ui.label("Robot status: unknown");
+            }
+            Some(status) => {
+                let (text, color) = match status.mode {
+                    Mode::Disabled => ("Mode: disabled", egui::Color32::from_rgb(255, 100, 0)),
+                    Mode::Enabled => ("Mode: enabled", egui::Color32::GREEN),
+                    Mode::EStopped => ("Mode: E-STOPPED", egui::Color32::RED),
+                };
+                ui.label(egui::RichText::new(text).color(color).strong());
+
+                let (text, color) = match status.drive {
+                    DriveStatus::Ok => ("Drive: OK".to_string(), egui::Color32::GREEN),
+                    DriveStatus::Recovering { consecutive_errors } => (
+                        format!("Drive: recovering ({} failed updates)", consecutive_errors),
+                        egui::Color32::from_rgb(255, 100, 0),
+                    ),
+                    DriveStatus::SafeStopped => (
+                        "Drive: stopped after repeated faults, center the sticks to resume".to_string(),
+                        egui::Color32::RED,
+                    ),
+                };
+                ui.label(egui::RichText::new(text).color(color));
+                ui.label(format!("Drive errors: {}", status.drive_errors));
+                ui.label(format!("Link losses: {}", status.link_losses));
+                ui.label(format!("Superseded commands: {}", status.superseded_moves));
+                ui.label(format!("Motor I2C transactions per tick: {:.2}", status.motor_transactions_per_tick));
+            }
         }
+
+        let disconnect = ui.horizontal(|ui| {
+            draw_health(ui, &self.link);
+            ui.button("Disconnect").clicked()
+        }).inner;
+
+        ui.horizontal(|ui| {
+            ui.label("Command rate: ");
+            let rate = egui::DragValue::new(&mut self.send_rate_hz)
+                .clamp_range(sender::MIN_SEND_RATE_HZ..=sender::MAX_SEND_RATE_HZ)
+                .suffix(" Hz");
+            if ui.add(rate).changed() {
+                self.link.sender().set_rate(self.send_rate_hz);
+            }
+        });
+        let stats = self.link.sender().stats();
+        ui.label(format!("Commands sent: {} ({:.1}/s), skipped: {}, failed: {}",
+            stats.sent, stats.rate_hz, stats.skipped, stats.errors));
+        if let Some(e) = &stats.last_error {
+            ui.label(egui::RichText::new(format!("Last send error: {}", e)).color(egui::Color32::RED));
+        }
+        gamepad::draw_debug(ui, gamepad);
+
+        if actions.slow {
+            ui.label("Speed: slow");
+        }
+
+        ui.separator();
+        self.controls.draw(ui, gamepad);
+        self.params.draw(ui);
+        for request in self.params.take_requests() {
+            self.link.request(request);
+        }
+        disconnect
     }
 
-    pub fn draw(&mut self, ui: &mut egui::Ui, gamepad: GamepadInput) {
-        let msg = Move {
-            translate: [gamepad.left_stick.0, gamepad.left_stick.1].into(),
-            rotate: gamepad.right_stick.0,
-        };
-        let msg_bytes = match postcard::to_slice_cobs(&msg, &mut self.buffer[..]) {
-            Ok(slice) => slice,
-            Err(e) => {
-                panic!("Unexpected serialization error ({}) on message: {:?}", e, msg)
-            }
-        };
-
-        match self.connection.try_send(msg_bytes) {
-            Ok(_) => {}
-            Err(e) => match e.kind() {
-                ErrorKind::WouldBlock => {
-                    self.skipped_msgs_count += 1;
-                }
-                ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => {
-                    log::error!("Broken pipe! Fix me!");
-                }
-                _ => {}
-            }
-        }
-
-        ui.label(format!("Skipped messages: {}", self.skipped_msgs_count));
-        ui.label(format!("Left stick: {:?}", gamepad.left_stick));
-        ui.label(format!("Right stick: {:?}", gamepad.right_stick));
-
-        ui.label(format!("DPad Down: {}", gamepad.dpad_down));
-        ui.label(format!("DPad Right: {}", gamepad.dpad_right));
-        ui.label(format!("DPad Up: {}", gamepad.dpad_up));
-        ui.label(format!("DPad Left: {}", gamepad.dpad_left));
+    /// Shown in place of the controls while the robot isn't answering. The robot is told to stop,
+    /// and the controls come back on their own once it answers again.
+    fn draw_link_lost(&mut self, ui: &mut egui::Ui) -> bool {
+        self.link.set_move(Move::stop());
+
+        crate::Banner::Error(eyre::eyre!(
+            "Lost the link to {}: nothing received for {:.1} s. Waiting for the robot to answer again.",
+            self.link.client().target(), self.link.since_last_reply().as_secs_f32())).draw(ui);
+        ui.horizontal(|ui| draw_health(ui, &self.link));
+        ui.button("Back to connect screen").clicked()
     }
 }
+
+/// Round-trip time, jitter, packet loss and time since the robot last sent anything
+pub fn draw_health(ui: &mut egui::Ui, link: &RobotLink) {
+    let orange = egui::Color32::from_rgb(255, 100, 0);
+    let health = link.health();
+
+    let millis = |duration: Option<Duration>| duration
+        .map_or_else(|| "-".to_string(), |duration| format!("{:.1} ms", duration.as_secs_f64() * 1000.0));
+    ui.label(format!("RTT: {}", millis(health.rtt)));
+    ui.label(format!("Jitter: {}", millis(health.jitter)));
+
+    match health.loss() {
+        None => ui.label("Loss: -"),
+        Some(loss) => {
+            let color = if loss > 0.0 { orange } else { egui::Color32::GREEN };
+            ui.label(egui::RichText::new(format!("Loss: {:.0}%", loss * 100.0)).color(color))
+        }
+    };
+
+    let since = link.since_last_reply();
+    let color = if since >= link::LINK_LOST_AFTER {
+        egui::Color32::RED
+    } else if since >= link::SLOW_REPLY {
+        orange
+    } else {
+        egui::Color32::GREEN
+    };
+    ui.label(egui::RichText::new(format!("Last reply: {:.1} s ago", since.as_secs_f32())).color(color));
+}
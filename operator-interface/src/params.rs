@@ -0,0 +1,288 @@
+//! Panel for inspecting and tuning the robot's parameters, and for keeping named presets of them
+//! on the operator's machine.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use eyre::Result;
+use messages::{ParamInfo, ParamRange, ParamValue, Request, RequestError, Response};
+
+use crate::config;
+
+/// Named sets of parameter values, keyed by preset name and then parameter name
+type PresetMap = BTreeMap<String, BTreeMap<String, ParamValue>>;
+
+pub struct ParamPanel {
+    params: Vec<ParamInfo>,
+    /// Requests waiting to be sent to the robot
+    outgoing: Vec<Request>,
+    /// Result of the last operation, and whether it failed
+    message: Option<(String, bool)>,
+    presets: Presets,
+    preset_name: String,
+}
+
+impl ParamPanel {
+    pub fn new() -> Self {
+        let presets = Presets::load();
+        let message = presets.error.as_ref().map(|e| (e.clone(), true));
+        Self {
+            params: Vec::new(),
+            outgoing: vec![Request::ListParams { start: 0 }],
+            message,
+            presets,
+            preset_name: String::new(),
+        }
+    }
+
+    /// Requests the panel wants sent to the robot since the last call
+    pub fn take_requests(&mut self) -> Vec<Request> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn handle_response(&mut self, request: Request, response: Response) {
+        match (request, response) {
+            (Request::ListParams { start }, Response::ParamList { total, params }) => {
+                if start == 0 {
+                    self.params.clear();
+                }
+                // pages that arrive out of order are dropped, and the listing restarted
+                if self.params.len() != usize::from(start) {
+                    self.outgoing.push(Request::ListParams { start: 0 });
+                    return
+                }
+
+                self.params.extend(params);
+                let listed = self.params.len();
+                if listed < usize::from(total) && listed > usize::from(start) {
+                    self.outgoing.push(Request::ListParams { start: listed as u16 });
+                }
+            }
+            (Request::GetParam(name), Response::Param(value)) => {
+                if let Some(param) = self.params.iter_mut().find(|param| param.name == name) {
+                    param.value = value;
+                }
+            }
+            // the new value is shown as soon as it's sent, and a later change may already be on
+            // its way, so there's nothing to update
+            (Request::SetParam(..), Response::Param(_)) => {}
+            (Request::SaveParams, Response::Saved) => {
+                self.message = Some(("Saved parameters on the robot".to_string(), false));
+            }
+            (Request::SetParam(name, value), Response::Error(e)) => {
+                let message = format!("Unable to set {} to {:?}: {}", name, value, describe(e));
+                self.message = Some((message, true));
+                // show the value the robot actually has
+                self.outgoing.push(Request::GetParam(name));
+            }
+            (request, Response::Error(e)) => {
+                self.message = Some((format!("{:?} failed: {}", request, describe(e)), true));
+            }
+            (request, response) => {
+                log::warn!("Unexpected response {:?} to request {:?}", response, request);
+            }
+        }
+    }
+
+    /// Handles a request the robot never answered
+    pub fn handle_timeout(&mut self, request: Request) {
+        match request {
+            // keep trying until the robot is reachable
+            Request::ListParams { .. } => self.outgoing.push(Request::ListParams { start: 0 }),
+            Request::SetParam(name, _) => {
+                self.message = Some((format!("Robot didn't respond to setting {}", name), true));
+                self.outgoing.push(Request::GetParam(name));
+            }
+            request => {
+                self.message = Some((format!("Robot didn't respond to {:?}", request), true));
+            }
+        }
+    }
+
+    pub fn draw(&mut self, ui: &mut egui::Ui) {
+        egui::CollapsingHeader::new("Parameters").show(ui, |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Refresh").clicked() {
+                    self.outgoing.push(Request::ListParams { start: 0 });
+                }
+                if ui.button("Save on robot").clicked() {
+                    self.outgoing.push(Request::SaveParams);
+                }
+            });
+            if let Some((message, is_error)) = &self.message {
+                let color = if *is_error { egui::Color32::RED } else { egui::Color32::GREEN };
+                ui.label(egui::RichText::new(message).color(color));
+            }
+
+            egui::Grid::new("params").striped(true).show(ui, |ui| {
+                for param in &mut self.params {
+                    if let Some(value) = draw_param(ui, param) {
+                        param.value = value;
+                        self.outgoing.push(Request::SetParam(param.name.clone(), value));
+                    }
+                    ui.end_row();
+                }
+            });
+
+            ui.separator();
+            self.draw_presets(ui);
+        });
+    }
+
+    fn draw_presets(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Preset: ");
+            ui.text_edit_singleline(&mut self.preset_name);
+            let can_save = !self.preset_name.is_empty() && !self.params.is_empty();
+            if ui.add_enabled(can_save, egui::Button::new("Save preset")).clicked() {
+                let values = self.params.iter()
+                    .map(|param| (param.name.to_string(), param.value))
+                    .collect();
+                self.presets.presets.insert(self.preset_name.clone(), values);
+                self.save_presets(format!("Saved preset {}", self.preset_name));
+            }
+        });
+
+        let mut load = None;
+        let mut delete = None;
+        for name in self.presets.presets.keys() {
+            ui.horizontal(|ui| {
+                ui.label(name);
+                if ui.button("Load").clicked() {
+                    load = Some(name.clone());
+                }
+                if ui.button("Delete").clicked() {
+                    delete = Some(name.clone());
+                }
+            });
+        }
+
+        if let Some(name) = load {
+            self.load_preset(&name);
+        }
+        if let Some(name) = delete {
+            self.presets.presets.remove(&name);
+            self.save_presets(format!("Deleted preset {}", name));
+        }
+    }
+
+    /// Sends every value in the preset that differs from the robot's, skipping parameters the
+    /// robot doesn't have
+    fn load_preset(&mut self, name: &str) {
+        let Some(values) = self.presets.presets.get(name) else { return };
+        let mut skipped = 0;
+        for (param_name, &value) in values {
+            match self.params.iter_mut().find(|param| param.name.as_str() == param_name) {
+                Some(param) => {
+                    if param.value != value {
+                        param.value = value;
+                        self.outgoing.push(Request::SetParam(param.name.clone(), value));
+                    }
+                }
+                None => skipped += 1,
+            }
+        }
+
+        let message = if skipped == 0 {
+            format!("Loaded preset {}", name)
+        } else {
+            format!("Loaded preset {}, skipping {} parameters the robot doesn't have", name, skipped)
+        };
+        self.message = Some((message, false));
+    }
+
+    fn save_presets(&mut self, success: String) {
+        self.message = Some(match self.presets.save() {
+            Ok(()) => (success, false),
+            Err(e) => (format!("{:#}", e), true),
+        });
+    }
+}
+
+/// Draws a row for a single parameter, returning its new value if the operator changed it
+fn draw_param(ui: &mut egui::Ui, param: &ParamInfo) -> Option<ParamValue> {
+    let modified = param.value != param.default;
+    let name = egui::RichText::new(param.name.as_str());
+    ui.label(if modified { name.strong().color(egui::Color32::YELLOW) } else { name });
+
+    let mut value = param.value;
+    let range = param.range.map(|ParamRange { min, max }| (min, max));
+    let changed = match (&mut value, range) {
+        (ParamValue::Bool(b), _) => ui.checkbox(b, "").changed(),
+        (ParamValue::Int(i), Some((ParamValue::Int(min), ParamValue::Int(max)))) => {
+            ui.add(egui::Slider::new(i, min..=max)).changed()
+        }
+        (ParamValue::Int(i), _) => ui.add(egui::DragValue::new(i)).changed(),
+        (ParamValue::Float(f), Some((ParamValue::Float(min), ParamValue::Float(max)))) => {
+            ui.add(egui::Slider::new(f, min..=max)).changed()
+        }
+        (ParamValue::Float(f), _) => ui.add(egui::DragValue::new(f).speed(0.01)).changed(),
+        (ParamValue::Choice(i), _) => {
+            let selected = param.choices.get(usize::from(*i)).map_or("?", |choice| choice.as_str());
+            let mut changed = false;
+            egui::ComboBox::from_id_source(param.name.as_str())
+                .selected_text(selected)
+                .show_ui(ui, |ui| {
+                    for (j, choice) in param.choices.iter().enumerate() {
+                        changed |= ui.selectable_value(i, j as u8, choice.as_str()).changed();
+                    }
+                });
+            changed
+        }
+    };
+
+    let reset = ui.add_enabled(modified, egui::Button::new("Reset"))
+        .on_hover_text(format!("Default: {}", display(param, param.default)))
+        .clicked();
+    if reset {
+        Some(param.default)
+    } else if changed {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn display(param: &ParamInfo, value: ParamValue) -> String {
+    match value {
+        ParamValue::Bool(b) => b.to_string(),
+        ParamValue::Int(i) => i.to_string(),
+        ParamValue::Float(f) => f.to_string(),
+        ParamValue::Choice(i) => param.choices.get(usize::from(i))
+            .map_or_else(|| i.to_string(), |choice| choice.to_string()),
+    }
+}
+
+fn describe(e: RequestError) -> &'static str {
+    match e {
+        RequestError::UnknownParam => "the robot doesn't have that parameter",
+        RequestError::InvalidValue => "the value has the wrong type or is out of range",
+        RequestError::SaveFailed => "the robot couldn't write its config file",
+    }
+}
+
+/// Presets stored as JSON in the operator's config directory
+struct Presets {
+    path: PathBuf,
+    presets: PresetMap,
+    /// Why the presets couldn't be loaded
+    error: Option<String>,
+}
+
+impl Presets {
+    fn load() -> Self {
+        let path = config::path("presets.json");
+        let (presets, error) = match config::read(&path, "parameter presets") {
+            Ok(presets) => (presets, None),
+            Err(e) => {
+                log::warn!("{:#}", e);
+                (PresetMap::new(), Some(format!("{:#}", e)))
+            }
+        };
+        Self { path, presets, error }
+    }
+
+    fn save(&self) -> Result<()> {
+        config::write(&self.path, &self.presets, "parameter presets")
+    }
+}
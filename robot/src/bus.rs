@@ -0,0 +1,254 @@
+//! Sharing of a single I2C bus between multiple device drivers.
+//!
+//! Each driver gets its own [`BusDevice`] handle implementing [`embedded_hal::i2c::I2c`], so
+//! drivers written against `embedded-hal` work unmodified. Handles record per-device statistics
+//! and account for the bus time they use in the current main loop tick. Once the tick's budget is
+//! spent, transactions from best effort devices are deferred until the next tick so that sensors
+//! can't delay critical outputs like the motors.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use embedded_hal::i2c::{self, I2c, Operation, SevenBitAddress};
+
+/// How a device's transactions are scheduled against the tick budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Always runs, even when the budget is exhausted. Critical transactions still count against
+    /// the budget.
+    Critical,
+    /// Deferred to the next tick when the budget is exhausted
+    BestEffort,
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceStats {
+    pub name: &'static str,
+    pub priority: Priority,
+    /// Number of transactions attempted on the bus
+    pub transactions: u64,
+    /// Number of attempted transactions that failed
+    pub errors: u64,
+    /// Number of transactions refused because the tick budget was exhausted
+    pub deferred: u64,
+    /// Total time spent in this device's transactions
+    pub bus_time: Duration,
+}
+
+#[derive(Debug)]
+pub enum BusError<E> {
+    /// The underlying bus reported an error
+    Bus(E),
+    /// The tick's bus budget was exhausted before the transaction could run
+    Deferred,
+}
+
+impl<E: fmt::Display> fmt::Display for BusError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusError::Bus(e) => write!(f, "{}", e),
+            BusError::Deferred => write!(f, "Transaction deferred, I2C bus budget exhausted"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for BusError<E> {}
+
+impl<E: i2c::Error> i2c::Error for BusError<E> {
+    fn kind(&self) -> i2c::ErrorKind {
+        match self {
+            BusError::Bus(e) => e.kind(),
+            BusError::Deferred => i2c::ErrorKind::Other,
+        }
+    }
+}
+
+struct BusState<I> {
+    bus: I,
+    devices: Vec<DeviceStats>,
+    tick_budget: Duration,
+    tick_used: Duration,
+}
+
+/// Owner of an I2C bus that hands out [`BusDevice`] handles to drivers
+pub struct SharedBus<I> {
+    state: Arc<Mutex<BusState<I>>>,
+}
+
+impl<I: I2c> SharedBus<I> {
+    /// `tick_budget` is the amount of bus time available to devices between calls to
+    /// [`SharedBus::begin_tick`]
+    pub fn new(bus: I, tick_budget: Duration) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(BusState {
+                bus,
+                devices: Vec::new(),
+                tick_budget,
+                tick_used: Duration::ZERO,
+            })),
+        }
+    }
+
+    /// Registers a new device on the bus, returning its handle
+    pub fn device(&self, name: &'static str, priority: Priority) -> BusDevice<I> {
+        let mut state = lock(&self.state);
+        state.devices.push(DeviceStats {
+            name,
+            priority,
+            transactions: 0,
+            errors: 0,
+            deferred: 0,
+            bus_time: Duration::ZERO,
+        });
+
+        BusDevice {
+            state: Arc::clone(&self.state),
+            id: state.devices.len() - 1,
+        }
+    }
+
+    /// Resets the bus time used, marking the start of a new main loop iteration
+    pub fn begin_tick(&self) {
+        lock(&self.state).tick_used = Duration::ZERO;
+    }
+
+    pub fn device_stats(&self) -> Vec<DeviceStats> {
+        lock(&self.state).devices.clone()
+    }
+}
+
+/// A single driver's handle to a [`SharedBus`]
+pub struct BusDevice<I> {
+    state: Arc<Mutex<BusState<I>>>,
+    id: usize,
+}
+
+impl<I> BusDevice<I> {
+    /// Whether a best effort transaction would run right now. Drivers can use this to skip
+    /// polling instead of handling [`BusError::Deferred`].
+    pub fn within_budget(&self) -> bool {
+        let state = lock(&self.state);
+        state.tick_used < state.tick_budget
+    }
+}
+
+impl<I: I2c> i2c::ErrorType for BusDevice<I> {
+    type Error = BusError<I::Error>;
+}
+
+impl<I: I2c> I2c for BusDevice<I> {
+    fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let mut state = lock(&self.state);
+        let state = &mut *state;
+
+        let device = &mut state.devices[self.id];
+        if device.priority == Priority::BestEffort && state.tick_used >= state.tick_budget {
+            device.deferred += 1;
+            return Err(BusError::Deferred)
+        }
+
+        let start = Instant::now();
+        let res = state.bus.transaction(address, operations);
+        let elapsed = start.elapsed();
+
+        state.tick_used += elapsed;
+        device.bus_time += elapsed;
+        device.transactions += 1;
+        if res.is_err() {
+            device.errors += 1;
+        }
+        res.map_err(BusError::Bus)
+    }
+}
+
+/// Locks the bus state, ignoring poisoning. The state is only held during single transactions and
+/// stays consistent even if a driver panics while holding it.
+fn lock<I>(state: &Mutex<BusState<I>>) -> MutexGuard<'_, BusState<I>> {
+    state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_i2c::FakeI2c;
+
+    const ADDRESS: SevenBitAddress = 0x40;
+
+    fn stats(bus: &SharedBus<FakeI2c>, name: &str) -> DeviceStats {
+        bus.device_stats().into_iter().find(|device| device.name == name).unwrap()
+    }
+
+    #[test]
+    fn best_effort_is_deferred_once_budget_is_spent() {
+        let i2c = FakeI2c::default();
+        i2c.set_latency(Duration::from_millis(2));
+        let bus = SharedBus::new(i2c.clone(), Duration::from_millis(1));
+        let mut sensor = bus.device("sensor", Priority::BestEffort);
+
+        assert!(sensor.within_budget());
+        sensor.write(ADDRESS, &[1]).unwrap();
+        assert!(!sensor.within_budget());
+        assert!(matches!(sensor.write(ADDRESS, &[2]), Err(BusError::Deferred)));
+
+        assert_eq!(i2c.writes(), vec![(ADDRESS, vec![1])]);
+        let sensor_stats = stats(&bus, "sensor");
+        assert_eq!((sensor_stats.transactions, sensor_stats.deferred), (1, 1));
+        assert!(sensor_stats.bus_time >= Duration::from_millis(2));
+    }
+
+    #[test]
+    fn critical_always_runs() {
+        let i2c = FakeI2c::default();
+        let bus = SharedBus::new(i2c.clone(), Duration::ZERO);
+        let mut motors = bus.device("motors", Priority::Critical);
+        let mut sensor = bus.device("sensor", Priority::BestEffort);
+
+        assert!(matches!(sensor.write(ADDRESS, &[1]), Err(BusError::Deferred)));
+        motors.write(ADDRESS, &[2]).unwrap();
+        motors.write(ADDRESS, &[3]).unwrap();
+
+        assert_eq!(i2c.writes(), vec![(ADDRESS, vec![2]), (ADDRESS, vec![3])]);
+        let motor_stats = stats(&bus, "motors");
+        assert_eq!((motor_stats.transactions, motor_stats.deferred), (2, 0));
+    }
+
+    #[test]
+    fn begin_tick_resets_the_budget() {
+        let i2c = FakeI2c::default();
+        i2c.set_latency(Duration::from_millis(2));
+        let bus = SharedBus::new(i2c.clone(), Duration::from_millis(1));
+        let mut motors = bus.device("motors", Priority::Critical);
+        let mut sensor = bus.device("sensor", Priority::BestEffort);
+
+        // critical transactions spend the budget too
+        motors.write(ADDRESS, &[1]).unwrap();
+        assert!(matches!(sensor.write(ADDRESS, &[2]), Err(BusError::Deferred)));
+
+        bus.begin_tick();
+        sensor.write(ADDRESS, &[3]).unwrap();
+        assert_eq!(i2c.writes(), vec![(ADDRESS, vec![1]), (ADDRESS, vec![3])]);
+    }
+
+    #[test]
+    fn counts_errors_per_device() {
+        let i2c = FakeI2c::default();
+        let bus = SharedBus::new(i2c.clone(), Duration::from_millis(1));
+        let mut motors = bus.device("motors", Priority::Critical);
+        let mut sensor = bus.device("sensor", Priority::BestEffort);
+
+        i2c.fail_next(1);
+        assert!(matches!(sensor.write(ADDRESS, &[1]), Err(BusError::Bus(_))));
+        sensor.write(ADDRESS, &[2]).unwrap();
+        motors.write(ADDRESS, &[3]).unwrap();
+
+        let sensor_stats = stats(&bus, "sensor");
+        assert_eq!((sensor_stats.transactions, sensor_stats.errors), (2, 1));
+        let motor_stats = stats(&bus, "motors");
+        assert_eq!((motor_stats.transactions, motor_stats.errors), (1, 0));
+    }
+}
@@ -0,0 +1,104 @@
+//! Announces the robot on the local network, so operators can find it without knowing its
+//! address.
+//!
+//! A [`Beacon`] is sent to the beacon address every period. By default that's the broadcast
+//! address, but any address works, e.g. a loopback one when the operator interface runs on the
+//! same machine.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use eyre::{eyre, Result, WrapErr};
+use futures_lite::StreamExt;
+use messages::{Beacon, Transport};
+use smol::Async;
+
+/// Builds the beacon for a robot accepting control messages on `port`, over `transport`
+pub fn beacon(name: &str, port: u16, transport: Transport) -> Result<Beacon> {
+    let mut beacon = Beacon {
+        name: Default::default(),
+        version: Default::default(),
+        port,
+        transport,
+    };
+    beacon.name.push_str(name).map_err(|()| {
+        eyre!("Robot name {} is longer than {} bytes", name, messages::ROBOT_NAME_MAX_LEN)
+    })?;
+    // a truncated version is still useful to operators
+    for c in env!("CARGO_PKG_VERSION").chars() {
+        if beacon.version.push(c).is_err() {
+            break
+        }
+    }
+    Ok(beacon)
+}
+
+/// Sends `beacon` to `target` every `period`, until an unrecoverable error
+pub async fn announce(beacon: Beacon, target: SocketAddr, period: Duration) -> Result<()> {
+    let bind_addr = match target {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = Async::<UdpSocket>::bind(bind_addr)
+        .wrap_err("Failed to create beacon socket")?;
+    socket.get_ref().set_broadcast(true)
+        .wrap_err("Failed to allow broadcasts on beacon socket")?;
+
+    let mut encoder = framing::Encoder::<Beacon>::new();
+    let frame = encoder.encode(&beacon)
+        .wrap_err("Failed to encode beacon")?
+        .to_vec();
+
+    log::info!("Announcing robot {} to {} every {:?}", beacon.name, target, period);
+    let mut timer = smol::Timer::interval(period);
+    let mut failing = false;
+    loop {
+        match socket.send_to(&frame, target).await {
+            Ok(_) => {
+                if failing {
+                    log::info!("Resumed announcing robot to {}", target);
+                }
+                failing = false;
+            }
+            // the network may not be up yet, so keep trying
+            Err(e) => {
+                if !failing {
+                    log::warn!("Failed to announce robot to {}: {}", target, e);
+                }
+                failing = true;
+            }
+        }
+        timer.next().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announces_to_loopback() {
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let target = receiver.local_addr().unwrap();
+        let sent = beacon("test-robot", 9090, Transport::Tcp).unwrap();
+
+        // the announcer runs until the test process exits
+        let announced = sent.clone();
+        std::thread::spawn(move || smol::block_on(announce(announced, target, Duration::from_millis(10))));
+
+        let mut buf = [0u8; framing::max_frame_len::<Beacon>()];
+        for _ in 0..2 {
+            let (len, from) = receiver.recv_from(&mut buf).unwrap();
+            assert!(from.ip().is_loopback());
+            let received: Beacon = framing::decode(&mut buf[..len]).unwrap();
+            assert_eq!(received, sent);
+        }
+    }
+
+    #[test]
+    fn rejects_long_names() {
+        let name = "r".repeat(messages::ROBOT_NAME_MAX_LEN + 1);
+        assert!(beacon(&name, 9090, Transport::Udp).is_err());
+    }
+}
@@ -1,39 +1,115 @@
-use crate::Pwm;
+use std::fmt;
 
-use adafruit_motorkit::dc::DcMotor;
-use eyre::{Result, WrapErr};
-use messages::Move;
+use crate::motor_hat::{Motor, MotorHat};
+use crate::params::{Param, Params};
+
+use embedded_hal::i2c::I2c;
+use messages::{DriveStatus, Move};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DriveFault<E> {
+    #[error("Failed to write motor throttles")]
+    Write(#[source] E),
+    #[error("Failed to reinitialize the motor hat after a write failure")]
+    Reinitialize(#[source] E),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DriveState {
+    Running,
+    /// The last `consecutive_errors` ticks failed to write throttles
+    Recovering { consecutive_errors: u32 },
+    /// The error budget was exhausted. Motors stay stopped until the operator centers the controls.
+    SafeStopped,
+}
 
 pub struct Drive {
-    args: crate::Args,
-    fl_drive_motor: DcMotor,
-    fr_drive_motor: DcMotor,
-    bl_drive_motor: DcMotor,
-    br_drive_motor: DcMotor,
+    /// The value below which movement commands are ignored
+    deadzone: Param<f32>,
+    /// Number of consecutive failed updates before the motors are stopped
+    error_budget: Param<u32>,
+    state: DriveState,
+    total_errors: u32,
 }
 
 impl Drive {
-    pub fn new(args: crate::Args, pwm: &mut Pwm) -> Result<Self> {
-        let fl_drive_motor = DcMotor::try_new(pwm, ports::FL_DRIVE_MOTOR)
-            .wrap_err("unable to construct front left motor")?;
-        let fr_drive_motor = DcMotor::try_new(pwm, ports::FR_DRIVE_MOTOR)
-            .wrap_err("unable to construct front right motor")?;
-        let bl_drive_motor = DcMotor::try_new(pwm, ports::BL_DRIVE_MOTOR)
-            .wrap_err("unable to construct back left motor")?;
-        let br_drive_motor = DcMotor::try_new(pwm, ports::BR_DRIVE_MOTOR)
-            .wrap_err("unable to construct back right motor")?;
-
-        Ok(Self {
-            args,
-            fl_drive_motor,
-            fr_drive_motor,
-            bl_drive_motor,
-            br_drive_motor,
-        })
+    pub fn new(args: &crate::Args, params: &Params) -> Self {
+        Self {
+            deadzone: params.register("drive.deadzone", args.deadzone, Some(0.0..=1.0)),
+            error_budget: params.register("drive.error_budget", args.drive_error_budget, Some(1..=1000)),
+            state: DriveState::Running,
+            total_errors: 0,
+        }
     }
 
-    pub fn main_loop(&mut self, pwm: &mut Pwm, control: Move) {
+    /// Writes the throttles for `control`. On a write failure, the motor hat is reinitialized and
+    /// the write retried. If that keeps failing for `drive.error_budget` ticks in a row, the motors
+    /// are stopped and commands are ignored until a neutral one is received.
+    pub async fn main_loop<I>(&mut self, pwm: &mut MotorHat<I>, control: Move) -> Result<(), DriveFault<I::Error>>
+    where
+        I: I2c,
+        I::Error: fmt::Display,
+    {
+        let control = self.apply_deadzone(control);
+
+        if self.state == DriveState::SafeStopped {
+            self.stage(pwm, Move::stop());
+            self.write(pwm).await?;
+            if control == Move::stop() {
+                log::info!("Controls centered after safe stop, resuming drive");
+                self.state = DriveState::Running;
+            }
+            return Ok(())
+        }
+
+        self.stage(pwm, control);
+        match self.write(pwm).await {
+            Ok(()) => {
+                if let DriveState::Recovering { consecutive_errors } = self.state {
+                    log::info!("Drive recovered after {} failed updates", consecutive_errors);
+                }
+                self.state = DriveState::Running;
+                Ok(())
+            }
+            Err(fault) => {
+                self.total_errors = self.total_errors.saturating_add(1);
+                let consecutive_errors = match self.state {
+                    DriveState::Recovering { consecutive_errors } => consecutive_errors + 1,
+                    _ => 1,
+                };
+
+                if consecutive_errors >= self.error_budget.get() {
+                    log::error!("Drive failed {} updates in a row, stopping motors", consecutive_errors);
+                    self.state = DriveState::SafeStopped;
+                    let _ = pwm.stop();
+                } else {
+                    self.state = DriveState::Recovering { consecutive_errors };
+                }
+                Err(fault)
+            }
+        }
+    }
+
+    pub fn status(&self) -> DriveStatus {
+        match self.state {
+            DriveState::Running => DriveStatus::Ok,
+            DriveState::Recovering { consecutive_errors } => DriveStatus::Recovering { consecutive_errors },
+            DriveState::SafeStopped => DriveStatus::SafeStopped,
+        }
+    }
+
+    pub fn total_errors(&self) -> u32 {
+        self.total_errors
+    }
+
+    pub fn reset<I: I2c>(&mut self, pwm: &mut MotorHat<I>) {
+        let _ = pwm.stop();
+    }
+
+    fn apply_deadzone(&self, control: Move) -> Move {
         let Move { translate, rotate } = control;
+        let zone = self.deadzone.get();
 
         fn deadzone(val: f32, zone: f32) -> f32 {
             let zone = zone.abs();
@@ -43,34 +119,151 @@ impl Drive {
             val
         }
 
-        let translate = mint::Vector2 {
-            x: deadzone(translate.x, self.args.deadzone),
-            y: deadzone(translate.y, self.args.deadzone),
-        };
-        let rotate = deadzone(rotate, self.args.deadzone);
+        Move {
+            translate: mint::Vector2 {
+                x: deadzone(translate.x, zone),
+                y: deadzone(translate.y, zone),
+            },
+            rotate: deadzone(rotate, zone),
+        }
+    }
+
+    fn stage<I: I2c>(&self, pwm: &mut MotorHat<I>, control: Move) {
+        let Move { translate, rotate } = control;
 
         let fl_speed = (translate.y + translate.x + rotate).clamp(-1.0, 1.0);
         let fr_speed = (translate.y - translate.x - rotate).clamp(-1.0, 1.0);
         let bl_speed = (translate.y - translate.x + rotate).clamp(-1.0, 1.0);
         let br_speed = (translate.y + translate.x - rotate).clamp(-1.0, 1.0);
 
-        self.fl_drive_motor.set_throttle(pwm, -fl_speed).expect("FL drive throttle failed");
-        self.fr_drive_motor.set_throttle(pwm, fr_speed).expect("FR drive throttle failed");
-        self.bl_drive_motor.set_throttle(pwm, -bl_speed).expect("BL drive throttle failed");
-        self.br_drive_motor.set_throttle(pwm, br_speed).expect("BR drive throttle failed");
+        pwm.set_throttle(ports::FL_DRIVE_MOTOR, -fl_speed);
+        pwm.set_throttle(ports::FR_DRIVE_MOTOR, fr_speed);
+        pwm.set_throttle(ports::BL_DRIVE_MOTOR, -bl_speed);
+        pwm.set_throttle(ports::BR_DRIVE_MOTOR, br_speed);
     }
 
-    pub fn reset(&mut self, pwm: &mut Pwm) {
-        let _ = self.fl_drive_motor.set_throttle(pwm, 0.0);
-        let _ = self.fr_drive_motor.set_throttle(pwm, 0.0);
-        let _ = self.bl_drive_motor.set_throttle(pwm, 0.0);
-        let _ = self.br_drive_motor.set_throttle(pwm, 0.0);
+    /// Flushes staged throttles, reinitializing the motor hat and retrying once on failure
+    async fn write<I>(&self, pwm: &mut MotorHat<I>) -> Result<(), DriveFault<I::Error>>
+    where
+        I: I2c,
+        I::Error: fmt::Display,
+    {
+        let Err(e) = pwm.flush() else { return Ok(()) };
+        log::warn!("Motor throttle write failed, reinitializing motor hat: {}", e);
+
+        pwm.initialize().await.map_err(DriveFault::Reinitialize)?;
+        pwm.flush().map_err(DriveFault::Write)?;
+        Ok(())
     }
 }
 
 mod ports {
-    pub const BL_DRIVE_MOTOR: adafruit_motorkit::Motor = adafruit_motorkit::Motor::Motor1;
-    pub const BR_DRIVE_MOTOR: adafruit_motorkit::Motor = adafruit_motorkit::Motor::Motor2;
-    pub const FL_DRIVE_MOTOR: adafruit_motorkit::Motor = adafruit_motorkit::Motor::Motor3;
-    pub const FR_DRIVE_MOTOR: adafruit_motorkit::Motor = adafruit_motorkit::Motor::Motor4;
+    use super::Motor;
+
+    pub const BL_DRIVE_MOTOR: Motor = Motor::Motor1;
+    pub const BR_DRIVE_MOTOR: Motor = Motor::Motor2;
+    pub const FL_DRIVE_MOTOR: Motor = Motor::Motor3;
+    pub const FR_DRIVE_MOTOR: Motor = Motor::Motor4;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_i2c::FakeI2c;
+    use crate::motor_hat::DEFAULT_PRESCALE;
+    use clap::Parser;
+    use futures_lite::future::block_on;
+    use std::path::PathBuf;
+
+    const ALL_OFF: [u8; 5] = [0xFA, 0x00, 0x00, 0x00, 0x10];
+
+    fn forward() -> Move {
+        Move {
+            translate: mint::Vector2 { x: 0.0, y: 0.5 },
+            rotate: 0.0,
+        }
+    }
+
+    /// A drive stopping after three failed updates, and its motor hat on a fake bus
+    fn drive() -> (Drive, MotorHat<FakeI2c>, FakeI2c) {
+        let args = crate::Args::parse_from(["robot", "--drive-error-budget", "3"]);
+        let params = Params::load(PathBuf::from("/nonexistent/robot.toml")).unwrap();
+        let i2c = FakeI2c::default();
+        let pwm = block_on(MotorHat::new(i2c.clone(), 0x60, DEFAULT_PRESCALE)).unwrap();
+        i2c.take_writes();
+        (Drive::new(&args, &params), pwm, i2c)
+    }
+
+    /// Whether every motor's registers already hold a brake
+    fn braked(pwm: &mut MotorHat<FakeI2c>) -> bool {
+        for motor in [Motor::Motor1, Motor::Motor2, Motor::Motor3, Motor::Motor4] {
+            pwm.set_throttle(motor, 0.0);
+        }
+        pwm.flush().unwrap() == 0
+    }
+
+    #[test]
+    fn reinitializes_and_retries_a_failed_write() {
+        let (mut drive, mut pwm, i2c) = drive();
+        i2c.fail_next(1);
+
+        block_on(drive.main_loop(&mut pwm, forward())).unwrap();
+        assert_eq!(drive.status(), DriveStatus::Ok);
+        assert_eq!(drive.total_errors(), 0);
+
+        let writes = i2c.writes();
+        assert_eq!(writes[0], (0x60, ALL_OFF.to_vec()));
+        // the throttles were written after reinitializing
+        assert!(writes.len() > 6);
+        assert!(!braked(&mut pwm));
+    }
+
+    #[test]
+    fn recovers_when_writes_succeed_again() {
+        let (mut drive, mut pwm, i2c) = drive();
+        i2c.fail_next(u32::MAX);
+        assert!(matches!(block_on(drive.main_loop(&mut pwm, forward())), Err(DriveFault::Reinitialize(_))));
+        assert!(block_on(drive.main_loop(&mut pwm, forward())).is_err());
+        assert_eq!(drive.status(), DriveStatus::Recovering { consecutive_errors: 2 });
+
+        i2c.fail_next(0);
+        block_on(drive.main_loop(&mut pwm, forward())).unwrap();
+        assert_eq!(drive.status(), DriveStatus::Ok);
+        assert_eq!(drive.total_errors(), 2);
+    }
+
+    #[test]
+    fn stops_once_error_budget_is_spent() {
+        let (mut drive, mut pwm, i2c) = drive();
+        i2c.fail_next(u32::MAX);
+        for consecutive_errors in 1..3 {
+            assert!(block_on(drive.main_loop(&mut pwm, forward())).is_err());
+            assert_eq!(drive.status(), DriveStatus::Recovering { consecutive_errors });
+        }
+
+        assert!(block_on(drive.main_loop(&mut pwm, forward())).is_err());
+        assert_eq!(drive.status(), DriveStatus::SafeStopped);
+        assert_eq!(drive.total_errors(), 3);
+    }
+
+    #[test]
+    fn resumes_only_once_controls_are_centered() {
+        let (mut drive, mut pwm, i2c) = drive();
+        i2c.fail_next(u32::MAX);
+        for _ in 0..3 {
+            let _ = block_on(drive.main_loop(&mut pwm, forward()));
+        }
+        i2c.fail_next(0);
+
+        // the bus works again, but the operator is still asking to move
+        block_on(drive.main_loop(&mut pwm, forward())).unwrap();
+        assert_eq!(drive.status(), DriveStatus::SafeStopped);
+        assert!(braked(&mut pwm));
+
+        block_on(drive.main_loop(&mut pwm, Move::stop())).unwrap();
+        assert_eq!(drive.status(), DriveStatus::Ok);
+
+        block_on(drive.main_loop(&mut pwm, forward())).unwrap();
+        assert!(!braked(&mut pwm));
+    }
 }
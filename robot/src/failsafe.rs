@@ -0,0 +1,220 @@
+//! Staged handling of late or missing operator commands.
+//!
+//! A single late packet shouldn't make the robot stutter, but a dead link must still bring it to a
+//! stop. After the last command is received, the failsafe:
+//! 1. holds that command for a grace period
+//! 2. ramps it down to a stop over the deceleration time
+//! 3. declares the link lost and keeps the robot stopped until a new command arrives
+//!
+//! Time is always passed in by the caller, so the staging doesn't depend on the main loop period.
+
+use std::time::{Duration, Instant};
+
+use messages::Move;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LinkState {
+    /// No command has been received yet
+    Waiting,
+    /// The last command is recent enough to be followed as-is
+    Active,
+    /// Commands stopped arriving and the last one is being ramped down
+    Decelerating,
+    /// Commands stopped arriving long enough ago that the robot has stopped
+    Lost,
+}
+
+pub struct CommandFailsafe {
+    grace: Duration,
+    decel: Duration,
+    last_command: Option<(Instant, Move)>,
+    state: LinkState,
+    link_losses: u32,
+}
+
+impl CommandFailsafe {
+    pub fn new(grace: Duration, decel: Duration) -> Self {
+        Self {
+            grace,
+            decel,
+            last_command: None,
+            state: LinkState::Waiting,
+            link_losses: 0,
+        }
+    }
+
+    /// Changes how long the last command is held before decelerating
+    pub fn set_grace(&mut self, grace: Duration) {
+        self.grace = grace;
+    }
+
+    /// Changes how long the robot takes to ramp down to a stop
+    pub fn set_decel(&mut self, decel: Duration) {
+        self.decel = decel;
+    }
+
+    /// Records a command received from the operator at `now`
+    pub fn command(&mut self, now: Instant, command: Move) {
+        self.last_command = Some((now, command));
+    }
+
+    /// Forgets the last command, so it isn't followed again after the robot is re-enabled
+    pub fn clear(&mut self) {
+        self.last_command = None;
+        self.state = LinkState::Waiting;
+    }
+
+    /// Advances the failsafe to `now`, returning the movement the robot should follow
+    pub fn update(&mut self, now: Instant) -> Move {
+        let Some((received, command)) = self.last_command else {
+            return Move::stop()
+        };
+
+        let age = now.saturating_duration_since(received);
+        // without a deceleration time the robot stops as soon as the grace period is over
+        let (state, movement) = if age <= self.grace {
+            (LinkState::Active, command)
+        } else if !self.decel.is_zero() && age <= self.grace + self.decel {
+            let remaining = 1.0 - (age - self.grace).as_secs_f32() / self.decel.as_secs_f32();
+            (LinkState::Decelerating, scale(command, remaining))
+        } else {
+            (LinkState::Lost, Move::stop())
+        };
+
+        if state != self.state {
+            match state {
+                LinkState::Active if self.state == LinkState::Lost => {
+                    log::info!("Operator link restored");
+                }
+                LinkState::Decelerating => {
+                    log::debug!("No command for {:?}, decelerating", age);
+                }
+                LinkState::Lost => {
+                    log::warn!("No command for {:?}, operator link lost", age);
+                    self.link_losses = self.link_losses.saturating_add(1);
+                }
+                _ => {}
+            }
+            self.state = state;
+        }
+
+        movement
+    }
+
+    /// Number of times the link has been declared lost
+    pub fn link_losses(&self) -> u32 {
+        self.link_losses
+    }
+}
+
+fn scale(movement: Move, factor: f32) -> Move {
+    let factor = factor.clamp(0.0, 1.0);
+    Move {
+        translate: mint::Vector2 {
+            x: movement.translate.x * factor,
+            y: movement.translate.y * factor,
+        },
+        rotate: movement.rotate * factor,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRACE: Duration = Duration::from_millis(100);
+    const DECEL: Duration = Duration::from_millis(200);
+
+    fn forward() -> Move {
+        Move {
+            translate: mint::Vector2 { x: 0.5, y: 1.0 },
+            rotate: -0.5,
+        }
+    }
+
+    #[test]
+    fn stops_without_a_command() {
+        let mut failsafe = CommandFailsafe::new(GRACE, DECEL);
+        assert_eq!(failsafe.update(Instant::now()), Move::stop());
+        assert_eq!(failsafe.state, LinkState::Waiting);
+    }
+
+    #[test]
+    fn holds_then_decelerates_then_stops() {
+        let mut failsafe = CommandFailsafe::new(GRACE, DECEL);
+        let start = Instant::now();
+        failsafe.command(start, forward());
+
+        assert_eq!(failsafe.update(start + GRACE), forward());
+        assert_eq!(failsafe.state, LinkState::Active);
+
+        let halfway = failsafe.update(start + GRACE + DECEL / 2);
+        assert_eq!(failsafe.state, LinkState::Decelerating);
+        assert!((halfway.translate.x - 0.25).abs() < 1e-6);
+        assert!((halfway.translate.y - 0.5).abs() < 1e-6);
+        assert!((halfway.rotate + 0.25).abs() < 1e-6);
+
+        assert_eq!(failsafe.update(start + GRACE + DECEL + Duration::from_millis(1)), Move::stop());
+        assert_eq!(failsafe.state, LinkState::Lost);
+    }
+
+    #[test]
+    fn counts_each_loss_once() {
+        let mut failsafe = CommandFailsafe::new(GRACE, DECEL);
+        let start = Instant::now();
+        failsafe.command(start, forward());
+
+        let lost = start + GRACE + DECEL + Duration::from_millis(1);
+        for offset in 0..5 {
+            failsafe.update(lost + Duration::from_millis(offset * 100));
+        }
+        assert_eq!(failsafe.link_losses(), 1);
+
+        let resumed = lost + Duration::from_secs(1);
+        failsafe.command(resumed, forward());
+        assert_eq!(failsafe.update(resumed), forward());
+        failsafe.update(resumed + GRACE + DECEL + Duration::from_millis(1));
+        assert_eq!(failsafe.link_losses(), 2);
+    }
+
+    #[test]
+    fn fresh_command_restores_active() {
+        let mut failsafe = CommandFailsafe::new(GRACE, DECEL);
+        let start = Instant::now();
+        failsafe.command(start, forward());
+        let lost = start + GRACE + DECEL + Duration::from_millis(1);
+        failsafe.update(lost);
+        assert_eq!(failsafe.state, LinkState::Lost);
+
+        failsafe.command(lost, Move::stop());
+        assert_eq!(failsafe.update(lost), Move::stop());
+        assert_eq!(failsafe.state, LinkState::Active);
+    }
+
+    #[test]
+    fn clear_forgets_the_last_command() {
+        let mut failsafe = CommandFailsafe::new(GRACE, DECEL);
+        let start = Instant::now();
+        failsafe.command(start, forward());
+        failsafe.update(start);
+
+        failsafe.clear();
+        assert_eq!(failsafe.state, LinkState::Waiting);
+        assert_eq!(failsafe.update(start), Move::stop());
+        assert_eq!(failsafe.state, LinkState::Waiting);
+    }
+
+    #[test]
+    fn zero_deceleration_stops_after_grace() {
+        let mut failsafe = CommandFailsafe::new(GRACE, Duration::ZERO);
+        let start = Instant::now();
+        failsafe.command(start, forward());
+
+        assert_eq!(failsafe.update(start + GRACE), forward());
+        let stopped = failsafe.update(start + GRACE + Duration::from_nanos(1));
+        assert_eq!(stopped, Move::stop());
+        assert!(!stopped.rotate.is_nan());
+        assert_eq!(failsafe.state, LinkState::Lost);
+        assert_eq!(failsafe.link_losses(), 1);
+    }
+}
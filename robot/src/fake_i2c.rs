@@ -0,0 +1,89 @@
+//! In-memory I2C bus for testing drivers without hardware.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use embedded_hal::i2c::{self, ErrorType, I2c, Operation, SevenBitAddress};
+
+pub type Write = (SevenBitAddress, Vec<u8>);
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct FakeError;
+
+impl fmt::Display for FakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("fake I2C failure")
+    }
+}
+
+impl std::error::Error for FakeError {}
+
+impl i2c::Error for FakeError {
+    fn kind(&self) -> i2c::ErrorKind {
+        i2c::ErrorKind::Other
+    }
+}
+
+#[derive(Default)]
+struct State {
+    writes: Vec<Write>,
+    /// Number of upcoming transactions that fail
+    failures: u32,
+    /// How long each transaction takes
+    latency: Duration,
+}
+
+/// I2C bus recording every successful write, shared with the test through clones
+#[derive(Clone, Default)]
+pub struct FakeI2c {
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeI2c {
+    pub fn writes(&self) -> Vec<Write> {
+        self.state().writes.clone()
+    }
+
+    /// Returns the writes recorded so far and forgets them
+    pub fn take_writes(&self) -> Vec<Write> {
+        std::mem::take(&mut self.state().writes)
+    }
+
+    /// Fails the next `count` transactions
+    pub fn fail_next(&self, count: u32) {
+        self.state().failures = count;
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        self.state().latency = latency;
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl ErrorType for FakeI2c {
+    type Error = FakeError;
+}
+
+impl I2c for FakeI2c {
+    fn transaction(&mut self, address: SevenBitAddress, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+        let mut state = self.state();
+        if !state.latency.is_zero() {
+            std::thread::sleep(state.latency);
+        }
+        if state.failures > 0 {
+            state.failures -= 1;
+            return Err(FakeError)
+        }
+
+        for operation in operations {
+            if let Operation::Write(bytes) = operation {
+                state.writes.push((address, bytes.to_vec()));
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,67 +1,339 @@
-use adafruit_motorkit::dc::DcMotor as AdafruitMotor;
-use adafruit_motorkit::MotorError;
-use eyre::{Result, WrapErr};
-use signal_hook::SigId;
+//! Ownership of the robot's outputs, guaranteeing that motors are stopped however the control
+//! loop ends.
+//!
+//! Stopping normally happens through the drivers in the main loop, but that can't be relied on
+//! when the loop is stuck, the process is panicking, or a signal arrives while we're blocked on
+//! I2C. Once installed, [`Hardware`] stops outputs through an independent [`StopOutputs`] path:
+//! - from a panic hook, which runs before unwinding or aborting
+//! - from a watchdog thread, when a termination signal arrives or the main loop stops sending
+//!   heartbeats
+//! - when the [`Hardware`] handle is dropped
+//!
+//! Any of these trips the hardware permanently. The main loop is expected to exit once
+//! [`Hardware::tripped`] returns a reason, and the watchdog exits the process itself if it
+//! doesn't within [`SHUTDOWN_GRACE_PERIOD`].
 
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::sync::{Arc, atomic::AtomicBool, LazyLock};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock, TryLockError};
+use std::time::{Duration, Instant};
 
-type Pca9685 = pwm_pca9685::Pca9685<linux_embedded_hal::I2cdev>;
-type Pwm = Rc<RefCell<Pca9685>>;
+use embedded_hal::i2c::{I2c, SevenBitAddress};
+use eyre::{bail, eyre, Result, WrapErr};
 
-static PWM: CLock<Pwm> = LazyLock<Pwm>::new(
-    || Rc::new(RefCell::new(Pca9685::))
-)
+use crate::motor_hat;
+
+/// How often the watchdog checks for signals and heartbeats
+const WATCHDOG_POLL_PERIOD: Duration = Duration::from_millis(5);
+
+/// How long the process may keep running after the hardware trips before the watchdog exits it
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(2);
 
 static SIGNAL_FLAG: LazyLock<Arc<AtomicBool>> = LazyLock::new(
     || Arc::new(AtomicBool::new(false)));
 
-thread_local! {
-    static HARDWARE: RefCell<Option<SharedHardware>> = RefCell::new(None);
+static HARDWARE: OnceLock<Arc<SharedHardware>> = OnceLock::new();
+
+/// An independent way of bringing all outputs to a safe state
+pub trait StopOutputs: Send {
+    fn stop_outputs(&mut self) -> Result<()>;
+}
+
+/// Stops every output on the motor hat through its own handle to the I2C bus
+pub struct MotorHatShutdown<I> {
+    i2c: I,
+    address: SevenBitAddress,
+}
+
+impl<I> MotorHatShutdown<I> {
+    /// `i2c` should be separate from the handle used by the motor hat's driver, so that stopping
+    /// doesn't depend on the driver releasing the bus
+    pub fn new(i2c: I, address: SevenBitAddress) -> Self {
+        Self { i2c, address }
+    }
+}
+
+impl<I> StopOutputs for MotorHatShutdown<I>
+where
+    I: I2c + Send,
+    I::Error: fmt::Display,
+{
+    fn stop_outputs(&mut self) -> Result<()> {
+        motor_hat::all_off(&mut self.i2c, self.address)
+            .map_err(|e| eyre!("Failed to turn off motor hat outputs: {}", e))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Signal,
+    Panic,
+    Watchdog,
+    Exit,
+}
+
+impl StopReason {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => StopReason::Signal,
+            2 => StopReason::Panic,
+            3 => StopReason::Watchdog,
+            4 => StopReason::Exit,
+            _ => return None,
+        })
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            StopReason::Signal => 1,
+            StopReason::Panic => 2,
+            StopReason::Watchdog => 3,
+            StopReason::Exit => 4,
+        }
+    }
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StopReason::Signal => "termination signal received",
+            StopReason::Panic => "panic",
+            StopReason::Watchdog => "main loop stopped responding",
+            StopReason::Exit => "hardware released",
+        })
+    }
 }
 
 struct SharedHardware {
-    pwm: Pca9685,
+    stopper: Mutex<Box<dyn StopOutputs>>,
+    /// The first [`StopReason`] that tripped the hardware, or 0
+    tripped: AtomicU8,
+    /// Whether outputs were successfully stopped after tripping
+    stopped: AtomicBool,
+    epoch: Instant,
+    /// Milliseconds after `epoch` of the last heartbeat, offset by one so 0 means no heartbeat
+    heartbeat: AtomicU64,
 }
 
 impl SharedHardware {
-    fn new() {
+    fn new(stopper: Box<dyn StopOutputs>) -> Self {
+        Self {
+            stopper: Mutex::new(stopper),
+            tripped: AtomicU8::new(0),
+            stopped: AtomicBool::new(false),
+            epoch: Instant::now(),
+            heartbeat: AtomicU64::new(0),
+        }
+    }
 
+    fn tripped(&self) -> Option<StopReason> {
+        StopReason::from_u8(self.tripped.load(Ordering::SeqCst))
+    }
+
+    /// Trips the hardware and stops outputs. `blocking` must be false when the caller could
+    /// already hold the stopper, e.g. in the panic hook.
+    fn trip(&self, reason: StopReason, blocking: bool) {
+        let first = self.tripped
+            .compare_exchange(0, reason.to_u8(), Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+        if first {
+            log::warn!("Stopping all outputs: {}", reason);
+        } else if self.stopped.load(Ordering::SeqCst) {
+            return
+        }
+
+        let mut stopper = if blocking {
+            self.stopper.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+        } else {
+            match self.stopper.try_lock() {
+                Ok(stopper) => stopper,
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+                Err(TryLockError::WouldBlock) => {
+                    log::error!("Output stop already in progress, unable to stop outputs");
+                    return
+                }
+            }
+        };
+
+        match stopper.stop_outputs() {
+            Ok(()) => self.stopped.store(true, Ordering::SeqCst),
+            Err(e) => log::error!("Failed to stop outputs: {:?}", e),
+        }
+    }
+
+    fn heartbeat(&self, now: Instant) {
+        let millis = now.saturating_duration_since(self.epoch).as_millis() as u64 + 1;
+        self.heartbeat.store(millis, Ordering::SeqCst);
+    }
+
+    fn last_heartbeat(&self) -> Option<Instant> {
+        match self.heartbeat.load(Ordering::SeqCst) {
+            0 => None,
+            millis => Some(self.epoch + Duration::from_millis(millis - 1)),
+        }
     }
-}
 
-pub fn initialize() {
+    /// Trips the hardware if a termination signal arrived or the last heartbeat is more than
+    /// `timeout` before `now`, returning the reason the hardware is tripped, if it is
+    fn check(&self, timeout: Duration, now: Instant) -> Option<StopReason> {
+        if SIGNAL_FLAG.load(Ordering::SeqCst) {
+            self.trip(StopReason::Signal, true);
+        }
+        if let Some(last) = self.last_heartbeat() {
+            if now.saturating_duration_since(last) > timeout {
+                self.trip(StopReason::Watchdog, true);
+            }
+        }
+        self.tripped()
+    }
+}
 
+/// Handle to the installed output stop guarantees
+pub struct Hardware {
+    shared: Arc<SharedHardware>,
 }
 
-pub fn poll() {
+impl Hardware {
+    /// Installs the signal handlers, panic hook and watchdog thread. Only one [`Hardware`] may
+    /// be installed per process.
+    ///
+    /// The watchdog trips the hardware when the main loop goes `watchdog_timeout` without calling
+    /// [`Hardware::heartbeat`]. It's only armed after the first heartbeat.
+    pub fn install(stopper: impl StopOutputs + 'static, watchdog_timeout: Duration) -> Result<Self> {
+        let shared = Arc::new(SharedHardware::new(Box::new(stopper)));
+        if HARDWARE.set(Arc::clone(&shared)).is_err() {
+            bail!("Hardware shutdown handlers are already installed");
+        }
+
+        for signal in [
+            signal_hook::consts::SIGINT,
+            signal_hook::consts::SIGTERM,
+            signal_hook::consts::SIGQUIT,
+        ] {
+            signal_hook::flag::register(signal, Arc::clone(&SIGNAL_FLAG))
+                .wrap_err_with(|| format!("Failed to register stop handler for signal {}", signal))?;
+        }
+
+        install_panic_hook(Arc::clone(&shared));
+
+        std::thread::Builder::new()
+            .name("hardware watchdog".to_string())
+            .spawn({
+                let shared = Arc::clone(&shared);
+                move || watchdog(&shared, watchdog_timeout)
+            })
+            .wrap_err("Failed to start hardware watchdog")?;
+
+        Ok(Self { shared })
+    }
 
+    /// Marks the main loop as alive
+    pub fn heartbeat(&self) {
+        self.shared.heartbeat(Instant::now());
+    }
+
+    /// The reason outputs were stopped, if they have been. Once tripped, outputs must not be
+    /// driven again.
+    pub fn tripped(&self) -> Option<StopReason> {
+        self.shared.tripped()
+    }
 }
 
-pub struct DcMotor {
-    cleanup_signal: SigId,
-    motor: AdafruitMotor,
+impl Drop for Hardware {
+    fn drop(&mut self) {
+        self.shared.trip(StopReason::Exit, true);
+    }
 }
 
-impl Drop for DcMotor {
-    fn drop(&mut self) {}
+/// Stops outputs whenever the process panics, before any other panic handling
+fn install_panic_hook(shared: Arc<SharedHardware>) {
+    let previous_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        shared.trip(StopReason::Panic, false);
+        previous_hook(info);
+    }));
+}
+
+fn watchdog(shared: &SharedHardware, timeout: Duration) {
+    let mut tripped_at = None;
+    loop {
+        std::thread::sleep(WATCHDOG_POLL_PERIOD);
+
+        let Some(reason) = shared.check(timeout, Instant::now()) else { continue };
+        if !shared.stopped.load(Ordering::SeqCst) {
+            // keep retrying until the outputs are known to be off
+            shared.trip(reason, true);
+        }
+
+        let tripped_at = *tripped_at.get_or_insert_with(Instant::now);
+        if tripped_at.elapsed() > SHUTDOWN_GRACE_PERIOD {
+            log::error!("Process didn't exit within {:?} of stopping outputs ({}). Exiting now.",
+                SHUTDOWN_GRACE_PERIOD, reason);
+            std::process::exit(1);
+        }
+    }
 }
 
-impl DcMotor {
-    pub fn try_new(pwm: &mut Pca9685, port: adafruit_motorkit::Motor)
-        -> Result<Self>
-    {
-        let motor = AdafruitMotor::try_new(pwm, port)
-            .wrap_err_with(|| format!("Failed to initialize motor {:?}", port))?;
-        let cleanup_signal = signal_hook::flag::register(
-            signal_hook::consts::SIGINT, Arc::clone(&SIGNAL_FLAG))
-            .wrap_err_with(|| format!("Failed to register cleanup handler for motor {:?}", port))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_i2c::FakeI2c;
+
+    const ADDRESS: SevenBitAddress = 0x60;
+
+    /// Writes to the ALL_LED registers turning every PCA9685 channel fully off
+    const ALL_OFF: [u8; 5] = [0xFA, 0x00, 0x00, 0x00, 0x10];
+
+    fn fake_hardware() -> (Arc<SharedHardware>, FakeI2c) {
+        let i2c = FakeI2c::default();
+        let stopper = MotorHatShutdown::new(i2c.clone(), ADDRESS);
+        (Arc::new(SharedHardware::new(Box::new(stopper))), i2c)
+    }
+
+    #[test]
+    fn panic_turns_off_every_channel() {
+        let (shared, i2c) = fake_hardware();
+        install_panic_hook(Arc::clone(&shared));
+
+        let result = std::thread::spawn(|| panic!("deliberate test panic")).join();
 
-        Ok(Self { cleanup_signal, motor })
+        assert!(result.is_err());
+        assert_eq!(shared.tripped(), Some(StopReason::Panic));
+        assert!(shared.stopped.load(Ordering::SeqCst));
+        assert_eq!(i2c.writes(), vec![(ADDRESS, ALL_OFF.to_vec())]);
     }
 
-    pub fn set_throttle(&mut self, pwm: &mut Pca9685, throttle: f32) -> Result<(), MotorError> {
-        self.motor.set_throttle(pwm, throttle)
+    #[test]
+    fn missed_heartbeat_turns_off_every_channel() {
+        let (shared, i2c) = fake_hardware();
+        let timeout = Duration::from_millis(100);
+        let start = shared.epoch + Duration::from_secs(1);
+        shared.heartbeat(start);
+
+        assert_eq!(shared.check(timeout, start + timeout * 2), Some(StopReason::Watchdog));
+        assert!(shared.stopped.load(Ordering::SeqCst));
+        assert_eq!(i2c.writes(), vec![(ADDRESS, ALL_OFF.to_vec())]);
+    }
+
+    #[test]
+    fn live_heartbeat_keeps_outputs_on() {
+        let (shared, i2c) = fake_hardware();
+        let timeout = Duration::from_millis(100);
+        let mut now = shared.epoch + Duration::from_secs(1);
+        for _ in 0..10 {
+            shared.heartbeat(now);
+            now += timeout / 2;
+            assert_eq!(shared.check(timeout, now), None);
+        }
+
+        assert!(i2c.writes().is_empty());
+    }
+
+    #[test]
+    fn watchdog_is_armed_by_first_heartbeat() {
+        let (shared, i2c) = fake_hardware();
+
+        assert_eq!(shared.check(Duration::from_millis(100), shared.epoch + Duration::from_secs(10)), None);
+        assert!(i2c.writes().is_empty());
     }
 }
@@ -0,0 +1,160 @@
+//! A single-slot mailbox where newer values replace ones that haven't been received yet.
+//!
+//! Used for commands where only the newest one matters, so the receiver never works through a
+//! backlog of stale values. Replaced values are counted as superseded.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use smol::channel::{self, TrySendError};
+
+/// The other half of the mailbox was dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Closed;
+
+struct Slot<T> {
+    value: Mutex<Option<T>>,
+    superseded: AtomicU64,
+}
+
+pub struct LatestSender<T> {
+    slot: Arc<Slot<T>>,
+    wake: channel::Sender<()>,
+}
+
+pub struct LatestReceiver<T> {
+    slot: Arc<Slot<T>>,
+    wake: channel::Receiver<()>,
+}
+
+pub fn latest<T>() -> (LatestSender<T>, LatestReceiver<T>) {
+    let slot = Arc::new(Slot {
+        value: Mutex::new(None),
+        superseded: AtomicU64::new(0),
+    });
+    // a single pending wake up is enough, since the receiver always takes the newest value
+    let (wake_tx, wake_rx) = channel::bounded(1);
+
+    (
+        LatestSender { slot: Arc::clone(&slot), wake: wake_tx },
+        LatestReceiver { slot, wake: wake_rx },
+    )
+}
+
+impl<T> Clone for LatestSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: Arc::clone(&self.slot),
+            wake: self.wake.clone(),
+        }
+    }
+}
+
+impl<T> LatestSender<T> {
+    /// Replaces any value that hasn't been received yet
+    pub fn send(&self, value: T) -> Result<(), Closed> {
+        let replaced = self.slot.value.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .replace(value);
+        if replaced.is_some() {
+            self.slot.superseded.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match self.wake.try_send(()) {
+            Ok(()) | Err(TrySendError::Full(())) => Ok(()),
+            Err(TrySendError::Closed(())) => Err(Closed),
+        }
+    }
+
+    /// Counts values that were dropped before they reached the mailbox, e.g. when draining a
+    /// batch of them at once
+    pub fn add_superseded(&self, count: u64) {
+        self.slot.superseded.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+impl<T> LatestReceiver<T> {
+    /// Waits for a value newer than the last one received
+    pub async fn recv(&self) -> Result<T, Closed> {
+        loop {
+            if let Some(value) = self.take() {
+                return Ok(value)
+            }
+            if self.wake.recv().await.is_err() {
+                // all senders are gone, but the last value may have arrived with the close
+                return self.take().ok_or(Closed)
+            }
+        }
+    }
+
+    /// Total number of values that were replaced before being received
+    pub fn superseded(&self) -> u64 {
+        self.slot.superseded.load(Ordering::Relaxed)
+    }
+
+    fn take(&self) -> Option<T> {
+        let value = self.slot.value.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        if value.is_some() {
+            // the wake up for this value may still be pending
+            let _ = self.wake.try_recv();
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::future;
+
+    #[test]
+    fn burst_yields_only_newest_value() {
+        let (tx, rx) = latest();
+        for value in 0..100 {
+            tx.send(value).unwrap();
+        }
+
+        assert_eq!(smol::block_on(rx.recv()), Ok(99));
+        assert_eq!(rx.superseded(), 99);
+    }
+
+    #[test]
+    fn counts_values_dropped_before_sending() {
+        let (tx, rx) = latest();
+        tx.send(1).unwrap();
+        tx.add_superseded(3);
+        tx.send(2).unwrap();
+
+        assert_eq!(smol::block_on(rx.recv()), Ok(2));
+        assert_eq!(rx.superseded(), 4);
+    }
+
+    #[test]
+    fn wakes_once_per_burst() {
+        let (tx, rx) = latest();
+        for burst in 0..3 {
+            for value in 0..10 {
+                tx.send(burst * 10 + value).unwrap();
+            }
+            assert_eq!(rx.wake.len(), 1);
+
+            assert_eq!(smol::block_on(rx.recv()), Ok(burst * 10 + 9));
+            assert_eq!(rx.wake.len(), 0);
+            // nothing newer has been sent, so the receiver keeps waiting
+            assert_eq!(smol::block_on(future::poll_once(rx.recv())), None);
+        }
+        assert_eq!(rx.superseded(), 27);
+    }
+
+    #[test]
+    fn delivers_last_value_after_close() {
+        let (tx, rx) = latest();
+        tx.send(7).unwrap();
+        drop(tx);
+
+        assert_eq!(smol::block_on(rx.recv()), Ok(7));
+        assert_eq!(smol::block_on(rx.recv()), Err(Closed));
+    }
+}
@@ -1,25 +1,84 @@
-// mod hardware;
+mod bus;
+mod discovery;
+mod hardware;
+mod latest;
 mod motor_hat;
 mod drive;
+mod failsafe;
+#[cfg(test)]
+mod fake_i2c;
+mod mode;
+mod params;
 
 use std::ffi::OsString;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use eyre::{eyre, bail, Result, WrapErr};
 use futures_lite::prelude::*;
 use linux_embedded_hal as hal;
-use smol::net::UdpSocket;
+use smol::Async;
 
-pub type Pwm = pwm_pca9685::Pca9685<hal::I2cdev>;
+/// Number of main loop iterations between reports of the motor hat's bus usage
+const STATS_LOG_PERIOD_TICKS: u64 = 1000;
+
+/// Number of main loop iterations the motor hat's bus usage reported in the status is averaged
+/// over
+const STATUS_STATS_PERIOD_TICKS: u64 = 50;
+
+/// Maximum time between status reports to the operator, even when the status hasn't changed
+const STATUS_PERIOD: Duration = Duration::from_millis(100);
+
+pub type Pwm = motor_hat::MotorHat<bus::BusDevice<hal::I2cdev>>;
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Transport {
+    /// Each control message is sent as its own datagram
+    Udp,
+    /// Operators connect and stream control messages
+    Tcp,
+}
+
+impl From<Transport> for messages::Transport {
+    fn from(transport: Transport) -> Self {
+        match transport {
+            Transport::Udp => messages::Transport::Udp,
+            Transport::Tcp => messages::Transport::Tcp,
+        }
+    }
+}
 
 #[derive(Parser, Clone, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// UDP socket address to listen for control message at
-    #[arg(short = 'l', long, default_value = "0.0.0.0:9090")]
+    /// Socket address to listen for control messages at. The default accepts operators on both
+    /// IPv4 and IPv6 wherever IPv6 sockets also accept IPv4, as they do by default on Linux. Use
+    /// 0.0.0.0:9090 on hosts without IPv6.
+    #[arg(short = 'l', long, default_value = "[::]:9090")]
     listen_addr: String,
 
+    /// Transport operators use to send control messages
+    #[arg(short = 't', long, value_enum, default_value_t = Transport::Udp)]
+    transport: Transport,
+
+    /// Name the robot announces itself to operators with
+    #[arg(short = 'n', long, default_value = "mappie")]
+    name: String,
+
+    /// Address that beacons announcing the robot are sent to. Use a loopback address to only
+    /// announce to operators on the same machine.
+    #[arg(long, default_value = "255.255.255.255:9091")]
+    beacon_addr: SocketAddr,
+
+    /// Time between beacons announcing the robot, or 0 to not announce it
+    #[arg(long, default_value_t = 1000)]
+    beacon_period_ms: u64,
+
     /// Path to the I2C bus device file with an attached PWM controller
     #[arg(short = 'd', long, default_value = "/dev/i2c-1")]
     i2c_dev: OsString,
@@ -32,6 +91,37 @@ struct Args {
     #[arg(short = 'p', long, default_value_t = 16)]
     loop_period_ms: u64,
 
+    /// How long the last command is followed after commands stop arriving
+    #[arg(long, default_value_t = 100)]
+    command_timeout_ms: u64,
+
+    /// How long the robot takes to ramp down to a stop once the command timeout expires, after
+    /// which the operator link is declared lost
+    #[arg(long, default_value_t = 250)]
+    decel_ms: u64,
+
+    /// Time each main loop iteration may spend on the I2C bus before sensor reads are deferred
+    #[arg(long, default_value_t = 8000)]
+    i2c_budget_us: u64,
+
+    /// Time without a main loop iteration after which the watchdog stops all motors
+    #[arg(long, default_value_t = 500)]
+    watchdog_timeout_ms: u64,
+
+    /// Number of consecutive failed drive updates before the motors are stopped
+    #[arg(long, default_value_t = 5)]
+    drive_error_budget: u32,
+
+    /// File marking a latched e-stop, so that it's restored when the robot restarts. Should be
+    /// an absolute path, so the latch doesn't depend on the directory the robot is started from.
+    #[arg(long, default_value = "/var/lib/mappie/estop.latch")]
+    estop_latch_file: PathBuf,
+
+    /// Config file that tuned parameters are saved to. Saved values take precedence over the
+    /// matching command line arguments. Like the e-stop latch, it should be an absolute path.
+    #[arg(short = 'c', long, default_value = "/var/lib/mappie/robot.toml")]
+    config: PathBuf,
+
     /// The value below which movement commands are ignored
     #[arg(long, default_value_t = 0.2)]
     deadzone: f32
@@ -49,13 +139,29 @@ fn main() -> Result<()> {
         async_signal::Signal::Int,
     ]).wrap_err("Failed to create signal handler")?;
 
+    // Motors must be stopped however the process ends, so these guarantees are also installed
+    // before the motors are touched. The stop path gets its own handle to the I2C bus so that it
+    // doesn't depend on the main loop releasing the shared one.
+    let stop_dev = hal::I2cdev::new(args.i2c_dev.as_os_str())
+        .wrap_err("Failed to open I2C device for stopping outputs")?;
+    let shutdown = hardware::Hardware::install(
+        hardware::MotorHatShutdown::new(stop_dev, args.pwm_addr),
+        Duration::from_millis(args.watchdog_timeout_ms),
+    ).wrap_err("Failed to install hardware shutdown handlers")?;
+
     let dev = hal::I2cdev::new(args.i2c_dev.as_os_str())
         .wrap_err("Failed to open I2C device")?;
-    let mut pwm = adafruit_motorkit::init_pwm(Some(dev))
-        .wrap_err("Failed to initialize PWM controller on motor hat")?;
+    let bus = bus::SharedBus::new(dev, Duration::from_micros(args.i2c_budget_us));
+    let pwm = smol::block_on(motor_hat::MotorHat::new(
+        bus.device("motor hat", bus::Priority::Critical), args.pwm_addr, motor_hat::DEFAULT_PRESCALE))
+        .map_err(|e| eyre!("Failed to initialize PWM controller on motor hat: {}", e))?;
 
-    let drive = drive::Drive::new(args.clone(), &mut pwm)
-        .wrap_err("Failed to initialize drive system")?;
+    let params = params::Params::load(args.config.clone())
+        .wrap_err("Failed to load parameters")?;
+    let drive = drive::Drive::new(&args, &params);
+    let loop_params = LoopParams::register(&args, &params);
+    let mut mode = mode::ModeMachine::load(args.estop_latch_file.clone())
+        .wrap_err("Failed to load robot mode")?;
 
     let mut hardware = scopeguard::guard((pwm, drive), |(mut pwm, mut drive)| {
         // reset all hardware to an off state when main() exits
@@ -64,11 +170,21 @@ fn main() -> Result<()> {
     let (pwm, drive) = &mut *hardware;
 
     // create the task to listen for new, connecting operators
-    let (control_tx, control_rx) = smol::channel::bounded(10);
+    let (move_tx, move_rx) = latest::latest();
+    let (mode_tx, mode_rx) = smol::channel::bounded(10);
+    let (status_tx, status_rx) = smol::channel::bounded(1);
+    let estop = Arc::new(AtomicBool::new(false));
+    let control = ControlContext {
+        move_tx,
+        mode_tx,
+        status_rx,
+        estop: Arc::clone(&estop),
+        params,
+    };
     let _listen_task = smol::spawn({
         let args = args.clone();
         async move {
-            match establish_operators(args.clone(), control_tx.clone()).await {
+            match establish_operators(args.clone(), control).await {
                 Err(e) => {
                     log::warn!("Control listener crashed: {e}");
                     panic!("Control listener crashed: {e}")
@@ -79,8 +195,14 @@ fn main() -> Result<()> {
         }
     });
 
+    let link = OperatorLink {
+        moves: move_rx,
+        mode_requests: mode_rx,
+        status_tx,
+        estop,
+    };
     let res = smol::block_on(async {
-        main_loop(args, pwm, drive, control_rx)
+        main_loop(loop_params, &shutdown, &bus, pwm, drive, &mut mode, link)
             // The listen task is critical. If it exits unnexpectedly, exit the code
             // .race(listen_task)
             // Exit cleanly after shutdown signal
@@ -106,117 +228,569 @@ fn main() -> Result<()> {
     res
 }
 
+/// Everything operator connections need to pass messages on to the rest of the robot
+#[derive(Clone)]
+struct ControlContext {
+    move_tx: latest::LatestSender<messages::Move>,
+    mode_tx: smol::channel::Sender<mode::ModeRequest>,
+    status_rx: smol::channel::Receiver<messages::Status>,
+    /// Set when an e-stop arrives, ahead of any requests queued in `mode_tx`
+    estop: Arc<AtomicBool>,
+    params: params::Params,
+}
+
 /// Accepts connections from new operators
-async fn establish_operators(args: Args, control_tx: smol::channel::Sender<messages::Move>) -> Result<()> {
-    // let control_socket = smol::net::TcpListener::bind(args.listen_addr.as_str()).await?;
-
-    // loop {
-        // log::info!("Waiting for operator...");
-        // let (conn, _addr) = control_socket.accept().await?;
-        // log::info!("Accepted operator connection");
-    log::info!("Using connectionless operators...");
-    let uconn = smol::net::UdpSocket::bind(args.listen_addr.as_str()).await?;
-    smol::spawn(control_connection(uconn, control_tx.clone())).detach();
-    Ok(())
-    // }
-}
-
-/// Decodes messages from operators and sends them to the main loop
-async fn control_connection(conn: UdpSocket, control_tx: smol::channel::Sender<messages::Move>) -> Result<()> {
-    let mut buf = [0u8; messages::Move::POSTCARD_COBS_BUFFER_MAX_SIZE];
-    // let mut buf_insert_index = 0;
+async fn establish_operators(args: Args, control: ControlContext) -> Result<()> {
+    match args.transport {
+        Transport::Udp => {
+            log::info!("Using connectionless operators...");
+            let uconn = Async::new(std::net::UdpSocket::bind(args.listen_addr.as_str())?)?;
+            spawn_beacon(&args, uconn.get_ref().local_addr()?.port());
+            smol::spawn(control_connection(uconn, control)).detach();
+            Ok(())
+        }
+        Transport::Tcp => {
+            let control_socket = smol::net::TcpListener::bind(args.listen_addr.as_str()).await?;
+            spawn_beacon(&args, control_socket.local_addr()?.port());
+
+            // only one operator is in control at a time, and the newest connection takes over
+            let mut current: Option<smol::Task<()>> = None;
+            loop {
+                log::info!("Waiting for operator...");
+                let (conn, addr) = control_socket.accept().await?;
+                log::info!("Accepted operator connection from {}", addr);
+                if current.take().is_some() {
+                    log::warn!("Dropping previous operator connection in favor of {}", addr);
+                }
+
+                current = Some(smol::spawn(control_stream(conn, addr, control.clone())));
+            }
+        }
+    }
+}
+
+/// Starts announcing the robot to operators, unless that's disabled
+fn spawn_beacon(args: &Args, port: u16) {
+    if args.beacon_period_ms == 0 {
+        return
+    }
+    let beacon = match discovery::beacon(&args.name, port, args.transport.into()) {
+        Ok(beacon) => beacon,
+        Err(e) => {
+            log::error!("Not announcing robot to operators: {:#}", e);
+            return
+        }
+    };
+
+    let target = args.beacon_addr;
+    let period = Duration::from_millis(args.beacon_period_ms);
+    smol::spawn(async move {
+        if let Err(e) = discovery::announce(beacon, target, period).await {
+            log::error!("Stopped announcing robot to operators: {:#}", e);
+        }
+    }).detach();
+}
+
+/// Messages decoded from a single batch of operator input, e.g. one read from a socket
+#[derive(Default)]
+struct CommandBatch {
+    newest_move: Option<messages::Move>,
+    superseded: u64,
+    mode_requests: Vec<mode::ModeRequest>,
+    /// Replies to requests in the batch, along with the address of the operator that sent each
+    /// request, since several operators may share a socket
+    responses: Vec<(SocketAddr, messages::Message)>,
+}
+
+impl CommandBatch {
+    /// Adds a message received from the operator at `from`
+    fn add(&mut self, params: &params::Params, from: SocketAddr, msg: messages::Message) {
+        match msg {
+            messages::Message::Command(command) => self.add_command(command),
+            messages::Message::Request { id, request } => {
+                self.responses.push((from, messages::Message::Response {
+                    id,
+                    response: respond(params, request),
+                }));
+            }
+            msg @ (messages::Message::Status(_) | messages::Message::Response { .. }) => {
+                log::debug!("Ignoring unexpected message from operator: {:?}", msg);
+            }
+        }
+    }
+
+    fn add_command(&mut self, command: messages::Command) {
+        if let messages::Command::Move(m) = command {
+            if self.newest_move.replace(m).is_some() {
+                self.superseded += 1;
+            }
+        } else if let Some(request) = mode::ModeRequest::from_command(command) {
+            self.mode_requests.push(request);
+        }
+    }
+
+    /// Passes the batch on to the main loop. Only the newest movement is sent, while mode
+    /// changes are never dropped, and e-stops are also flagged through `control.estop` so the
+    /// main loop handles them first.
+    ///
+    /// Returns false once the main loop is gone.
+    async fn forward(self, control: &ControlContext) -> bool {
+        if self.mode_requests.contains(&mode::ModeRequest::EStop) {
+            control.estop.store(true, Ordering::SeqCst);
+        }
+        for request in self.mode_requests {
+            if control.mode_tx.send(request).await.is_err() {
+                return false
+            }
+        }
+
+        control.move_tx.add_superseded(self.superseded);
+        match self.newest_move {
+            Some(movement) => control.move_tx.send(movement).is_ok(),
+            None => true,
+        }
+    }
+}
+
+/// Answers a request from an operator
+fn respond(params: &params::Params, request: messages::Request) -> messages::Response {
+    use messages::{RequestError, Response};
+
+    match request {
+        messages::Request::Ping => Response::Pong,
+        messages::Request::GetParam(name) => match params.get(&name) {
+            Some(value) => Response::Param(value),
+            None => Response::Error(RequestError::UnknownParam),
+        },
+        messages::Request::SetParam(name, value) => match params.set(&name, value) {
+            Ok(value) => Response::Param(value),
+            Err(e) => Response::Error(e),
+        },
+        messages::Request::ListParams { start } => {
+            let (total, page) = params.list(start.into());
+            Response::ParamList {
+                total: total.try_into().unwrap_or(u16::MAX),
+                params: page.into_iter().collect(),
+            }
+        }
+        messages::Request::SaveParams => match params.save() {
+            Ok(()) => Response::Saved,
+            Err(e) => {
+                log::error!("Failed to save parameters: {:#}", e);
+                Response::Error(RequestError::SaveFailed)
+            }
+        },
+    }
+}
+
+/// Logs a frame from an operator that couldn't be decoded
+fn log_invalid_message(e: framing::Error, received_msg_count: u64) {
+    match e {
+        // newer operators may send messages we don't support yet
+        framing::Error::UnknownMessage => {
+            log::debug!("Skipping unknown message from operator ({} msgs received)", received_msg_count);
+        }
+        e => {
+            log::warn!("Received invalid message: {} ({} msgs received)", e, received_msg_count);
+        }
+    }
+}
+
+/// Decodes messages from operators and sends them to the main loop. Status reports from the main
+/// loop are sent to the operator we last received from, while responses go back to whichever
+/// operator sent the request.
+///
+/// All datagrams queued on the socket are read at once and forwarded as a single batch, so the
+/// main loop always acts on the latest movement instead of working through a backlog.
+async fn control_connection(conn: Async<std::net::UdpSocket>, control: ControlContext) -> Result<()> {
+    enum Event {
+        Readable(std::io::Result<()>),
+        Status(messages::Status),
+    }
+
+    let mut buf = [0u8; framing::max_frame_len::<messages::Message>()];
+    let mut encoder = framing::Encoder::<messages::Message>::new();
     let mut received_msg_count = 0;
+    let mut operator = None;
 
     loop {
-        let len = match conn.recv(&mut buf).await {
-            Ok(len) => len,
-            Err(e) => match e.kind() {
-                ErrorKind::UnexpectedEof => {
-                    eyre::bail!("Unexpected EOF while receiving from operator")
-                },
-                _ => {
+        let event = async { Event::Readable(conn.readable().await) }
+            .race(async {
+                match control.status_rx.recv().await {
+                    Ok(status) => Event::Status(status),
+                    // the main loop is gone, so there's nothing left to report
+                    Err(_) => std::future::pending().await,
+                }
+            }).await;
+
+        match event {
+            Event::Readable(Ok(())) => {}
+            Event::Readable(Err(e)) => {
+                return Err(eyre::Report::new(e)
+                    .wrap_err("Unhandled I/O error while waiting for operator"))
+            }
+            Event::Status(status) => {
+                if let Some(operator) = operator {
+                    send_to_operator(&conn, &mut encoder, operator, messages::Message::Status(status)).await;
+                }
+                continue
+            }
+        }
+
+        let mut batch = CommandBatch::default();
+        loop {
+            let (len, addr) = match conn.get_ref().recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
                     return Err(eyre::Report::new(e)
                         .wrap_err("Unhandled I/O error while receiving from operator"))
                 }
+            };
+
+            if operator != Some(addr) {
+                log::info!("Receiving control from operator at {}", addr);
+                operator = Some(addr);
+            }
+            if len == 0 {
+                log::warn!("Received empty datagram from operator");
+                continue
+            }
+
+            match framing::decode(&mut buf[..len]) {
+                Ok(msg) => {
+                    received_msg_count += 1;
+                    batch.add(&control.params, addr, msg);
+                }
+                Err(e) => log_invalid_message(e, received_msg_count),
             }
-        };
+        }
 
-        if len == 0 {
-            log::warn!("Connection to operator closed!");
+        let responses = std::mem::take(&mut batch.responses);
+        if !batch.forward(&control).await {
+            log::info!("Control channel closed. Exiting control connection");
             return Ok(())
         }
-        if buf[len - 1] != 0 {
-            log::warn!("Didn't receive complete datagram! ({} msgs received)", received_msg_count);
+        for (requester, response) in responses {
+            send_to_operator(&conn, &mut encoder, requester, response).await;
+        }
+    }
+}
+
+async fn send_to_operator(
+    conn: &Async<std::net::UdpSocket>,
+    encoder: &mut framing::Encoder<messages::Message>,
+    operator: SocketAddr,
+    msg: messages::Message,
+) {
+    if let Some(bytes) = encode_message(encoder, &msg) {
+        if let Err(e) = conn.send_to(bytes, operator).await {
+            log::debug!("Failed to send to operator at {}: {}", operator, e);
         }
+    }
+}
 
-        // we've reached the end of a cobs packet, read this in as a message
-        let msg: messages::Move = match postcard::from_bytes_cobs(&mut buf[..len]) {
-            Ok(msg) => msg,
-            Err(e) => {
-                log::warn!("Received invalid control message: {} ({} msgs received)", e, received_msg_count);
+/// Decodes a stream of messages from a connected operator and sends them to the main loop, while
+/// sending status reports from the main loop and responses to requests back.
+///
+/// Each read is forwarded as a single batch, like datagrams in [`control_connection`]. When the
+/// operator disconnects, the robot is stopped immediately instead of waiting for the command
+/// timeout.
+async fn control_stream(mut conn: smol::net::TcpStream, addr: SocketAddr, control: ControlContext) {
+    enum Event {
+        Read(std::io::Result<usize>),
+        Status(messages::Status),
+    }
+
+    let mut decoder = framing::Decoder::<messages::Message>::new();
+    let mut buf = [0u8; 512];
+    let mut encoder = framing::Encoder::<messages::Message>::new();
+    let mut received_msg_count = 0;
+
+    loop {
+        let event = async { Event::Read(conn.read(&mut buf).await) }
+            .race(async {
+                match control.status_rx.recv().await {
+                    Ok(status) => Event::Status(status),
+                    Err(_) => std::future::pending().await,
+                }
+            }).await;
+
+        let len = match event {
+            Event::Read(Ok(0)) => {
+                log::warn!("Operator at {} disconnected", addr);
+                break
+            }
+            Event::Read(Ok(len)) => len,
+            Event::Read(Err(e)) => {
+                log::warn!("Connection to operator at {} failed: {}", addr, e);
+                break
+            }
+            Event::Status(status) => {
+                if let Some(bytes) = encode_message(&mut encoder, &messages::Message::Status(status)) {
+                    if let Err(e) = conn.write_all(bytes).await {
+                        log::warn!("Failed to send status to operator at {}: {}", addr, e);
+                        break
+                    }
+                }
                 continue
             }
         };
-        // next_msg_start = i + 1;
-        received_msg_count += 1;
 
-        if control_tx.send(msg).await.is_err() {
+        let mut batch = CommandBatch::default();
+        decoder.push(&buf[..len], |msg| match msg {
+            Ok(msg) => {
+                received_msg_count += 1;
+                batch.add(&control.params, addr, msg);
+            }
+            Err(framing::Error::FrameTooLong) => {
+                log::warn!("Discarding oversized frame from operator at {}", addr);
+            }
+            Err(e) => log_invalid_message(e, received_msg_count),
+        });
+
+        let responses = std::mem::take(&mut batch.responses);
+        if !batch.forward(&control).await {
             log::info!("Control channel closed. Exiting control connection");
-            return Ok(())
+            return
         }
+        let mut out = Vec::new();
+        for (_, response) in responses {
+            if let Some(bytes) = encode_message(&mut encoder, &response) {
+                out.extend_from_slice(bytes);
+            }
+        }
+        if out.is_empty() {
+            continue
+        }
+        if let Err(e) = conn.write_all(&out).await {
+            log::warn!("Failed to send responses to operator at {}: {}", addr, e);
+            break
+        }
+    }
 
-        // let last_insert_index = buf_insert_index;
-        // buf_insert_index += len;
-        // let mut next_msg_start = 0;
+    if control.move_tx.send(messages::Move::stop()).is_err() {
+        log::info!("Control channel closed while stopping after disconnect");
+    }
+}
 
-        // Loop through the buffer, finding any full received messages by looking for the
-        // terminator byte (value 0)
-        // for i in last_insert_index..buf_insert_index {
-        //     let byte = buf[i];
-        //     if byte == 0 {
-        //     }
-        // }
+fn encode_message<'a>(
+    encoder: &'a mut framing::Encoder<messages::Message>,
+    msg: &messages::Message,
+) -> Option<&'a [u8]> {
+    match encoder.encode(msg) {
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            log::error!("Unable to serialize {:?}: {}", msg, e);
+            None
+        }
+    }
+}
 
-        // move any unprocessed data to the front of the buffer and reset the buf_insert_index,
-        // effectively erasing all the messages we just deserialized and processed
-        // buf.copy_within(next_msg_start..buf_insert_index, 0);
-        // buf_insert_index -= next_msg_start;
+/// The main loop's side of the connection to operators
+struct OperatorLink {
+    moves: latest::LatestReceiver<messages::Move>,
+    mode_requests: smol::channel::Receiver<mode::ModeRequest>,
+    status_tx: smol::channel::Sender<messages::Status>,
+    /// Set when an e-stop arrives, ahead of any requests queued in `mode_requests`
+    estop: Arc<AtomicBool>,
+}
+
+/// Tunable settings of the main loop
+struct LoopParams {
+    /// The maximum amount of time between main loop iterations
+    period_ms: params::Param<u64>,
+    /// How long the last command is followed after commands stop arriving
+    command_timeout_ms: params::Param<u64>,
+    /// How long the robot takes to ramp down to a stop once the command timeout expires
+    decel_ms: params::Param<u64>,
+    /// Most verbose messages that are logged. Messages filtered out by `RUST_LOG` can't be
+    /// enabled this way.
+    log_level: params::Param<log::LevelFilter>,
+}
+
+impl LoopParams {
+    fn register(args: &Args, params: &params::Params) -> Self {
+        // the watchdog stops the robot if an iteration takes longer than its timeout, so the
+        // period has to leave room for the iteration's own work
+        let max_period_ms = (args.watchdog_timeout_ms / 2).clamp(1, 1000);
+        Self {
+            period_ms: params.register("loop.period_ms", args.loop_period_ms, Some(1..=max_period_ms)),
+            command_timeout_ms: params.register(
+                "failsafe.command_timeout_ms", args.command_timeout_ms, Some(0..=10_000)),
+            decel_ms: params.register("failsafe.decel_ms", args.decel_ms, Some(1..=10_000)),
+            log_level: params.register("log.level", log::max_level(), None),
+        }
     }
 }
 
 async fn main_loop(
-    args: Args,
+    mut loop_params: LoopParams,
+    shutdown: &hardware::Hardware,
+    bus: &bus::SharedBus<hal::I2cdev>,
     pwm: &mut Pwm,
     drive: &mut drive::Drive,
-    control: smol::channel::Receiver<messages::Move>
+    mode: &mut mode::ModeMachine,
+    link: OperatorLink,
 ) -> Result<()> {
+    enum Control {
+        Move(Result<messages::Move, latest::Closed>),
+        Mode(Result<mode::ModeRequest, smol::channel::RecvError>),
+        Timeout,
+    }
+
+    let OperatorLink { moves, mode_requests, status_tx, estop } = link;
+
     // follow the configured minimum update rate, even when a message isn't received
-    let minimum_update_period = std::time::Duration::from_millis(args.loop_period_ms);
+    let mut minimum_update_period = Duration::from_millis(loop_params.period_ms.get());
     let mut update_timer = smol::Timer::after(minimum_update_period);
 
-    let mut movement;
+    let mut failsafe = failsafe::CommandFailsafe::new(
+        Duration::from_millis(loop_params.command_timeout_ms.get()),
+        Duration::from_millis(loop_params.decel_ms.get()),
+    );
+    // the level may have been loaded from the config file, which doesn't count as a change
+    log::set_max_level(loop_params.log_level.get());
+    let mut last_stats = pwm.stats();
+    let mut last_status_stats = last_stats;
+    let mut motor_transactions_per_tick = 0.0;
+    let mut last_device_stats = bus.device_stats();
+    let mut last_status = None;
+    let mut last_status_sent = Instant::now();
 
     log::debug!("Starting main loop");
     loop {
         log::trace!("Waiting for control...");
-        let control = async { Some(control.recv().await) }
+        let control = async { Control::Move(moves.recv().await) }
+            .race(async { Control::Mode(mode_requests.recv().await) })
             .race(async {
                 update_timer.next().await;
-                None
+                Control::Timeout
             }).await;
 
         log::trace!("Beginning main loop iteration");
-        if let Some(control) = control {
-            movement = match control {
-                Ok(c) => c,
-                Err(_) => bail!("Control unexpectedly lost!")
-            };
-        } else {
-            log::trace!("Control timed out in main loop");
-            movement = messages::Move::stop();
+        if let Some(reason) = shutdown.tripped() {
+            bail!("Outputs were stopped: {}", reason);
+        }
+        shutdown.heartbeat();
+
+        if estop.swap(false, Ordering::SeqCst) {
+            mode.request(mode::ModeRequest::EStop);
+        }
+
+        if let Some(period_ms) = loop_params.period_ms.changed() {
+            minimum_update_period = Duration::from_millis(period_ms);
+        }
+        if let Some(timeout_ms) = loop_params.command_timeout_ms.changed() {
+            failsafe.set_grace(Duration::from_millis(timeout_ms));
+        }
+        if let Some(decel_ms) = loop_params.decel_ms.changed() {
+            failsafe.set_decel(Duration::from_millis(decel_ms));
+        }
+        if let Some(level) = loop_params.log_level.changed() {
+            log::set_max_level(level);
+        }
+
+        let now = Instant::now();
+        match control {
+            Control::Move(Ok(m)) => failsafe.command(now, m),
+            Control::Mode(Ok(request)) => {
+                let was_enabled = mode.allows_motion();
+                mode.request(request);
+                if mode.allows_motion() && !was_enabled {
+                    // don't resume a command that was sent while the robot was disabled
+                    failsafe.clear();
+                }
+            }
+            Control::Move(Err(_)) | Control::Mode(Err(_)) => bail!("Control unexpectedly lost!"),
+            Control::Timeout => log::trace!("No control received this iteration"),
         }
+
+        let movement = failsafe.update(now);
+        let movement = if mode.allows_motion() { movement } else { messages::Move::stop() };
         update_timer.set_after(minimum_update_period);
 
-        drive.main_loop(pwm, movement);
+        bus.begin_tick();
+        if let Err(fault) = drive.main_loop(pwm, movement).await {
+            log::warn!("Drive fault: {:#}", eyre::Report::new(fault));
+        }
+
+        let stats = pwm.stats();
+        let ticks = stats.flushes - last_status_stats.flushes;
+        if ticks >= STATUS_STATS_PERIOD_TICKS {
+            let transactions = stats.transactions - last_status_stats.transactions;
+            motor_transactions_per_tick = transactions as f32 / ticks as f32;
+            last_status_stats = stats;
+        }
+
+        let status = messages::Status {
+            mode: mode.mode(),
+            drive: drive.status(),
+            drive_errors: drive.total_errors(),
+            link_losses: failsafe.link_losses(),
+            superseded_moves: moves.superseded().try_into().unwrap_or(u32::MAX),
+            motor_transactions_per_tick,
+        };
+        if (last_status != Some(status) || last_status_sent.elapsed() >= STATUS_PERIOD)
+            && status_tx.try_send(status).is_ok()
+        {
+            last_status = Some(status);
+            last_status_sent = Instant::now();
+        }
+
+        let ticks = stats.flushes - last_stats.flushes;
+        if ticks >= STATS_LOG_PERIOD_TICKS {
+            let transactions = stats.transactions - last_stats.transactions;
+            let channels = stats.channels_written - last_stats.channels_written;
+            log::debug!("Motor hat used {:.2} I2C transactions and wrote {:.2} channels per tick over the last {} ticks",
+                transactions as f64 / ticks as f64, channels as f64 / ticks as f64, ticks);
+            last_stats = stats;
+
+            let device_stats = bus.device_stats();
+            for (device, last) in device_stats.iter().zip(last_device_stats.iter()) {
+                let errors = device.errors - last.errors;
+                let deferred = device.deferred - last.deferred;
+                let transactions = device.transactions - last.transactions;
+                if errors > 0 {
+                    log::warn!("I2C device '{}' had {} errors in {} transactions ({} total errors)",
+                        device.name, errors, transactions, device.errors);
+                }
+                log::debug!("I2C device '{}': {} transactions, {} deferred, {:?} bus time",
+                    device.name, transactions, deferred, device.bus_time - last.bus_time);
+            }
+            last_device_stats = device_stats;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_go_to_their_requester() {
+        let params = params::Params::load(PathBuf::from("/nonexistent/robot.toml")).unwrap();
+        let oi: SocketAddr = "192.168.1.10:40000".parse().unwrap();
+        let ctl: SocketAddr = "192.168.1.11:50000".parse().unwrap();
+
+        let mut batch = CommandBatch::default();
+        batch.add(&params, oi, messages::Message::Request { id: 7, request: messages::Request::Ping });
+        batch.add(&params, ctl, messages::Message::Request { id: 0, request: messages::Request::Ping });
+        batch.add(&params, oi, messages::Message::Command(messages::Command::Enable));
+
+        let pong = |id| messages::Message::Response { id, response: messages::Response::Pong };
+        assert_eq!(batch.responses, vec![(oi, pong(7)), (ctl, pong(0))]);
+        assert_eq!(batch.mode_requests, vec![mode::ModeRequest::Enable]);
+    }
+
+    #[test]
+    fn loop_period_stays_within_watchdog_timeout() {
+        let args = Args::parse_from(["robot", "--watchdog-timeout-ms", "500", "--loop-period-ms", "400"]);
+        let params = params::Params::load(PathBuf::from("/nonexistent/robot.toml")).unwrap();
+        let loop_params = LoopParams::register(&args, &params);
+
+        assert_eq!(loop_params.period_ms.get(), 250);
+        assert_eq!(params.set("loop.period_ms", messages::ParamValue::Int(600)),
+            Err(messages::RequestError::InvalidValue));
     }
 }
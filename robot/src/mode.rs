@@ -0,0 +1,182 @@
+//! The robot's operating mode.
+//!
+//! The robot starts disabled and only drives once an operator enables it. An emergency stop
+//! overrides every other mode and stays latched, across operator reconnections and robot
+//! restarts, until an operator explicitly clears it. The latch survives restarts through a file
+//! that exists for as long as the e-stop is active.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use eyre::{Result, WrapErr};
+use messages::{Command, Mode};
+
+/// Requested changes to the robot's mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModeRequest {
+    Enable,
+    Disable,
+    EStop,
+    ClearEStop,
+}
+
+impl ModeRequest {
+    /// The mode change requested by an operator command, if it is one
+    pub fn from_command(command: Command) -> Option<Self> {
+        Some(match command {
+            Command::Enable => ModeRequest::Enable,
+            Command::Disable => ModeRequest::Disable,
+            Command::EStop => ModeRequest::EStop,
+            Command::ClearEStop => ModeRequest::ClearEStop,
+            Command::Move(_) => return None,
+        })
+    }
+}
+
+pub struct ModeMachine {
+    mode: Mode,
+    latch_path: PathBuf,
+}
+
+impl ModeMachine {
+    /// Starts disabled, unless an e-stop was still latched when the robot last exited
+    pub fn load(latch_path: PathBuf) -> Result<Self> {
+        let latched = latch_path.try_exists()
+            .wrap_err_with(|| format!("Unable to check e-stop latch file {}", latch_path.display()))?;
+        let mode = if latched {
+            log::warn!("E-stop is still latched from a previous run ({}), clear it to drive",
+                latch_path.display());
+            Mode::EStopped
+        } else {
+            Mode::Disabled
+        };
+
+        Ok(Self { mode, latch_path })
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Whether motors may be driven in the current mode
+    pub fn allows_motion(&self) -> bool {
+        self.mode == Mode::Enabled
+    }
+
+    /// Applies a mode change. Requests that aren't valid in the current mode are ignored, and the
+    /// resulting mode is returned either way.
+    pub fn request(&mut self, request: ModeRequest) -> Mode {
+        let next = match (self.mode, request) {
+            (_, ModeRequest::EStop) => Mode::EStopped,
+            (Mode::EStopped, ModeRequest::ClearEStop) => Mode::Disabled,
+            (Mode::EStopped, ModeRequest::Enable | ModeRequest::Disable) => {
+                log::warn!("Ignoring {:?} request, the e-stop must be cleared first", request);
+                Mode::EStopped
+            }
+            (_, ModeRequest::Enable) => Mode::Enabled,
+            (_, ModeRequest::Disable) => Mode::Disabled,
+            (mode, ModeRequest::ClearEStop) => mode,
+        };
+
+        if next != self.mode {
+            log::info!("Robot mode changed from {:?} to {:?}", self.mode, next);
+            match next {
+                Mode::EStopped => self.set_latch(),
+                _ if self.mode == Mode::EStopped => self.clear_latch(),
+                _ => {}
+            }
+            self.mode = next;
+        }
+        self.mode
+    }
+
+    fn set_latch(&self) {
+        // the e-stop is still latched in memory, so failing to persist it isn't fatal, but the
+        // latch won't survive a restart
+        let written = match self.latch_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir),
+            _ => Ok(()),
+        }.and_then(|()| fs::write(&self.latch_path, b""));
+        if let Err(e) = written {
+            log::error!("Unable to persist e-stop latch to {}: {}", self.latch_path.display(), e);
+        }
+    }
+
+    fn clear_latch(&self) {
+        match fs::remove_file(&self.latch_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                log::error!("Unable to remove e-stop latch {}, it will be restored on restart: {}",
+                    self.latch_path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Latch path in a fresh directory of its own, which doesn't exist yet
+    fn latch_path(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mappie-mode-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("estop.latch")
+    }
+
+    #[test]
+    fn starts_disabled() {
+        let machine = ModeMachine::load(latch_path("starts_disabled")).unwrap();
+        assert_eq!(machine.mode(), Mode::Disabled);
+        assert!(!machine.allows_motion());
+    }
+
+    #[test]
+    fn estop_overrides_every_mode() {
+        for start in [ModeRequest::Enable, ModeRequest::Disable, ModeRequest::EStop] {
+            let path = latch_path(&format!("estop_overrides_{:?}", start));
+            let mut machine = ModeMachine::load(path).unwrap();
+            machine.request(start);
+            assert_eq!(machine.request(ModeRequest::EStop), Mode::EStopped, "after {:?}", start);
+            assert!(!machine.allows_motion());
+        }
+    }
+
+    #[test]
+    fn estop_must_be_cleared_before_enabling() {
+        let mut machine = ModeMachine::load(latch_path("estop_must_be_cleared")).unwrap();
+        machine.request(ModeRequest::EStop);
+        assert_eq!(machine.request(ModeRequest::Enable), Mode::EStopped);
+        assert_eq!(machine.request(ModeRequest::Disable), Mode::EStopped);
+
+        // clearing doesn't enable on its own
+        assert_eq!(machine.request(ModeRequest::ClearEStop), Mode::Disabled);
+        assert_eq!(machine.request(ModeRequest::Enable), Mode::Enabled);
+    }
+
+    #[test]
+    fn latch_file_follows_estop() {
+        let path = latch_path("latch_file_follows_estop");
+        let mut machine = ModeMachine::load(path.clone()).unwrap();
+
+        machine.request(ModeRequest::EStop);
+        assert!(path.exists());
+        machine.request(ModeRequest::ClearEStop);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn estop_survives_restart() {
+        let path = latch_path("estop_survives_restart");
+        ModeMachine::load(path.clone()).unwrap().request(ModeRequest::EStop);
+
+        let mut machine = ModeMachine::load(path.clone()).unwrap();
+        assert_eq!(machine.mode(), Mode::EStopped);
+        assert_eq!(machine.request(ModeRequest::Enable), Mode::EStopped);
+
+        machine.request(ModeRequest::ClearEStop);
+        assert_eq!(ModeMachine::load(path).unwrap().mode(), Mode::Disabled);
+    }
+}
@@ -1,25 +1,383 @@
-// use embedded_hal::i2c::{I2c, SevenBitAddress};
-//
-// pub struct MotorHat<I: I2c> {
-//     i2c_bus: I,
-// }
-//
-// impl<I: I2c> MotorHat<I> {
-//     pub fn new(i2c_bus: I) {
-//         Self {
-//             i2c_bus
-//         }
-//     }
-//
-//     /// Throttle in range [-1.0, 1.0]. A throttle of 0.0 engages brake mode.
-//     pub fn throttle_motor1(throttle: f32) {
-//         let throttle = throttle.clamp(-1.0, 1.0);
-//
-//         
-//     }
-// }
-//
-// struct I2cPin<I: I2c> {
-//     i2c_bus: &mut I,
-//     address: SevenBitAddress,
-// }
+//! Driver for the Adafruit DC motor HAT, which pairs a PCA9685 PWM controller with two TB6612FNG
+//! H-bridges.
+//!
+//! Instead of writing every channel whenever a throttle changes, the driver stages channel values
+//! in memory and only sends the ones that differ from what's already in the PCA9685's registers.
+//! Changed channels are flushed with the controller's register auto-increment, so neighbouring
+//! channels are written in a single I2C transaction.
+
+use std::time::Duration;
+
+use embedded_hal::i2c::{I2c, SevenBitAddress};
+
+/// Number of PWM channels on the PCA9685
+pub const CHANNEL_COUNT: usize = 16;
+
+/// Prescaler giving a ~1.6kHz PWM frequency, the same as Adafruit's own libraries
+pub const DEFAULT_PRESCALE: u8 = 3;
+
+/// Number of unchanged channels between two changed ones that are rewritten anyway to save a
+/// transaction. Each bridged channel costs 4 data bytes, which is cheaper than the start
+/// condition, address and register bytes of a new transaction plus the ioctl behind it.
+const MAX_BRIDGED_CHANNELS: usize = 2;
+
+mod reg {
+    pub const MODE1: u8 = 0x00;
+    pub const MODE2: u8 = 0x01;
+    pub const LED0_ON_L: u8 = 0x06;
+    pub const ALL_LED_ON_L: u8 = 0xFA;
+    pub const PRE_SCALE: u8 = 0xFE;
+}
+
+mod mode1 {
+    pub const RESTART: u8 = 0x80;
+    pub const AUTO_INCREMENT: u8 = 0x20;
+    pub const SLEEP: u8 = 0x10;
+    pub const ALLCALL: u8 = 0x01;
+}
+
+mod mode2 {
+    pub const OUTDRV: u8 = 0x04;
+}
+
+/// The motor ports on the HAT, labeled M1-M4 on the board
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Motor {
+    Motor1,
+    Motor2,
+    Motor3,
+    Motor4,
+}
+
+/// PCA9685 channels wired to a single TB6612FNG motor output
+struct MotorChannels {
+    pwm: usize,
+    in1: usize,
+    in2: usize,
+}
+
+impl Motor {
+    fn channels(self) -> MotorChannels {
+        let (pwm, in2, in1) = match self {
+            Motor::Motor1 => (8, 9, 10),
+            Motor::Motor2 => (13, 12, 11),
+            Motor::Motor3 => (2, 3, 4),
+            Motor::Motor4 => (7, 6, 5),
+        };
+        MotorChannels { pwm, in1, in2 }
+    }
+}
+
+/// The on/off counts of a single PWM channel, as stored in its LEDn_ON/LEDn_OFF registers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ChannelValue {
+    on: u16,
+    off: u16,
+}
+
+impl ChannelValue {
+    const FULL_ON: Self = Self { on: 0x1000, off: 0 };
+    const FULL_OFF: Self = Self { on: 0, off: 0x1000 };
+
+    /// Duty cycle in range [0.0, 1.0]
+    fn duty(duty: f32) -> Self {
+        match (duty.clamp(0.0, 1.0) * 4095.0).round() as u16 {
+            0 => Self::FULL_OFF,
+            4095 => Self::FULL_ON,
+            counts => Self { on: 0, off: counts },
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 4] {
+        let [on_l, on_h] = self.on.to_le_bytes();
+        let [off_l, off_h] = self.off.to_le_bytes();
+        [on_l, on_h, off_l, off_h]
+    }
+}
+
+/// Running totals of the bus traffic generated by the driver
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of calls to [`MotorHat::flush`]
+    pub flushes: u64,
+    /// Number of I2C write transactions issued while flushing
+    pub transactions: u64,
+    /// Number of channels written while flushing, including bridged ones
+    pub channels_written: u64,
+}
+
+pub struct MotorHat<I> {
+    i2c: I,
+    address: SevenBitAddress,
+    prescale: u8,
+    /// Channel values believed to be in the controller's registers. `None` means the register
+    /// contents are unknown, e.g. after a failed write, and must be rewritten.
+    written: [Option<ChannelValue>; CHANNEL_COUNT],
+    /// Channel values to be sent on the next flush
+    pending: [ChannelValue; CHANNEL_COUNT],
+    stats: Stats,
+}
+
+impl<I: I2c> MotorHat<I> {
+    /// Takes ownership of the I2C bus and initializes the PWM controller with all outputs off
+    pub async fn new(i2c: I, address: SevenBitAddress, prescale: u8) -> Result<Self, I::Error> {
+        let mut hat = Self {
+            i2c,
+            address,
+            prescale,
+            written: [None; CHANNEL_COUNT],
+            pending: [ChannelValue::FULL_OFF; CHANNEL_COUNT],
+            stats: Stats::default(),
+        };
+        hat.initialize().await?;
+        Ok(hat)
+    }
+
+    /// Resets the PWM controller's mode, frequency and outputs.
+    ///
+    /// All outputs are turned off, but staged throttles are kept and will be rewritten by the
+    /// next flush.
+    pub async fn initialize(&mut self) -> Result<(), I::Error> {
+        self.written = [None; CHANNEL_COUNT];
+        all_off(&mut self.i2c, self.address)?;
+
+        self.i2c.write(self.address, &[reg::MODE2, mode2::OUTDRV])?;
+        // the prescaler can only be changed while the oscillator is asleep
+        let mode = mode1::ALLCALL | mode1::AUTO_INCREMENT;
+        self.i2c.write(self.address, &[reg::MODE1, mode | mode1::SLEEP])?;
+        self.i2c.write(self.address, &[reg::PRE_SCALE, self.prescale])?;
+        self.i2c.write(self.address, &[reg::MODE1, mode])?;
+        // the oscillator needs 500us to stabilize after waking up
+        smol::Timer::after(Duration::from_micros(500)).await;
+        self.i2c.write(self.address, &[reg::MODE1, mode | mode1::RESTART])?;
+
+        self.written = [Some(ChannelValue::FULL_OFF); CHANNEL_COUNT];
+        Ok(())
+    }
+
+    /// Stages a throttle in range [-1.0, 1.0] for the next flush. A throttle of 0.0 engages brake
+    /// mode.
+    pub fn set_throttle(&mut self, motor: Motor, throttle: f32) {
+        let MotorChannels { pwm, in1, in2 } = motor.channels();
+        let throttle = throttle.clamp(-1.0, 1.0);
+
+        let (in1_value, in2_value) = if throttle > 0.0 {
+            (ChannelValue::FULL_ON, ChannelValue::FULL_OFF)
+        } else if throttle < 0.0 {
+            (ChannelValue::FULL_OFF, ChannelValue::FULL_ON)
+        } else {
+            (ChannelValue::FULL_ON, ChannelValue::FULL_ON)
+        };
+
+        self.pending[pwm] = ChannelValue::duty(throttle.abs());
+        self.pending[in1] = in1_value;
+        self.pending[in2] = in2_value;
+    }
+
+    /// Writes all staged channel values that differ from the controller's registers, returning
+    /// the number of I2C transactions used
+    pub fn flush(&mut self) -> Result<usize, I::Error> {
+        self.stats.flushes += 1;
+
+        let mut transactions = 0;
+        let mut channel = 0;
+        while channel < CHANNEL_COUNT {
+            if !self.is_dirty(channel) {
+                channel += 1;
+                continue
+            }
+
+            // extend the run of channels to write, bridging short gaps of unchanged channels
+            let start = channel;
+            let mut end = channel + 1;
+            let mut scan = end;
+            while scan < CHANNEL_COUNT && scan - end <= MAX_BRIDGED_CHANNELS {
+                if self.is_dirty(scan) {
+                    end = scan + 1;
+                }
+                scan += 1;
+            }
+
+            self.write_channels(start, end)?;
+            transactions += 1;
+            channel = end;
+        }
+
+        Ok(transactions)
+    }
+
+    /// Stages and immediately writes a brake on every motor
+    pub fn stop(&mut self) -> Result<(), I::Error> {
+        for motor in [Motor::Motor1, Motor::Motor2, Motor::Motor3, Motor::Motor4] {
+            self.set_throttle(motor, 0.0);
+        }
+        self.flush().map(|_| ())
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    fn is_dirty(&self, channel: usize) -> bool {
+        self.written[channel] != Some(self.pending[channel])
+    }
+
+    /// Writes channels in the range `start..end` in one auto-incrementing transaction
+    fn write_channels(&mut self, start: usize, end: usize) -> Result<(), I::Error> {
+        let mut buf = [0u8; 1 + 4 * CHANNEL_COUNT];
+        buf[0] = reg::LED0_ON_L + 4 * start as u8;
+        for (i, value) in self.pending[start..end].iter().enumerate() {
+            buf[1 + 4 * i..][..4].copy_from_slice(&value.to_bytes());
+        }
+
+        self.stats.transactions += 1;
+        if let Err(e) = self.i2c.write(self.address, &buf[..1 + 4 * (end - start)]) {
+            // some of the registers may have been written before the failure
+            self.written[start..end].fill(None);
+            return Err(e)
+        }
+
+        self.stats.channels_written += (end - start) as u64;
+        for channel in start..end {
+            self.written[channel] = Some(self.pending[channel]);
+        }
+        Ok(())
+    }
+}
+
+/// Turns off every output of the PWM controller in a single transaction.
+///
+/// This doesn't depend on any driver state, so it's safe to use from shutdown paths with their own
+/// handle to the bus. A [`MotorHat`] on the same controller won't know about the change, so it
+/// must be reinitialized before being used again.
+pub fn all_off<I: I2c>(i2c: &mut I, address: SevenBitAddress) -> Result<(), I::Error> {
+    let mut buf = [0u8; 5];
+    buf[0] = reg::ALL_LED_ON_L;
+    buf[1..].copy_from_slice(&ChannelValue::FULL_OFF.to_bytes());
+    i2c.write(address, &buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_i2c::FakeI2c;
+    use futures_lite::future::block_on;
+
+    const ADDRESS: SevenBitAddress = 0x60;
+
+    const ON: [u8; 4] = [0x00, 0x10, 0x00, 0x00];
+    const OFF: [u8; 4] = [0x00, 0x00, 0x00, 0x10];
+
+    fn hat() -> (MotorHat<FakeI2c>, FakeI2c) {
+        let i2c = FakeI2c::default();
+        let hat = block_on(MotorHat::new(i2c.clone(), ADDRESS, DEFAULT_PRESCALE)).unwrap();
+        i2c.take_writes();
+        (hat, i2c)
+    }
+
+    /// The write of consecutive channels starting at `channel`
+    fn channels(channel: u8, values: &[[u8; 4]]) -> (SevenBitAddress, Vec<u8>) {
+        let mut bytes = vec![reg::LED0_ON_L + 4 * channel];
+        bytes.extend(values.iter().flatten());
+        (ADDRESS, bytes)
+    }
+
+    #[test]
+    fn initializes_with_outputs_off() {
+        let i2c = FakeI2c::default();
+        block_on(MotorHat::new(i2c.clone(), ADDRESS, DEFAULT_PRESCALE)).unwrap();
+
+        let writes = i2c.writes();
+        assert_eq!(writes[0], (ADDRESS, vec![reg::ALL_LED_ON_L, 0x00, 0x00, 0x00, 0x10]));
+        assert!(writes.contains(&(ADDRESS, vec![reg::PRE_SCALE, DEFAULT_PRESCALE])));
+        assert_eq!(writes.last(), Some(&(ADDRESS, vec![reg::MODE1, 0xA1])));
+    }
+
+    #[test]
+    fn writes_each_motors_channels() {
+        // in2 starts off, so only the pwm and in1 channels change, bridging in2 between them
+        let cases = [
+            (Motor::Motor1, channels(8, &[ON, OFF, ON])),
+            (Motor::Motor2, channels(11, &[ON, OFF, ON])),
+            (Motor::Motor3, channels(2, &[ON, OFF, ON])),
+            (Motor::Motor4, channels(5, &[ON, OFF, ON])),
+        ];
+        for (motor, write) in cases {
+            let (mut hat, i2c) = hat();
+            hat.set_throttle(motor, 1.0);
+            assert_eq!(hat.flush().unwrap(), 1);
+            assert_eq!(i2c.writes(), vec![write], "{:?}", motor);
+        }
+    }
+
+    #[test]
+    fn writes_partial_reverse_throttle() {
+        let (mut hat, i2c) = hat();
+        hat.set_throttle(Motor::Motor1, -0.5);
+        hat.flush().unwrap();
+
+        // 2048 of 4096 counts on, and in1 stays off
+        assert_eq!(i2c.writes(), vec![channels(8, &[[0x00, 0x00, 0x00, 0x08], ON])]);
+    }
+
+    #[test]
+    fn skips_unchanged_channels() {
+        let (mut hat, i2c) = hat();
+        hat.set_throttle(Motor::Motor1, 0.5);
+        hat.flush().unwrap();
+        i2c.take_writes();
+
+        hat.set_throttle(Motor::Motor1, 0.5);
+        assert_eq!(hat.flush().unwrap(), 0);
+        assert!(i2c.writes().is_empty());
+    }
+
+    #[test]
+    fn bridges_gaps_of_up_to_max_bridged_channels() {
+        // reversing changes the pwm and in2 channels: 2 and 3, then 6 and 7
+        let (mut hat, i2c) = hat();
+        hat.set_throttle(Motor::Motor3, -1.0);
+        hat.set_throttle(Motor::Motor4, -1.0);
+        assert_eq!(hat.flush().unwrap(), 1);
+        assert_eq!(i2c.writes(), vec![channels(2, &[ON, ON, OFF, OFF, ON, ON])]);
+    }
+
+    #[test]
+    fn splits_longer_gaps() {
+        // 2 and 4, then 8 and 9, with three unchanged channels between them
+        let (mut hat, i2c) = hat();
+        hat.set_throttle(Motor::Motor3, 1.0);
+        hat.set_throttle(Motor::Motor1, -1.0);
+        assert_eq!(hat.flush().unwrap(), 2);
+        assert_eq!(i2c.writes(), vec![channels(2, &[ON, OFF, ON]), channels(8, &[ON, ON])]);
+    }
+
+    #[test]
+    fn rewrites_channels_after_failed_write() {
+        let (mut hat, i2c) = hat();
+        hat.set_throttle(Motor::Motor1, 1.0);
+        i2c.fail_next(1);
+        assert!(hat.flush().is_err());
+
+        // nothing was staged since, but the registers' contents are unknown
+        assert_eq!(hat.flush().unwrap(), 1);
+        assert_eq!(i2c.writes(), vec![channels(8, &[ON, OFF, ON])]);
+        assert_eq!(hat.flush().unwrap(), 0);
+    }
+
+    #[test]
+    fn counts_transactions() {
+        let (mut hat, i2c) = hat();
+        hat.set_throttle(Motor::Motor3, 1.0);
+        hat.set_throttle(Motor::Motor1, -1.0);
+        hat.flush().unwrap();
+        hat.flush().unwrap();
+        assert_eq!(hat.stats(), Stats { flushes: 2, transactions: 2, channels_written: 5 });
+
+        // failed transactions count, but their channels don't
+        hat.set_throttle(Motor::Motor1, 1.0);
+        i2c.fail_next(1);
+        assert!(hat.flush().is_err());
+        assert_eq!(hat.stats(), Stats { flushes: 3, transactions: 3, channels_written: 5 });
+    }
+}
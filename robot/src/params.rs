@@ -0,0 +1,566 @@
+//! Named parameters that can be tuned while the robot is running.
+//!
+//! Subsystems register each tunable value along with its default and valid range, and get back a
+//! typed [`Param`] handle to read it with. Operators list, read and change parameters through
+//! requests on the control connection, and can save the current values to the robot's config
+//! file. Saved values are loaded in place of the defaults the next time the parameters are
+//! registered, so they take precedence over the matching command line arguments.
+//!
+//! The config file is TOML, with parameters under a `[params]` table. Dots in parameter names
+//! nest tables, e.g. `drive.deadzone` is saved as `deadzone` under `[params.drive]`.
+
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use eyre::{eyre, Result, WrapErr};
+use messages::{ParamChoiceName, ParamInfo, ParamName, ParamRange, ParamValue, RequestError};
+
+const CONFIG_PARAMS_TABLE: &str = "params";
+
+/// Rust types that can be used as parameter values
+pub trait ParamType: Copy + fmt::Debug {
+    /// Names of the values a [`ParamValue::Choice`] parameter can take
+    const CHOICES: &'static [&'static str] = &[];
+
+    fn to_value(self) -> ParamValue;
+    /// `None` if the value has the wrong type or doesn't fit in `Self`
+    fn from_value(value: ParamValue) -> Option<Self>;
+}
+
+impl ParamType for bool {
+    fn to_value(self) -> ParamValue {
+        ParamValue::Bool(self)
+    }
+
+    fn from_value(value: ParamValue) -> Option<Self> {
+        match value {
+            ParamValue::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl ParamType for f32 {
+    fn to_value(self) -> ParamValue {
+        ParamValue::Float(self)
+    }
+
+    fn from_value(value: ParamValue) -> Option<Self> {
+        match value {
+            ParamValue::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! int_param_type {
+    ($($ty:ty),*) => {$(
+        impl ParamType for $ty {
+            fn to_value(self) -> ParamValue {
+                ParamValue::Int(self.try_into().unwrap_or(i32::MAX))
+            }
+
+            fn from_value(value: ParamValue) -> Option<Self> {
+                match value {
+                    ParamValue::Int(i) => i.try_into().ok(),
+                    _ => None,
+                }
+            }
+        }
+    )*};
+}
+
+int_param_type!(i32, u32, u64);
+
+impl ParamType for log::LevelFilter {
+    const CHOICES: &'static [&'static str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+    fn to_value(self) -> ParamValue {
+        ParamValue::Choice(self as u8)
+    }
+
+    fn from_value(value: ParamValue) -> Option<Self> {
+        match value {
+            ParamValue::Choice(i) => log::LevelFilter::iter().nth(i.into()),
+            _ => None,
+        }
+    }
+}
+
+struct Entry {
+    name: &'static str,
+    value: ParamValue,
+    default: ParamValue,
+    range: Option<ParamRange>,
+    choices: &'static [&'static str],
+    /// Whether a value can be converted to the type the parameter was registered with
+    accepts: fn(ParamValue) -> bool,
+    /// Incremented on every change
+    version: u64,
+}
+
+impl Entry {
+    fn info(&self) -> ParamInfo {
+        ParamInfo {
+            name: param_name(self.name),
+            value: self.value,
+            default: self.default,
+            range: self.range,
+            // the number and length of choices are checked when they're registered
+            choices: self.choices.iter()
+                .map(|&choice| ParamChoiceName::from(choice))
+                .collect(),
+        }
+    }
+
+    /// The nearest value to `value` within the parameter's range
+    fn clamp(&self, value: ParamValue) -> ParamValue {
+        let Some(ParamRange { min, max }) = self.range else { return value };
+        match (min, value, max) {
+            (ParamValue::Int(min), ParamValue::Int(v), ParamValue::Int(max)) => {
+                ParamValue::Int(v.clamp(min, max))
+            }
+            (ParamValue::Float(min), ParamValue::Float(v), ParamValue::Float(max)) => {
+                ParamValue::Float(v.clamp(min, max))
+            }
+            _ => value,
+        }
+    }
+
+    fn validate(&self, value: ParamValue) -> bool {
+        (self.accepts)(value) && match self.range {
+            None => true,
+            Some(ParamRange { min, max }) => match (min, value, max) {
+                (ParamValue::Int(min), ParamValue::Int(v), ParamValue::Int(max)) => {
+                    (min..=max).contains(&v)
+                }
+                (ParamValue::Float(min), ParamValue::Float(v), ParamValue::Float(max)) => {
+                    (min..=max).contains(&v)
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+struct Registry {
+    entries: Vec<Entry>,
+    config_path: PathBuf,
+    /// Values saved in the config file, applied as parameters are registered
+    saved: toml::Table,
+}
+
+/// Handle to the shared parameter registry
+#[derive(Clone)]
+pub struct Params {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Params {
+    /// Loads saved parameter values from `config_path`. A missing config file is treated as an
+    /// empty one, and is created the first time parameters are saved.
+    pub fn load(config_path: PathBuf) -> Result<Self> {
+        let saved = match fs::read_to_string(&config_path) {
+            Ok(text) => {
+                let mut config: toml::Table = text.parse()
+                    .wrap_err_with(|| format!("Invalid config file {}", config_path.display()))?;
+                match config.remove(CONFIG_PARAMS_TABLE) {
+                    Some(toml::Value::Table(params)) => params,
+                    Some(_) => return Err(eyre!("[{}] in config file {} isn't a table",
+                        CONFIG_PARAMS_TABLE, config_path.display())),
+                    None => toml::Table::new(),
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => toml::Table::new(),
+            Err(e) => {
+                return Err(eyre::Report::new(e)
+                    .wrap_err(format!("Unable to read config file {}", config_path.display())))
+            }
+        };
+
+        Ok(Self {
+            registry: Arc::new(Mutex::new(Registry {
+                entries: Vec::new(),
+                config_path,
+                saved,
+            })),
+        })
+    }
+
+    /// Adds a parameter, starting from its saved value if there's a valid one and `default`
+    /// otherwise. A `default` outside of `range` is clamped to it.
+    ///
+    /// # Panics
+    /// If a parameter with the same name was already registered, or its name or choices don't fit
+    /// in a [`ParamInfo`]
+    pub fn register<T: ParamType>(
+        &self,
+        name: &'static str,
+        default: T,
+        range: Option<RangeInclusive<T>>,
+    ) -> Param<T> {
+        assert!(name.len() <= messages::PARAM_NAME_MAX_LEN, "Parameter name {} is too long", name);
+        assert!(T::CHOICES.len() <= messages::PARAM_CHOICES_MAX,
+            "Parameter {} has too many choices", name);
+        assert!(T::CHOICES.iter().all(|choice| choice.len() <= messages::PARAM_CHOICE_NAME_MAX_LEN),
+            "Parameter {} has a choice with a name that's too long", name);
+
+        let mut registry = self.lock();
+        assert!(registry.entries.iter().all(|entry| entry.name != name),
+            "Parameter {} was registered twice", name);
+
+        let mut entry = Entry {
+            name,
+            value: default.to_value(),
+            default: default.to_value(),
+            range: range.map(|range| ParamRange {
+                min: range.start().to_value(),
+                max: range.end().to_value(),
+            }),
+            choices: T::CHOICES,
+            accepts: |value| T::from_value(value).is_some(),
+            version: 0,
+        };
+        // defaults come from the command line, so they aren't necessarily in range
+        let default = entry.clamp(entry.default);
+        if default != entry.default {
+            log::warn!("Default {:?} for parameter {} is out of range, using {:?}", entry.default, name, default);
+            entry.default = default;
+            entry.value = default;
+        }
+        if let Some(saved) = lookup(&registry.saved, name) {
+            match from_toml(saved, entry.value, entry.choices).filter(|&value| entry.validate(value)) {
+                Some(value) => {
+                    log::info!("Using saved value {:?} for parameter {}", value, name);
+                    entry.value = value;
+                }
+                None => log::warn!("Ignoring invalid saved value {} for parameter {}", saved, name),
+            }
+        }
+
+        let index = registry.entries.len();
+        registry.entries.push(entry);
+        Param {
+            params: self.clone(),
+            index,
+            seen: 0,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<ParamValue> {
+        let registry = self.lock();
+        registry.entries.iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.value)
+    }
+
+    /// Changes a parameter, returning its new value
+    pub fn set(&self, name: &str, value: ParamValue) -> Result<ParamValue, RequestError> {
+        let mut registry = self.lock();
+        let entry = registry.entries.iter_mut()
+            .find(|entry| entry.name == name)
+            .ok_or(RequestError::UnknownParam)?;
+        if !entry.validate(value) {
+            log::warn!("Rejected value {:?} for parameter {}", value, name);
+            return Err(RequestError::InvalidValue)
+        }
+
+        if entry.value != value {
+            log::info!("Parameter {} changed from {:?} to {:?}", name, entry.value, value);
+            entry.value = value;
+            entry.version += 1;
+        }
+        Ok(entry.value)
+    }
+
+    /// Describes up to [`messages::PARAM_LIST_PAGE_LEN`] parameters starting from the `start`th,
+    /// along with the total number of parameters
+    pub fn list(&self, start: usize) -> (usize, Vec<ParamInfo>) {
+        let registry = self.lock();
+        let page = registry.entries.iter()
+            .skip(start)
+            .take(messages::PARAM_LIST_PAGE_LEN)
+            .map(Entry::info)
+            .collect();
+        (registry.entries.len(), page)
+    }
+
+    /// Writes the current value of every parameter to the config file, keeping anything else
+    /// already in it
+    pub fn save(&self) -> Result<()> {
+        let mut registry = self.lock();
+        let path = registry.config_path.clone();
+
+        let mut config: toml::Table = match fs::read_to_string(&path) {
+            Ok(text) => text.parse()
+                .wrap_err_with(|| format!("Invalid config file {}", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => toml::Table::new(),
+            Err(e) => {
+                return Err(eyre::Report::new(e)
+                    .wrap_err(format!("Unable to read config file {}", path.display())))
+            }
+        };
+
+        let mut saved = registry.saved.clone();
+        for entry in &registry.entries {
+            insert(&mut saved, entry.name, to_toml(entry.value, entry.choices));
+        }
+        config.insert(CONFIG_PARAMS_TABLE.to_string(), toml::Value::Table(saved.clone()));
+
+        // write the new config next to the old one first, so a failure can't leave it truncated
+        let text = toml::to_string(&config).wrap_err("Unable to serialize config")?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Unable to create config directory {}", dir.display()))?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, text)
+            .wrap_err_with(|| format!("Unable to write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .wrap_err_with(|| format!("Unable to replace config file {}", path.display()))?;
+
+        registry.saved = saved;
+        log::info!("Saved {} parameters to {}", registry.entries.len(), path.display());
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Registry> {
+        self.registry.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Typed handle to a single registered parameter
+pub struct Param<T> {
+    params: Params,
+    index: usize,
+    /// Version of the value last returned by [`Param::changed`]
+    seen: u64,
+    _type: PhantomData<T>,
+}
+
+impl<T: ParamType> Param<T> {
+    pub fn get(&self) -> T {
+        let registry = self.params.lock();
+        let value = registry.entries[self.index].value;
+        // only values the type accepts are ever stored
+        T::from_value(value).expect("Parameter holds a value of the wrong type")
+    }
+
+    /// The parameter's value, if it changed since the last call
+    pub fn changed(&mut self) -> Option<T> {
+        let version = self.params.lock().entries[self.index].version;
+        if version == self.seen {
+            return None
+        }
+        self.seen = version;
+        Some(self.get())
+    }
+}
+
+fn param_name(name: &str) -> ParamName {
+    let mut param_name = ParamName::new();
+    // names are checked against the maximum length when they're registered
+    let _ = param_name.push_str(name);
+    param_name
+}
+
+fn lookup<'a>(table: &'a toml::Table, name: &str) -> Option<&'a toml::Value> {
+    let (table, key) = match name.rsplit_once('.') {
+        Some((path, key)) => {
+            let table = path.split('.')
+                .try_fold(table, |table, part| table.get(part)?.as_table())?;
+            (table, key)
+        }
+        None => (table, name),
+    };
+    table.get(key)
+}
+
+fn insert(table: &mut toml::Table, name: &str, value: toml::Value) {
+    match name.split_once('.') {
+        Some((part, rest)) => {
+            let child = table.entry(part)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if !child.is_table() {
+                *child = toml::Value::Table(toml::Table::new());
+            }
+            if let toml::Value::Table(child) = child {
+                insert(child, rest, value);
+            }
+        }
+        None => {
+            table.insert(name.to_string(), value);
+        }
+    }
+}
+
+/// Choices are saved by name, so they stay valid if more are added
+fn to_toml(value: ParamValue, choices: &[&str]) -> toml::Value {
+    match value {
+        ParamValue::Bool(b) => toml::Value::Boolean(b),
+        ParamValue::Int(i) => toml::Value::Integer(i.into()),
+        ParamValue::Float(f) => toml::Value::Float(f.into()),
+        ParamValue::Choice(i) => toml::Value::String(choices[usize::from(i)].to_string()),
+    }
+}
+
+/// Converts a saved value to the same type as `like`
+fn from_toml(value: &toml::Value, like: ParamValue, choices: &[&str]) -> Option<ParamValue> {
+    Some(match (like, value) {
+        (ParamValue::Bool(_), toml::Value::Boolean(b)) => ParamValue::Bool(*b),
+        (ParamValue::Int(_), toml::Value::Integer(i)) => ParamValue::Int((*i).try_into().ok()?),
+        (ParamValue::Float(_), toml::Value::Float(f)) => ParamValue::Float(*f as f32),
+        (ParamValue::Float(_), toml::Value::Integer(i)) => ParamValue::Float(*i as f32),
+        (ParamValue::Choice(_), toml::Value::String(name)) => {
+            let i = choices.iter().position(|choice| choice == name)?;
+            ParamValue::Choice(i.try_into().ok()?)
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> Params {
+        Params::load(PathBuf::from("/nonexistent/robot.toml")).unwrap()
+    }
+
+    /// Config path in a fresh directory of its own, which doesn't exist yet
+    fn config_path(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mappie-params-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("robot.toml")
+    }
+
+    #[test]
+    fn clamps_default_to_range() {
+        let params = params();
+        let low = params.register("test.low", 0u64, Some(1..=10_000));
+        let high = params.register("test.high", 2.0f32, Some(0.0..=1.0));
+
+        assert_eq!(low.get(), 1);
+        assert_eq!(high.get(), 1.0);
+        let (_, infos) = params.list(0);
+        assert_eq!(infos[0].default, ParamValue::Int(1));
+        assert_eq!(infos[1].default, ParamValue::Float(1.0));
+    }
+
+    #[test]
+    fn keeps_default_in_range() {
+        let params = params();
+        let param = params.register("test.in_range", 250u64, Some(1..=10_000));
+        assert_eq!(param.get(), 250);
+    }
+
+    #[test]
+    fn rejects_values_out_of_range() {
+        let params = params();
+        let param = params.register("test.value", 5i32, Some(0..=10));
+
+        assert_eq!(params.set("test.value", ParamValue::Int(11)), Err(RequestError::InvalidValue));
+        assert_eq!(params.set("test.value", ParamValue::Float(1.0)), Err(RequestError::InvalidValue));
+        assert_eq!(params.set("test.value", ParamValue::Int(10)), Ok(ParamValue::Int(10)));
+        assert_eq!(param.get(), 10);
+    }
+
+    #[test]
+    fn saved_values_are_loaded() {
+        let path = config_path("saved_values_are_loaded");
+        let params = Params::load(path.clone()).unwrap();
+        params.register("drive.deadzone", 0.2f32, Some(0.0..=1.0));
+        params.register("drive.error_budget", 5u32, Some(1..=1000));
+        params.register("log.level", log::LevelFilter::Info, None);
+        params.register("enabled", true, None);
+        params.set("drive.deadzone", ParamValue::Float(0.5)).unwrap();
+        params.set("drive.error_budget", ParamValue::Int(7)).unwrap();
+        params.set("log.level", log::LevelFilter::Debug.to_value()).unwrap();
+        params.set("enabled", ParamValue::Bool(false)).unwrap();
+        params.save().unwrap();
+
+        let config: toml::Table = fs::read_to_string(&path).unwrap().parse().unwrap();
+        let drive = config["params"]["drive"].as_table().unwrap();
+        assert_eq!(drive["deadzone"].as_float(), Some(0.5));
+        assert_eq!(drive["error_budget"].as_integer(), Some(7));
+        assert_eq!(config["params"]["log"]["level"].as_str(), Some("debug"));
+
+        let params = Params::load(path).unwrap();
+        assert_eq!(params.register("drive.deadzone", 0.2f32, Some(0.0..=1.0)).get(), 0.5);
+        assert_eq!(params.register("drive.error_budget", 5u32, Some(1..=1000)).get(), 7);
+        assert_eq!(params.register("log.level", log::LevelFilter::Info, None).get(), log::LevelFilter::Debug);
+        assert!(!params.register("enabled", true, None).get());
+    }
+
+    #[test]
+    fn saving_keeps_the_rest_of_the_config() {
+        let path = config_path("saving_keeps_the_rest_of_the_config");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "name = \"mappie\"\n\n[params.removed]\nvalue = 3\n").unwrap();
+
+        let params = Params::load(path.clone()).unwrap();
+        params.register("drive.deadzone", 0.2f32, None);
+        params.save().unwrap();
+
+        let config: toml::Table = fs::read_to_string(&path).unwrap().parse().unwrap();
+        assert_eq!(config["name"].as_str(), Some("mappie"));
+        assert_eq!(config["params"]["removed"]["value"].as_integer(), Some(3));
+        assert_eq!(config["params"]["drive"]["deadzone"].as_float(), Some(0.2f32.into()));
+    }
+
+    #[test]
+    fn invalid_saved_values_are_ignored() {
+        let path = config_path("invalid_saved_values_are_ignored");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "[params]\nrange = 50\ntype = \"fast\"\nchoice = \"loud\"\n").unwrap();
+
+        let params = Params::load(path).unwrap();
+        assert_eq!(params.register("range", 5i32, Some(0..=10)).get(), 5);
+        assert_eq!(params.register("type", 0.5f32, None).get(), 0.5);
+        assert_eq!(params.register("choice", log::LevelFilter::Warn, None).get(), log::LevelFilter::Warn);
+    }
+
+    #[test]
+    fn changed_reports_each_new_value_once() {
+        let params = params();
+        let mut param = params.register("test.value", 5i32, None);
+        assert_eq!(param.changed(), None);
+
+        params.set("test.value", ParamValue::Int(6)).unwrap();
+        assert_eq!(param.changed(), Some(6));
+        assert_eq!(param.changed(), None);
+
+        // setting the same value again isn't a change
+        params.set("test.value", ParamValue::Int(6)).unwrap();
+        assert_eq!(param.changed(), None);
+    }
+
+    #[test]
+    fn lists_params_a_page_at_a_time() {
+        let params = params();
+        let names = ["a", "b", "c", "d", "e"];
+        for (i, name) in names.into_iter().enumerate() {
+            params.register(name, i as i32, None);
+        }
+
+        let mut listed = Vec::new();
+        let mut start = 0;
+        loop {
+            let (total, page) = params.list(start);
+            assert_eq!(total, names.len());
+            assert!(page.len() <= messages::PARAM_LIST_PAGE_LEN);
+            if page.is_empty() {
+                break
+            }
+            start += page.len();
+            listed.extend(page.into_iter().map(|info| info.name));
+        }
+        assert_eq!(listed, names.map(param_name));
+    }
+}